@@ -0,0 +1,259 @@
+use crate::duplicates::{
+    default_component_names, DuplicateMap, Grouping, GroupingOpts, RecordIdentifier, RecordPosition,
+};
+use crate::index::IndexRecord;
+use anyhow::{ensure, Result};
+use indexmap::IndexMap;
+use std::io::Write;
+use std::time::Instant;
+
+/// The bases used for random barcodes and UMIs.
+const BASES: [u8; 4] = *b"ACGT";
+
+/// Options which control the synthetic index used by the benchmark.
+///
+/// # Fields
+///
+/// * `reads` - The number of reads in the index.
+/// * `cells` - The number of cells (barcodes).
+/// * `mean_duplicates` - The mean number of reads per molecule, which must be at least 1.
+/// * `seed` - The random seed.
+pub struct BenchmarkOpts {
+    pub reads: usize,
+    pub cells: usize,
+    pub mean_duplicates: f64,
+    pub seed: u64,
+}
+
+/// The duplicate map used before identifiers were packed, which stores a `RecordIdentifier` for
+/// every read and for every group. This is kept only to compare against `DuplicateMap`.
+struct LegacyDuplicateMap {
+    by_id: IndexMap<RecordIdentifier, Vec<RecordPosition>>,
+    pos_to_id: IndexMap<usize, RecordIdentifier>,
+}
+
+impl LegacyDuplicateMap {
+    fn new() -> Self {
+        LegacyDuplicateMap {
+            by_id: Default::default(),
+            pos_to_id: Default::default(),
+        }
+    }
+
+    fn insert(&mut self, record: &IndexRecord, grouping: &Grouping) -> Result<()> {
+        let mut id = RecordIdentifier::from_string(&record.id);
+        id.sample = record.sample.clone();
+        let id = grouping.identifier(id)?;
+
+        let rec_pos = RecordPosition {
+            pos: record.pos,
+            length: record.rec_len,
+        };
+
+        self.pos_to_id.insert(record.pos, id.clone());
+        self.by_id.entry(id).or_default().push(rec_pos);
+
+        Ok(())
+    }
+
+    fn records_by_pos(&self, pos: &usize) -> Option<&Vec<RecordPosition>> {
+        let id = self.pos_to_id.get(pos)?;
+        self.by_id.get(id)
+    }
+
+    /// The approximate number of bytes of memory used by the map, counted in the same way as
+    /// `DuplicateMap::memory_usage`.
+    fn memory_usage(&self) -> usize {
+        fn id_bytes(id: &RecordIdentifier) -> usize {
+            id.sample.capacity()
+                + id.components.capacity() * std::mem::size_of::<String>()
+                + id.components.iter().map(|c| c.capacity()).sum::<usize>()
+        }
+
+        // an `IndexMap` stores its entries (with their hashes) in a vector, and their indices in
+        // a hash table with one control byte per bucket and at most 8/7 buckets per entry
+        fn table_bytes<K, V>(map: &IndexMap<K, V>) -> usize {
+            map.capacity() * (std::mem::size_of::<(K, V, u64)>())
+                + map.capacity() * (std::mem::size_of::<usize>() + 1) * 8 / 7
+        }
+
+        table_bytes(&self.by_id)
+            + table_bytes(&self.pos_to_id)
+            + self
+                .by_id
+                .iter()
+                .map(|(id, records)| {
+                    id_bytes(id) + records.capacity() * std::mem::size_of::<RecordPosition>()
+                })
+                .sum::<usize>()
+            + self.pos_to_id.values().map(id_bytes).sum::<usize>()
+    }
+}
+
+/// The result of benchmarking one map.
+///
+/// # Fields
+///
+/// * `name` - The name of the map.
+/// * `build` - The time taken to insert every read, in seconds.
+/// * `lookup` - The time taken to look up the group of every read by its position, in seconds.
+/// * `memory` - The approximate memory used by the map, in bytes.
+/// * `groups` - The number of groups in the map.
+struct BenchmarkResult {
+    name: &'static str,
+    build: f64,
+    lookup: f64,
+    memory: usize,
+    groups: usize,
+}
+
+/// Generates a synthetic index, where each read is sequenced from a random molecule.
+fn synthetic_records(opts: &BenchmarkOpts) -> Result<Vec<IndexRecord>> {
+    ensure!(opts.cells > 0, "The number of cells must be at least 1");
+    ensure!(
+        opts.mean_duplicates >= 1.0,
+        "The mean number of duplicates must be at least 1"
+    );
+
+    let mut rng = fastrand::Rng::with_seed(opts.seed);
+    let mut random_sequence =
+        |len: usize| -> String { (0..len).map(|_| BASES[rng.usize(0..4)] as char).collect() };
+
+    let barcodes = (0..opts.cells)
+        .map(|_| random_sequence(16))
+        .collect::<Vec<_>>();
+    let molecules = ((opts.reads as f64 / opts.mean_duplicates).ceil() as usize).max(1);
+    let molecules = (0..molecules)
+        .map(|i| {
+            RecordIdentifier::new(vec![barcodes[i % opts.cells].clone(), random_sequence(12)])
+                .to_string()
+        })
+        .collect::<Vec<_>>();
+
+    let mut pos = 0;
+    let records = (0..opts.reads)
+        .map(|_| {
+            let rec_len = 500 + rng.usize(0..1000);
+            let record = IndexRecord {
+                id: molecules[rng.usize(0..molecules.len())].clone(),
+                sample: String::new(),
+                pos,
+                avg_qual: 20.0,
+                n_bases: rec_len / 2,
+                rec_len,
+                ignored: false,
+                reason: None,
+            };
+            pos += rec_len;
+            record
+        })
+        .collect();
+
+    Ok(records)
+}
+
+fn benchmark_packed(records: &[IndexRecord], grouping: &Grouping) -> Result<BenchmarkResult> {
+    let start = Instant::now();
+    let mut map = DuplicateMap::new();
+    for record in records {
+        map.insert(record, grouping)?;
+    }
+    map.finish();
+    let build = start.elapsed().as_secs_f64();
+
+    let start = Instant::now();
+    let mut total = 0;
+    for record in records {
+        if let Some(group) = map.group_by_pos(record.pos) {
+            total += map
+                .group_reads(group)
+                .iter()
+                .map(|&read| map.record_position(read).length)
+                .sum::<usize>();
+        }
+    }
+    let lookup = start.elapsed().as_secs_f64();
+    ensure!(total > 0 || records.is_empty(), "No reads were found");
+
+    Ok(BenchmarkResult {
+        name: "packed",
+        build,
+        lookup,
+        memory: map.memory_usage(),
+        groups: map.group_count(),
+    })
+}
+
+fn benchmark_legacy(records: &[IndexRecord], grouping: &Grouping) -> Result<BenchmarkResult> {
+    let start = Instant::now();
+    let mut map = LegacyDuplicateMap::new();
+    for record in records {
+        map.insert(record, grouping)?;
+    }
+    map.by_id.shrink_to_fit();
+    map.pos_to_id.shrink_to_fit();
+    let build = start.elapsed().as_secs_f64();
+
+    let start = Instant::now();
+    let mut total = 0;
+    for record in records {
+        if let Some(group) = map.records_by_pos(&record.pos) {
+            total += group.iter().map(|rec| rec.length).sum::<usize>();
+        }
+    }
+    let lookup = start.elapsed().as_secs_f64();
+    ensure!(total > 0 || records.is_empty(), "No reads were found");
+
+    Ok(BenchmarkResult {
+        name: "legacy",
+        build,
+        lookup,
+        memory: map.memory_usage(),
+        groups: map.by_id.len(),
+    })
+}
+
+/// Compares the time and memory used by `DuplicateMap` with the map it replaced, on a synthetic
+/// index of barcodes and UMIs, and writes a table of the results.
+///
+/// # Arguments
+///
+/// * `opts` - The options which control the synthetic index.
+/// * `writer` - The writer to write the table of results to.
+pub fn benchmark<W: Write>(opts: &BenchmarkOpts, writer: &mut W) -> Result<()> {
+    info!("Generating {} synthetic index records...", opts.reads);
+    let records = synthetic_records(opts)?;
+    let grouping = Grouping::new(&default_component_names(2), &GroupingOpts::default())?;
+
+    info!("Benchmarking the legacy duplicate map...");
+    let legacy = benchmark_legacy(&records, &grouping)?;
+    info!("Benchmarking the packed duplicate map...");
+    let packed = benchmark_packed(&records, &grouping)?;
+
+    ensure!(
+        legacy.groups == packed.groups,
+        "The packed map found {} groups, but the legacy map found {}",
+        packed.groups,
+        legacy.groups
+    );
+
+    writeln!(
+        writer,
+        "{:<8} {:>10} {:>11} {:>12} {:>15} {:>10}",
+        "map", "build (s)", "lookup (s)", "memory (MB)", "bytes per read", "groups"
+    )?;
+    for result in [legacy, packed] {
+        writeln!(
+            writer,
+            "{:<8} {:>10.3} {:>11.3} {:>12.1} {:>15.1} {:>10}",
+            result.name,
+            result.build,
+            result.lookup,
+            result.memory as f64 / 1024f64.powi(2),
+            result.memory as f64 / records.len().max(1) as f64,
+            result.groups
+        )?;
+    }
+
+    Ok(())
+}
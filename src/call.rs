@@ -15,6 +15,110 @@ enum GroupType {
     Duplex(usize),
 }
 
+/// Warns if the options given to `call` conflict with those that the index was created with, as
+/// the read positions stored in the index are only valid for the file it was created from.
+///
+/// # Arguments
+///
+/// * `index` - The index, with the grouping given to `call`.
+/// * `input` - The path to the input .fastq file given to `call`.
+/// * `filtered_output` - The `--filtered-output` given to `call`, if any.
+/// * `unmatched_output` - The `--unmatched-output` given to `call`, if any.
+///
+/// # Returns
+///
+/// * `Result<()>` - Returns `Ok(())` if successful, or an error if the input cannot be read.
+pub fn check_index_parameters(
+    index: &mut IndexReader,
+    input: &str,
+    filtered_output: &Option<String>,
+    unmatched_output: &Option<String>,
+) -> Result<()> {
+    let metadata = &index.metadata;
+    let input_path = std::fs::canonicalize(input)?.display().to_string();
+    if input_path != metadata.file_path {
+        warn!(
+            "The index was created from {}, but the input is {input_path}. \
+            Reads will be read from the wrong positions unless these files are identical",
+            metadata.file_path
+        );
+    }
+
+    if metadata.nailpolish_version != crate::cli::VERSION {
+        warn!(
+            "The index was created by nailpolish v{}, but this is v{}",
+            metadata.nailpolish_version,
+            crate::cli::VERSION
+        );
+    }
+
+    // the index date is only compared if it can be parsed, as older indexes may use other formats
+    let index_date = chrono::DateTime::parse_from_rfc3339(&metadata.index_date);
+    let modified = std::fs::metadata(input)?.modified();
+    if let (Ok(index_date), Ok(modified)) = (index_date, modified) {
+        if chrono::DateTime::<chrono::Utc>::from(modified) > index_date {
+            warn!("The input {input} was modified after the index was created, so it may be out of date");
+        }
+    }
+
+    let parameters = &metadata.parameters;
+    if parameters.is_recorded() {
+        info!(
+            "Index created with barcode format {}, length filter {} and quality filter {}",
+            parameters.barcode_format(),
+            parameters.len,
+            parameters.qual
+        );
+    } else {
+        debug!("The index does not record the parameters it was created with");
+    }
+
+    if filtered_output.is_some() && metadata.filtered_reads == 0 {
+        if parameters.is_recorded() {
+            warn!(
+                "--filtered-output was given, but no reads were filtered by the length filter {} \
+                or quality filter {} of the index, so it will be empty",
+                parameters.len, parameters.qual
+            );
+        } else {
+            warn!("--filtered-output was given, but no reads were filtered when the index was created, so it will be empty");
+        }
+    }
+
+    if unmatched_output.is_some() {
+        if parameters.is_recorded() && !parameters.skip_unmatched {
+            warn!(
+                "--unmatched-output was given, but the index was created without \
+                --skip-unmatched, so every read matched its barcode format {} and it will be empty",
+                parameters.barcode_format()
+            );
+        } else if metadata.unmatched_read_count == 0 {
+            warn!("--unmatched-output was given, but every read matched the barcode format of the index, so it will be empty");
+        }
+    }
+
+    let barcode_format = parameters.barcode_format();
+    let grouping = index.grouping.clone();
+    let components = index.component_names()?;
+    let options = [
+        ("--cell", &grouping.cell),
+        ("--molecule", &grouping.molecule),
+    ];
+    for (option, names) in options {
+        for name in names.iter().flatten() {
+            if !components.contains(name) {
+                warn!(
+                    "{option} {name} is not a component of the index, which was created with \
+                    barcode format {barcode_format}. Its components are: {}",
+                    components.join(", ")
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
 /// Generates consensus sequences from the input in a thread-stable manner.
 ///
 /// # Arguments
@@ -95,15 +199,16 @@ pub fn consensus(
 
                 // output original reads as well, if requested
                 if matches!(loc, GroupType::Duplex(_)) && output_originals {
-                    if !first {
-                        writer.write_all(b"\n")?;
-                    }
-                    first = false;
-
                     let group_size = group.records.len();
                     for (idx, r) in group.records.iter_mut().enumerate() {
+                        if !first {
+                            writer.write_all(b"\n")?;
+                        }
+                        first = false;
+
                         r.add_metadata(
                             group.index,
+                            &group.id.sample,
                             ReadType::Original,
                             idx + 1,
                             group_size,
@@ -164,7 +269,14 @@ fn call_umi_group(group: &mut UMIGroup) {
     if length == 1 {
         let mut rec = group.records[0].clone();
 
-        rec.add_metadata(group.index, ReadType::Single, 1, 1, group.avg_qual);
+        rec.add_metadata(
+            group.index,
+            &group.id.sample,
+            ReadType::Single,
+            1,
+            1,
+            group.avg_qual,
+        );
 
         group.consensus = Some(rec);
 
@@ -194,6 +306,7 @@ fn call_umi_group(group: &mut UMIGroup) {
 
     rec.add_metadata(
         group.index,
+        &group.id.sample,
         ReadType::Consensus,
         0,
         group.records.len(),
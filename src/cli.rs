@@ -32,6 +32,11 @@ const STYLES: Styles = Styles::styled()
     styles = STYLES
 )]
 pub struct Cli {
+    /// read options from a TOML or YAML file, with a table for each subcommand such as
+    /// `[index]`. options given on the command line override those in the file
+    #[arg(long, global = true, verbatim_doc_comment)]
+    pub config: Option<String>,
+
     #[command(subcommand)]
     pub command: Commands,
 }
@@ -44,8 +49,24 @@ pub enum Commands {
         /// the input .fastq file
         file: String,
 
-        #[arg(value_enum, conflicts_with = "barcode_regex", default_value = "bc-umi")]
-        preset: crate::preset::PresetBarcodeFormats,
+        /// the header format preset, which is either built-in or from the `--presets` file.
+        /// see `nailpolish presets list` for the available presets. `auto` detects the preset
+        /// from the first reads of the input
+        #[arg(
+            conflicts_with = "barcode_regex",
+            default_value = "bc-umi",
+            verbatim_doc_comment
+        )]
+        preset: String,
+
+        /// a TOML or YAML file of user presets, each given as a `[[preset]]` table with a `name`,
+        /// `regex`, and optionally `components`, `umi_length`, `description` and `example`
+        #[arg(long, env = "NAILPOLISH_PRESETS", verbatim_doc_comment)]
+        presets: Option<String>,
+
+        /// the number of reads to sample when detecting the preset with `auto`
+        #[arg(long, default_value_t = 1000)]
+        detect_reads: usize,
 
         /// the output index file
         #[arg(short, default_value = "index.tsv")]
@@ -57,9 +78,16 @@ pub enum Commands {
         #[arg(long, verbatim_doc_comment)]
         clusters: Option<String>,
 
+        /// the cluster file has a sample column after the read ID, i.e. READ_ID;SAMPLE;BARCODE;UMI.
+        /// reads are only grouped together if they are from the same sample
+        #[arg(long, requires = "clusters", verbatim_doc_comment)]
+        sample_column: bool,
+
         /// barcode regex format type, for custom header styles. this will override the preset given.
         /// for example, for the `bc-umi` preset:
         ///     ^([ATCG]{16})_([ATCG]{12})
+        /// a capture group named `sample`, e.g. `^(?<sample>S\d+)_`, gives the sample of each read
+        /// in a multi-sample library
         #[arg(long, verbatim_doc_comment)]
         barcode_regex: Option<String>,
 
@@ -90,18 +118,149 @@ pub enum Commands {
             verbatim_doc_comment
         )]
         qual: ArgInterval,
+
+        /// write the effective options, including defaults, to a TOML or YAML file which can be
+        /// passed to `--config`
+        #[arg(long, verbatim_doc_comment)]
+        dump_config: Option<String>,
+    },
+
+    /// Run `index`, `summary` and `call` in turn, writing every output to a directory
+    #[command(arg_required_else_help = true)]
+    Run {
+        /// the input .fastq file
+        file: String,
+
+        /// the header format preset, or `auto`. see `nailpolish presets list` for the available
+        /// presets
+        #[arg(
+            conflicts_with = "barcode_regex",
+            default_value = "bc-umi",
+            verbatim_doc_comment
+        )]
+        preset: String,
+
+        /// a TOML or YAML file of user presets. see `index --help`
+        #[arg(long, env = "NAILPOLISH_PRESETS")]
+        presets: Option<String>,
+
+        /// the number of reads to sample when detecting the preset with `auto`
+        #[arg(long, default_value_t = 1000)]
+        detect_reads: usize,
+
+        /// the output directory, which will contain the index, summary, consensus .fastq and a
+        /// manifest.json listing each file
+        #[arg(short, default_value = "nailpolish_output", verbatim_doc_comment)]
+        output: String,
+
+        /// whether to use a file containing pre-clustered reads. see `index --help`
+        #[arg(long)]
+        clusters: Option<String>,
+
+        /// the cluster file has a sample column after the read ID. see `index --help`
+        #[arg(long, requires = "clusters")]
+        sample_column: bool,
+
+        /// barcode regex format type, for custom header styles. this will override the preset given
+        #[arg(long)]
+        barcode_regex: Option<String>,
+
+        /// skip, instead of error, on reads which are not accounted for. see `index --help`
+        #[arg(long)]
+        skip_unmatched: bool,
+
+        /// filter lengths to a value within the given float interval [a,b]. see `index --help`
+        #[arg(
+            long,
+            value_parser = |x: &str| ArgInterval::try_from(x),
+            default_value = "0,15000"
+        )]
+        len: ArgInterval,
+
+        /// filter average read quality to a value within the given float interval [a,b]
+        #[arg(
+            long,
+            value_parser = |x: &str| ArgInterval::try_from(x),
+            default_value = "0,inf"
+        )]
+        qual: ArgInterval,
+
+        /// the format of the summary report
+        #[arg(long, value_enum, default_value = "html")]
+        format: crate::summary::SummaryFormat,
+
+        /// the number of threads to use
+        #[arg(short, long, default_value_t = 4)]
+        threads: usize,
+
+        /// only show the duplicated reads, not the single ones
+        #[arg(short, long, action)]
+        duplicates_only: bool,
+
+        /// for each duplicate group of reads, report the original reads along with the consensus
+        #[arg(short, long, action)]
+        report_original_reads: bool,
+
+        /// compress the consensus output
+        #[arg(long, value_enum, default_value = "none")]
+        compress: crate::compress::Compression,
+
+        /// the identifier components which define a cell. see `summary --help`
+        #[arg(long, value_delimiter = ',')]
+        cell: Option<Vec<String>>,
+
+        /// the identifier components which define a molecule. see `summary --help`
+        #[arg(long, value_delimiter = ',')]
+        molecule: Option<Vec<String>>,
+
+        /// memory map the input to read duplicates from, instead of reading them from the file.
+        /// this may be faster on fast local storage, but the input must not be modified while
+        /// running
+        #[arg(long, verbatim_doc_comment)]
+        mmap: bool,
     },
 
     /// Generate a summary of duplicate statistics from an index file
     #[command(arg_required_else_help = true)]
     Summary {
-        /// the index file
-        #[arg(long)]
-        index: String,
+        /// the index file. if several are given (e.g. `--index a.tsv --index b.tsv`), a
+        /// single report comparing each sample is produced
+        #[arg(long, required = true, verbatim_doc_comment)]
+        index: Vec<String>,
+
+        /// a sample sheet used to label each index when comparing samples, with one
+        /// `INDEX<TAB>LABEL` line per index. by default, samples are labelled by the name
+        /// of their .fastq file
+        #[arg(long, verbatim_doc_comment)]
+        samples: Option<String>,
 
-        /// output file
-        #[arg(short, default_value = "summary.html")]
-        output: String,
+        /// output file, or `-` for standard output. defaults to summary.html, summary.json,
+        /// summary.tsv or nailpolish_mqc.json depending on the format, and to standard output for
+        /// the text format
+        #[arg(short, verbatim_doc_comment)]
+        output: Option<String>,
+
+        /// the format of the output file. defaults to text when writing to standard output (with
+        /// `-o -`, or when no output file is given and standard output is a terminal), and to
+        /// html otherwise
+        #[arg(long, value_enum, verbatim_doc_comment)]
+        format: Option<crate::summary::SummaryFormat>,
+
+        /// write per-cell statistics (reads, UMIs and duplicate rate for each barcode) to this
+        /// TSV file
+        #[arg(long, verbatim_doc_comment)]
+        cell_output: Option<String>,
+
+        /// the identifier components which define a cell, separated by commas, e.g.
+        /// `--cell sample,barcode`. the component names of an index are shown by `summary`.
+        /// defaults to every component except the last, which is taken to be the UMI
+        #[arg(long, value_delimiter = ',', verbatim_doc_comment)]
+        cell: Option<Vec<String>>,
+
+        /// the identifier components which, along with the cell, define a molecule. defaults
+        /// to every component which is not used by `--cell`
+        #[arg(long, value_delimiter = ',', verbatim_doc_comment)]
+        molecule: Option<Vec<String>>,
     },
 
     /// Generate a consensus-called 'cleaned up' file
@@ -130,6 +289,199 @@ pub enum Commands {
         /// for each duplicate group of reads, report the original reads along with the consensus
         #[arg(short, long, action)]
         report_original_reads: bool,
+
+        /// compress the output. if not given, this is inferred from the output file extension
+        /// (.gz, .bgz, .zst), and otherwise no compression is used
+        #[arg(long, value_enum, verbatim_doc_comment)]
+        compress: Option<crate::compress::Compression>,
+
+        /// the identifier components which define a cell. see `summary --help`
+        #[arg(long, value_delimiter = ',')]
+        cell: Option<Vec<String>>,
+
+        /// the identifier components which define a molecule. see `summary --help`
+        #[arg(long, value_delimiter = ',')]
+        molecule: Option<Vec<String>>,
+
+        /// write the reads which were filtered out by --len or --qual when the index was created
+        /// to this .fastq, labelled `UT:Z:IGN` with the reason in an `FR:Z` tag, e.g.
+        /// `FR:Z:too_short`
+        #[arg(long, verbatim_doc_comment)]
+        filtered_output: Option<String>,
+
+        /// write the reads which did not match the barcode format or cluster file when the index
+        /// was created with --skip-unmatched to this .fastq, labelled `UT:Z:IGN FR:Z:unmatched`
+        #[arg(long, verbatim_doc_comment)]
+        unmatched_output: Option<String>,
+
+        /// memory map the input to read duplicates from, instead of reading them from the file.
+        /// this may be faster on fast local storage, but the input must not be modified while
+        /// running
+        #[arg(long, verbatim_doc_comment)]
+        mmap: bool,
+
+        /// write the effective options, including defaults, to a TOML or YAML file which can be
+        /// passed to `--config`
+        #[arg(long, verbatim_doc_comment)]
+        dump_config: Option<String>,
+    },
+
+    /// List the header format presets which can be used by `index` and `run`
+    #[command(arg_required_else_help = true)]
+    Presets {
+        #[command(subcommand)]
+        command: PresetsCommands,
+    },
+
+    /// Generate a summary of the consensus-called output of `call`
+    #[command(arg_required_else_help = true)]
+    Qc {
+        /// the .fastq produced by `call`, which may be compressed
+        #[arg(long)]
+        input: String,
+
+        /// the output HTML report
+        #[arg(short, default_value = "qc.html")]
+        output: String,
+
+        /// the output JSON file
+        #[arg(long, default_value = "qc.json")]
+        json: String,
+    },
+
+    /// Simulate a .fastq of reads with known duplicates, for benchmarking
+    #[command(arg_required_else_help = true)]
+    Simulate {
+        /// the output .fastq, or standard output if not given. compression is inferred from the
+        /// file extension (.gz, .bgz, .zst)
+        #[arg(short, verbatim_doc_comment)]
+        output: Option<String>,
+
+        /// the output truth table, recording the molecule that each read was sequenced from
+        #[arg(long, default_value = "truth.tsv")]
+        truth: String,
+
+        /// a .fasta or .fastq file of transcripts to simulate reads from. if not given, random
+        /// transcripts are generated
+        #[arg(long, verbatim_doc_comment)]
+        transcripts: Option<String>,
+
+        /// the number of random transcripts to generate
+        #[arg(long, default_value_t = 500)]
+        transcript_count: usize,
+
+        /// the mean length of random transcripts
+        #[arg(long, default_value_t = 1000)]
+        transcript_length: usize,
+
+        /// the number of cells (barcodes)
+        #[arg(long, default_value_t = 100)]
+        cells: usize,
+
+        /// the number of molecules (UMIs) in each cell
+        #[arg(long, default_value_t = 50)]
+        umis: usize,
+
+        /// the length of each barcode
+        #[arg(long, default_value_t = 16)]
+        barcode_length: usize,
+
+        /// the length of each UMI
+        #[arg(long, default_value_t = 12)]
+        umi_length: usize,
+
+        /// the distribution of the number of reads (PCR duplicates) from each molecule
+        #[arg(long, value_enum, default_value = "geometric")]
+        duplication: crate::simulate::DuplicationModel,
+
+        /// the mean number of reads from each molecule
+        #[arg(long, default_value_t = 2.0)]
+        mean_duplicates: f64,
+
+        /// the per-base substitution error rate
+        #[arg(long, default_value_t = 0.03)]
+        substitution_rate: f64,
+
+        /// the per-base insertion error rate, which is doubled within homopolymers
+        #[arg(long, default_value_t = 0.02)]
+        insertion_rate: f64,
+
+        /// the per-base deletion error rate, which is doubled within homopolymers
+        #[arg(long, default_value_t = 0.02)]
+        deletion_rate: f64,
+
+        /// the per-base substitution rate of the barcode written in each read header
+        #[arg(long, default_value_t = 0.0)]
+        barcode_error_rate: f64,
+
+        /// the per-base substitution rate of the UMI written in each read header
+        #[arg(long, default_value_t = 0.0)]
+        umi_error_rate: f64,
+
+        /// write the transcripts that reads were simulated from to this .fasta file, for use with
+        /// `evaluate --reference`
+        #[arg(long, verbatim_doc_comment)]
+        reference_output: Option<String>,
+
+        /// the random seed
+        #[arg(long, default_value_t = 1)]
+        seed: u64,
+    },
+
+    /// Evaluate grouping and consensus accuracy against the truth table from `simulate`
+    #[command(arg_required_else_help = true)]
+    Evaluate {
+        /// the truth table written by `simulate`
+        #[arg(long)]
+        truth: String,
+
+        /// the index file to take the grouping of reads from. requires --input
+        #[arg(long, required_unless_present = "grouped", requires = "input")]
+        index: Option<String>,
+
+        /// the .fastq which the index was created from
+        #[arg(long)]
+        input: Option<String>,
+
+        /// the output of `group` to take the grouping of reads from, instead of an index
+        #[arg(long, conflicts_with_all = ["index", "input"])]
+        grouped: Option<String>,
+
+        /// the output of `call`, to evaluate the identity of consensus reads. requires
+        /// --reference
+        #[arg(long, verbatim_doc_comment)]
+        consensus: Option<String>,
+
+        /// a .fasta of the transcripts named in the truth table, as written by
+        /// `simulate --reference-output`. if given, the identity of reads to their true
+        /// transcript is reported
+        #[arg(long, verbatim_doc_comment)]
+        reference: Option<String>,
+
+        /// the output JSON file, or standard output if not given
+        #[arg(short)]
+        output: Option<String>,
+    },
+
+    /// Compare the time and memory used by the duplicate map with the map it replaced, on a
+    /// synthetic index
+    #[cfg(feature = "benchmark")]
+    Benchmark {
+        /// the number of reads in the synthetic index
+        #[arg(long, default_value_t = 1_000_000)]
+        reads: usize,
+
+        /// the number of cells (barcodes)
+        #[arg(long, default_value_t = 1000)]
+        cells: usize,
+
+        /// the mean number of reads from each molecule
+        #[arg(long, default_value_t = 2.0)]
+        mean_duplicates: f64,
+
+        /// the random seed
+        #[arg(long, default_value_t = 1)]
+        seed: u64,
     },
 
     /// Tag each read by its UMI group, and write to a .fastq file. Due to the large amounts of
@@ -145,6 +497,215 @@ pub enum Commands {
 
         #[arg(short)]
         output: Option<String>,
+
+        /// the number of threads to use. if a command is given, this is the number of processes
+        /// which are run at once; this will not guard against race conditions in any downstream
+        /// applications used. this is also the number of threads used for reading and
+        /// compression
+        #[arg(short, long, default_value_t = 1, verbatim_doc_comment)]
+        threads: usize,
+
+        /// compress the output. if not given, this is inferred from the output file extension
+        /// (.gz, .bgz, .zst), and otherwise no compression is used
+        #[arg(long, value_enum, verbatim_doc_comment)]
+        compress: Option<crate::compress::Compression>,
+
+        /// the order in which groups are written
+        #[arg(long, value_enum, default_value = "input", conflicts_with = "command")]
+        sort: crate::sort::GroupOrder,
+
+        /// the maximum memory (in MB) used to sort reads in memory, before sorted chunks are
+        /// written to temporary files. the temporary directory can be set using $TMPDIR
+        #[arg(long, default_value_t = 1024, verbatim_doc_comment)]
+        sort_memory: usize,
+
+        /// the shell used to run the given command
+        #[arg(long, default_value = "bash")]
+        shell: String,
+
+        /// warn and skip, instead of error, when the command exits with a non-zero exit code
+        #[arg(long)]
+        keep_going: bool,
+
+        /// the identifier components which define a cell. see `summary --help`
+        #[arg(long, value_delimiter = ',')]
+        cell: Option<Vec<String>>,
+
+        /// the identifier components which define a molecule. see `summary --help`
+        #[arg(long, value_delimiter = ',')]
+        molecule: Option<Vec<String>>,
+
+        /// memory map the input to read duplicates from, instead of reading them from the file.
+        /// this may be faster on fast local storage, but the input must not be modified while
+        /// running
+        #[arg(long, verbatim_doc_comment)]
+        mmap: bool,
+
+        /// write the reads which were filtered out by --len or --qual when the index was created
+        /// to this .fastq, labelled `UT:Z:IGN` with the reason in an `FR:Z` tag, e.g.
+        /// `FR:Z:too_short`
+        #[arg(long, verbatim_doc_comment)]
+        filtered_output: Option<String>,
+
+        /// write the reads which did not match the barcode format or cluster file when the index
+        /// was created with --skip-unmatched to this .fastq, labelled `UT:Z:IGN FR:Z:unmatched`
+        #[arg(long, verbatim_doc_comment)]
+        unmatched_output: Option<String>,
+
+        /// the command to run for each group. the reads of each group are passed as .fastq
+        /// standard input, and standard output is collected in group order. the following
+        /// environment variables are set for each group:
+        ///   NAILPOLISH_UG         the group index (as in the `UG` tag)
+        ///   NAILPOLISH_ID         the group identifier, i.e. BC_UMI
+        ///   NAILPOLISH_SAMPLE     the sample, if the index records samples
+        ///   NAILPOLISH_BC         the barcode
+        ///   NAILPOLISH_UMI        the UMI
+        ///   NAILPOLISH_SIZE       the number of reads in the group
+        ///   NAILPOLISH_AVG_QUAL   the average PHRED quality of the group
+        /// if no command is given, each read is tagged and written directly
+        #[arg(trailing_var_arg = true, verbatim_doc_comment)]
+        command: Vec<String>,
+    },
+
+    /// Write the reads of chosen barcodes, UMIs or identifiers, reading them directly from their
+    /// positions in the input
+    #[command(
+        arg_required_else_help = true,
+        group(
+            clap::ArgGroup::new("selection")
+                .required(true)
+                .multiple(true)
+                .args(["sample", "barcode", "umi", "id", "ids"])
+        )
+    )]
+    Query {
+        /// the index file
+        #[arg(long)]
+        index: String,
+
+        /// the input .fastq
+        #[arg(long)]
+        input: String,
+
+        /// the output .fastq, or standard output if not given. compression is inferred from the
+        /// file extension (.gz, .bgz, .zst)
+        #[arg(short, verbatim_doc_comment)]
+        output: Option<String>,
+
+        /// only write reads from these samples
+        #[arg(long, value_delimiter = ',')]
+        sample: Vec<String>,
+
+        /// only write reads with these barcodes, i.e. every component of the identifier except
+        /// the UMI, joined by underscores
+        #[arg(long, value_delimiter = ',', verbatim_doc_comment)]
+        barcode: Vec<String>,
+
+        /// only write reads with these UMIs
+        #[arg(long, value_delimiter = ',')]
+        umi: Vec<String>,
+
+        /// only write reads with these identifiers, e.g. BC_UMI
+        #[arg(long, value_delimiter = ',')]
+        id: Vec<String>,
+
+        /// only write reads with the identifiers in this file, with one identifier per line
+        #[arg(long)]
+        ids: Option<String>,
+
+        /// also write reads which were filtered out when the index was created
+        #[arg(long)]
+        include_ignored: bool,
+    },
+
+    /// Apply new length and quality filters to an index, without reading the .fastq again
+    #[command(
+        arg_required_else_help = true,
+        group(
+            clap::ArgGroup::new("filters")
+                .required(true)
+                .multiple(true)
+                .args(["len", "qual"])
+        )
+    )]
+    Filter {
+        /// the index file
+        #[arg(long)]
+        index: String,
+
+        /// the output index. if not given, the index is rewritten in place
+        #[arg(short)]
+        output: Option<String>,
+
+        /// filter lengths to a value within the given float interval [a,b].
+        /// if not given, the length filter of the index is kept. see `index --help`
+        #[arg(long, value_parser = |x: &str| ArgInterval::try_from(x), verbatim_doc_comment)]
+        len: Option<ArgInterval>,
+
+        /// filter average read quality to a value within the given float interval [a,b].
+        /// if not given, the quality filter of the index is kept. see `index --help`
+        #[arg(long, value_parser = |x: &str| ArgInterval::try_from(x), verbatim_doc_comment)]
+        qual: Option<ArgInterval>,
+    },
+
+    /// Write a smaller .fastq and a matching index, containing chosen barcodes or a random
+    /// fraction of molecules
+    #[command(
+        arg_required_else_help = true,
+        group(
+            clap::ArgGroup::new("selection")
+                .required(true)
+                .multiple(true)
+                .args(["sample", "barcode", "barcodes", "fraction"])
+        )
+    )]
+    Subset {
+        /// the index file
+        #[arg(long)]
+        index: String,
+
+        /// the input .fastq
+        #[arg(long)]
+        input: String,
+
+        /// the output .fastq, which is not compressed
+        #[arg(short)]
+        output: String,
+
+        /// the output index of the subset
+        #[arg(long)]
+        index_output: String,
+
+        /// only keep reads from these samples
+        #[arg(long, value_delimiter = ',')]
+        sample: Vec<String>,
+
+        /// only keep reads with these barcodes. see `query --help`
+        #[arg(long, value_delimiter = ',')]
+        barcode: Vec<String>,
+
+        /// only keep reads with the barcodes in this file, with one barcode per line
+        #[arg(long)]
+        barcodes: Option<String>,
+
+        /// only keep this fraction of molecules, chosen at random. every read of a chosen molecule
+        /// is kept
+        #[arg(long, verbatim_doc_comment)]
+        fraction: Option<f64>,
+
+        /// the random seed used to choose molecules
+        #[arg(long, default_value_t = 1)]
+        seed: u64,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum PresetsCommands {
+    /// Show each preset with its regex, components and an example header
+    List {
+        /// a TOML or YAML file of user presets. see `index --help`
+        #[arg(long, env = "NAILPOLISH_PRESETS")]
+        presets: Option<String>,
     },
 }
 
@@ -208,6 +769,13 @@ impl<'a> TryFrom<&'a str> for ArgInterval {
     }
 }
 
+/// Formats the interval in the same `a,b` form that it is parsed from.
+impl std::fmt::Display for ArgInterval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{},{}", self.min, self.max)
+    }
+}
+
 impl ArgInterval {
     pub fn contains(&self, v: f64) -> bool {
         let v = v as f64;
@@ -0,0 +1,260 @@
+use anyhow::{Context, Result};
+use flate2::write::{DeflateEncoder, GzEncoder};
+use rayon::prelude::*;
+use std::io::{BufWriter, Write};
+
+/// Compression formats which can be used for `.fastq` output.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// plain, uncompressed text
+    None,
+
+    /// gzip, written as a series of independently compressed gzip members
+    Gzip,
+
+    /// blocked gzip, as used by `samtools`/`htslib` and readable by any gzip decoder
+    Bgzf,
+
+    /// Zstandard
+    Zstd,
+}
+
+impl Compression {
+    /// Infers the compression format from the extension of an output path, defaulting to
+    /// no compression if the extension is not recognised.
+    pub fn from_path(path: &str) -> Self {
+        let path = path.to_lowercase();
+
+        if path.ends_with(".bgz") || path.ends_with(".bgzf") {
+            Compression::Bgzf
+        } else if path.ends_with(".gz") {
+            Compression::Gzip
+        } else if path.ends_with(".zst") || path.ends_with(".zstd") {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// The file extension conventionally used for this compression format, including the
+    /// leading `.`, or an empty string for no compression.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Bgzf => ".bgz",
+            Compression::Zstd => ".zst",
+        }
+    }
+}
+
+/// The uncompressed size of each gzip member. Each member is compressed independently, so this
+/// is the unit of work given to each thread.
+const GZIP_BLOCK_SIZE: usize = 1024usize.pow(2);
+
+/// The maximum uncompressed size of a BGZF block, chosen so that the compressed block
+/// (including the header and footer) always fits in the 16-bit `BSIZE` field.
+const BGZF_BLOCK_SIZE: usize = 0xff00;
+
+/// The empty block which marks the end of a BGZF file.
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// The number of blocks buffered per thread before a batch is compressed in parallel.
+const BLOCKS_PER_THREAD: usize = 4;
+
+#[derive(Copy, Clone)]
+enum BlockFormat {
+    Gzip,
+    Bgzf,
+}
+
+impl BlockFormat {
+    fn block_size(&self) -> usize {
+        match self {
+            BlockFormat::Gzip => GZIP_BLOCK_SIZE,
+            BlockFormat::Bgzf => BGZF_BLOCK_SIZE,
+        }
+    }
+
+    /// Compresses a single block of uncompressed data into a self-contained gzip member.
+    fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            BlockFormat::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            BlockFormat::Bgzf => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                let cdata = encoder.finish()?;
+
+                let mut crc = flate2::Crc::new();
+                crc.update(data);
+
+                // header (18 bytes) + compressed data + CRC32 and ISIZE (8 bytes)
+                let block_size = 18 + cdata.len() + 8;
+                let mut block = Vec::with_capacity(block_size);
+                block.extend_from_slice(&[
+                    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42,
+                    0x43, 0x02, 0x00,
+                ]);
+                block.extend_from_slice(&((block_size - 1) as u16).to_le_bytes());
+                block.extend_from_slice(&cdata);
+                block.extend_from_slice(&crc.sum().to_le_bytes());
+                block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+                Ok(block)
+            }
+        }
+    }
+}
+
+/// A writer which splits its input into fixed-size blocks, and compresses batches of blocks in
+/// parallel before writing them out in order.
+pub struct BlockWriter {
+    inner: BufWriter<Box<dyn Write + Send>>,
+    format: BlockFormat,
+    buf: Vec<u8>,
+    batch_size: usize,
+    pool: rayon::ThreadPool,
+}
+
+impl BlockWriter {
+    fn new(inner: Box<dyn Write + Send>, format: BlockFormat, threads: usize) -> Result<Self> {
+        let batch_size = format.block_size() * BLOCKS_PER_THREAD * threads;
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .context("Could not create compression thread pool")?;
+
+        Ok(BlockWriter {
+            inner: BufWriter::new(inner),
+            format,
+            buf: Vec::with_capacity(batch_size),
+            batch_size,
+            pool,
+        })
+    }
+
+    /// Compresses and writes out everything which is currently buffered.
+    fn write_batch(&mut self) -> std::io::Result<()> {
+        let format = self.format;
+        let buf = &self.buf;
+
+        let blocks = self.pool.install(|| {
+            buf.par_chunks(format.block_size())
+                .map(|chunk| format.compress(chunk))
+                .collect::<std::io::Result<Vec<_>>>()
+        })?;
+
+        for block in blocks {
+            self.inner.write_all(&block)?;
+        }
+        self.buf.clear();
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> std::io::Result<()> {
+        self.write_batch()?;
+
+        if matches!(self.format, BlockFormat::Bgzf) {
+            self.inner.write_all(&BGZF_EOF)?;
+        }
+
+        self.inner.flush()
+    }
+}
+
+impl Write for BlockWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        self.buf.extend_from_slice(data);
+
+        if self.buf.len() >= self.batch_size {
+            self.write_batch()?;
+        }
+
+        Ok(data.len())
+    }
+
+    /// Flushing only flushes whole blocks, so that the block boundaries (and hence the output)
+    /// do not depend on how often the writer is flushed.
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// An output writer, which may compress its output. `finish()` must be called once writing is
+/// complete, so that any trailing compressed data is written.
+pub enum OutputWriter {
+    Plain(BufWriter<Box<dyn Write + Send>>),
+    Block(BlockWriter),
+    Zstd(zstd::Encoder<'static, BufWriter<Box<dyn Write + Send>>>),
+}
+
+impl OutputWriter {
+    /// Wraps `inner` in a writer which compresses with the given format, using up to `threads`
+    /// threads for compression.
+    pub fn new(
+        inner: Box<dyn Write + Send>,
+        compression: Compression,
+        threads: usize,
+    ) -> Result<Self> {
+        let threads = threads.max(1);
+
+        let writer = match compression {
+            Compression::None => OutputWriter::Plain(BufWriter::new(inner)),
+            Compression::Gzip => {
+                OutputWriter::Block(BlockWriter::new(inner, BlockFormat::Gzip, threads)?)
+            }
+            Compression::Bgzf => {
+                OutputWriter::Block(BlockWriter::new(inner, BlockFormat::Bgzf, threads)?)
+            }
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(BufWriter::new(inner), 0)?;
+                if threads > 1 {
+                    encoder
+                        .multithread(threads as u32)
+                        .context("Could not enable multithreaded zstd compression")?;
+                }
+                OutputWriter::Zstd(encoder)
+            }
+        };
+
+        Ok(writer)
+    }
+
+    /// Writes any remaining buffered data, along with any format-specific trailers.
+    pub fn finish(self) -> Result<()> {
+        match self {
+            OutputWriter::Plain(mut w) => w.flush()?,
+            OutputWriter::Block(w) => w.finish()?,
+            OutputWriter::Zstd(w) => w.finish()?.flush()?,
+        };
+
+        Ok(())
+    }
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+        match self {
+            OutputWriter::Plain(w) => w.write(data),
+            OutputWriter::Block(w) => w.write(data),
+            OutputWriter::Zstd(w) => w.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            OutputWriter::Plain(w) => w.flush(),
+            OutputWriter::Block(w) => w.flush(),
+            OutputWriter::Zstd(w) => w.flush(),
+        }
+    }
+}
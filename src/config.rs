@@ -0,0 +1,340 @@
+use anyhow::{bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::ffi::OsString;
+use std::path::Path;
+
+/// Arguments which are never read from, or written to, a configuration file.
+const IGNORED_ARGS: [&str; 4] = ["help", "version", "config", "dump_config"];
+
+/// The formats that a configuration file can be written in.
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum ConfigFormat {
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Infers the format of a configuration file from its extension.
+    fn from_path(path: &str) -> Result<Self> {
+        let extension = Path::new(path)
+            .extension()
+            .map(|x| x.to_string_lossy().to_lowercase());
+
+        match extension.as_deref() {
+            Some("toml") => Ok(ConfigFormat::Toml),
+            Some("yaml" | "yml") => Ok(ConfigFormat::Yaml),
+            _ => bail!("{path} should have a .toml, .yaml or .yml extension"),
+        }
+    }
+}
+
+/// Reads and deserializes a TOML or YAML file, with the format inferred from its extension.
+///
+/// # Arguments
+///
+/// * `path` - The path to a file with a .toml, .yaml or .yml extension.
+///
+/// # Returns
+///
+/// * `Result<T>` - The deserialized contents of the file.
+pub fn read_file<T: DeserializeOwned>(path: &str) -> Result<T> {
+    let format = ConfigFormat::from_path(path)?;
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("Could not read {path}"))?;
+
+    match format {
+        ConfigFormat::Toml => {
+            toml::from_str(&contents).with_context(|| format!("Could not parse {path} as TOML"))
+        }
+        ConfigFormat::Yaml => serde_yaml::from_str(&contents)
+            .with_context(|| format!("Could not parse {path} as YAML")),
+    }
+}
+
+/// Reads a TOML or YAML configuration file into a JSON value, so that both formats can be
+/// handled in the same way.
+fn read_config(path: &str) -> Result<Map<String, Value>> {
+    match read_file(path)? {
+        Value::Object(map) => Ok(map),
+        Value::Null => Ok(Map::new()),
+        _ => bail!("Config file {path} should contain a table of options"),
+    }
+}
+
+/// Converts a configuration value into the strings that would be passed on the command line.
+fn value_strings(key: &str, value: &Value) -> Result<Vec<String>> {
+    match value {
+        Value::String(s) => Ok(vec![s.clone()]),
+        Value::Number(n) => Ok(vec![n.to_string()]),
+        Value::Bool(b) => Ok(vec![b.to_string()]),
+        Value::Array(values) => values
+            .iter()
+            .map(|v| match v {
+                Value::Array(_) | Value::Object(_) | Value::Null => {
+                    bail!("Config option `{key}` should be a list of plain values")
+                }
+                v => Ok(value_strings(key, v)?.remove(0)),
+            })
+            .collect(),
+        Value::Null => Ok(vec![]),
+        Value::Object(_) => bail!("Config option `{key}` should not be a table"),
+    }
+}
+
+/// Finds the value of the `--config` option, whether it was given before or after the subcommand.
+fn config_path(matches: &ArgMatches) -> Option<String> {
+    let find = |m: &ArgMatches| m.try_get_one::<String>("config").ok().flatten().cloned();
+
+    find(matches).or_else(|| matches.subcommand().and_then(|(_, sub)| find(sub)))
+}
+
+/// Adds the options given in a `--config` file to the command line arguments, so that they can be
+/// parsed as usual. Options which are explicitly given on the command line take precedence over
+/// the configuration file.
+///
+/// The configuration file contains one table per subcommand, such as `[index]` or `[call]`, with
+/// keys named after the long form of each option (either `skip-unmatched` or `skip_unmatched`).
+/// Options at the top level apply to every subcommand which accepts them, so shared options such
+/// as `threads` only need to be given once. Flags are given as booleans, and options which may be
+/// given multiple times as lists.
+///
+/// # Arguments
+///
+/// * `command` - The clap `Command` describing the command line interface.
+/// * `args` - The command line arguments, including the program name.
+///
+/// # Returns
+///
+/// * `Result<Vec<OsString>>` - The command line arguments with the configuration file applied.
+pub fn apply_config(command: Command, args: Vec<OsString>) -> Result<Vec<OsString>> {
+    // the arguments are parsed leniently first, to find the config file and the subcommand
+    let Ok(matches) = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+    else {
+        return Ok(args);
+    };
+
+    let Some(path) = config_path(&matches) else {
+        return Ok(args);
+    };
+    let Some((name, sub_matches)) = matches.subcommand() else {
+        return Ok(args);
+    };
+    let subcommand = command
+        .find_subcommand(name)
+        .expect("subcommand was matched by clap");
+
+    let mut config = read_config(&path)?;
+    let section = match config.remove(name) {
+        Some(Value::Object(section)) => section,
+        Some(_) => bail!("Config section `{name}` in {path} should be a table"),
+        None => Map::new(),
+    };
+
+    // top-level options are shared between subcommands, so only those which apply are used
+    let shared = config
+        .into_iter()
+        .filter(|(_, value)| !value.is_object())
+        .map(|(key, value)| (key, value, false));
+    let specific = section.into_iter().map(|(key, value)| (key, value, true));
+
+    let mut options = Vec::new();
+    let mut positionals = Vec::new();
+    let mut trailing = Vec::new();
+
+    for (key, value, required) in shared.chain(specific) {
+        let id = key.replace('-', "_");
+        let arg = subcommand
+            .get_arguments()
+            .find(|arg| arg.get_id() == id.as_str() && !IGNORED_ARGS.contains(&id.as_str()));
+
+        let Some(arg) = arg else {
+            if required {
+                bail!("Unknown option `{key}` for `{name}` in config {path}");
+            }
+            continue;
+        };
+
+        // options given on the command line or by environment variables override the config file,
+        // including any options which conflict with it
+        let overridden = std::iter::once(arg)
+            .chain(subcommand.get_arg_conflicts_with(arg))
+            .any(|a| is_explicit(sub_matches.value_source(a.get_id().as_str())));
+        if overridden {
+            debug!("Option `{key}` was overridden on the command line, ignoring config value");
+            continue;
+        }
+
+        let values = value_strings(&key, &value)?;
+
+        if arg.is_positional() {
+            if arg.is_trailing_var_arg_set() {
+                trailing.extend(values);
+            } else {
+                positionals.push((arg.get_index().unwrap_or_default(), values));
+            }
+        } else if !arg.get_action().takes_values() {
+            // flags are only added if they are enabled
+            if values.iter().any(|v| v == "true") {
+                options.push(flag_name(arg));
+            }
+        } else {
+            for value in values {
+                match arg.get_long() {
+                    // use `=`, so that values such as `-inf,inf` are not mistaken for flags
+                    Some(long) => options.push(format!("--{long}={value}")),
+                    None => options.push(format!("{}{value}", flag_name(arg))),
+                }
+            }
+        }
+    }
+
+    if options.is_empty() && positionals.is_empty() && trailing.is_empty() {
+        return Ok(args);
+    }
+    info!("Using options from config {path}");
+
+    // options are inserted directly after the subcommand, and positionals after the positionals
+    // already given, but before any `--` separating trailing arguments
+    let subcommand_position = args
+        .iter()
+        .position(|arg| arg.to_str() == Some(name))
+        .unwrap_or(1);
+    let separator = args.iter().position(|arg| arg == "--");
+
+    let mut result: Vec<OsString> = args[..=subcommand_position].to_vec();
+    result.extend(options.into_iter().map(OsString::from));
+
+    let (rest, tail) = match separator {
+        Some(position) => {
+            args[subcommand_position + 1..].split_at(position - subcommand_position - 1)
+        }
+        None => (&args[subcommand_position + 1..], &[][..]),
+    };
+    result.extend_from_slice(rest);
+
+    positionals.sort_by_key(|(index, _)| *index);
+    result.extend(
+        positionals
+            .into_iter()
+            .flat_map(|(_, v)| v)
+            .map(OsString::from),
+    );
+
+    if !trailing.is_empty() && tail.is_empty() {
+        result.push(OsString::from("--"));
+        result.extend(trailing.into_iter().map(OsString::from));
+    } else {
+        result.extend_from_slice(tail);
+    }
+
+    Ok(result)
+}
+
+/// Whether a value was given explicitly, rather than from a default.
+fn is_explicit(source: Option<ValueSource>) -> bool {
+    matches!(
+        source,
+        Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+    )
+}
+
+/// Returns the flag used to pass an argument on the command line, e.g. `--threads` or `-o`.
+fn flag_name(arg: &Arg) -> String {
+    match (arg.get_long(), arg.get_short()) {
+        (Some(long), _) => format!("--{long}"),
+        (None, Some(short)) => format!("-{short}"),
+        (None, None) => unreachable!("only positional arguments have no flag"),
+    }
+}
+
+/// Writes the effective configuration of a subcommand, including default values, to a TOML or
+/// YAML file. The file can later be passed to `--config` to repeat the same run.
+///
+/// # Arguments
+///
+/// * `command` - The clap `Command` describing the command line interface.
+/// * `matches` - The parsed command line arguments.
+/// * `output` - The path to write the configuration to, with a .toml, .yaml or .yml extension.
+///
+/// # Returns
+///
+/// * `Result<()>` - Returns an `Ok(())` if successful, or an `anyhow::Error` if an error occurs.
+pub fn dump_config(command: &Command, matches: &ArgMatches, output: &str) -> Result<()> {
+    let format = ConfigFormat::from_path(output)?;
+
+    let Some((name, sub_matches)) = matches.subcommand() else {
+        bail!("No subcommand was given, so there is no configuration to write");
+    };
+    let subcommand = command
+        .find_subcommand(name)
+        .expect("subcommand was matched by clap");
+
+    let mut section = Map::new();
+    for arg in subcommand.get_arguments() {
+        let id = arg.get_id().as_str();
+        if IGNORED_ARGS.contains(&id) {
+            continue;
+        }
+
+        // defaults are not written for options which conflict with one that was given, such as
+        // the preset when a barcode regex is used
+        let is_default = sub_matches.value_source(id) == Some(ValueSource::DefaultValue);
+        let conflicting = subcommand
+            .get_arg_conflicts_with(arg)
+            .iter()
+            .any(|a| is_explicit(sub_matches.value_source(a.get_id().as_str())));
+        if is_default && conflicting {
+            continue;
+        }
+
+        let Some(raw) = sub_matches.get_raw(id) else {
+            continue;
+        };
+        let values: Vec<String> = raw.map(|x| x.to_string_lossy().to_string()).collect();
+
+        let value = if !arg.get_action().takes_values() {
+            Value::Bool(values.iter().any(|v| v == "true"))
+        } else if matches!(arg.get_action(), ArgAction::Append) || arg.is_trailing_var_arg_set() {
+            Value::Array(values.into_iter().map(Value::String).collect())
+        } else {
+            match values.into_iter().next() {
+                Some(value) => typed_value(value),
+                None => continue,
+            }
+        };
+
+        section.insert(id.to_string(), value);
+    }
+
+    let mut config = Map::new();
+    config.insert(name.to_string(), Value::Object(section));
+    let config = Value::Object(config);
+
+    let contents = match format {
+        ConfigFormat::Toml => toml::to_string_pretty(&config)?,
+        ConfigFormat::Yaml => serde_yaml::to_string(&config)?,
+    };
+    std::fs::write(output, contents)
+        .with_context(|| format!("Could not write config to {output}"))?;
+
+    info!("Wrote effective config to {output}");
+    Ok(())
+}
+
+/// Converts a command line value into a number if possible, so that the configuration file is
+/// easier to read and edit.
+fn typed_value(value: String) -> Value {
+    if let Ok(n) = value.parse::<i64>() {
+        return Value::from(n);
+    }
+    match value.parse::<f64>() {
+        Ok(n) if n.is_finite() => Value::from(n),
+        _ => Value::String(value),
+    }
+}
@@ -0,0 +1,263 @@
+use crate::preset::Preset;
+use anyhow::{bail, Context, Result};
+use indexmap::IndexMap;
+use regex::{Captures, Regex};
+use std::collections::{BTreeSet, HashSet};
+use std::fmt::Write;
+
+/// The name of the preset which detects the header format from the input.
+pub const AUTO_PRESET: &str = "auto";
+
+/// The minimum proportion of sampled headers which a preset must match to be chosen.
+const MIN_MATCH_RATE: f64 = 0.9;
+
+/// The minimum length of a run of bases to be considered a barcode or UMI when suggesting a regex.
+const MIN_SUGGESTED_LENGTH: usize = 6;
+
+/// How well a candidate preset matched the sampled headers.
+///
+/// # Fields
+///
+/// * `matched` - The number of headers which the regex matched.
+/// * `clean` - The number of headers where every component was matched in full, rather than as a
+///   part of a longer run of bases (as when a 10bp UMI regex is used on 12bp UMIs).
+struct CandidateScore<'a> {
+    preset: &'a Preset,
+    matched: usize,
+    clean: usize,
+}
+
+impl CandidateScore<'_> {
+    /// The key used to rank candidates: headers matched in full, then headers matched at all, then
+    /// the number of components (so that BARCODE_UMI is preferred over UMI alone), and then
+    /// whether the UMI has a fixed length.
+    fn rank(&self) -> (usize, usize, usize, bool) {
+        (
+            self.clean,
+            self.matched,
+            self.preset.components.len(),
+            self.preset.umi_length.is_some(),
+        )
+    }
+}
+
+fn is_base(c: Option<char>) -> bool {
+    c.is_some_and(|c| "ACGTNacgtn".contains(c))
+}
+
+/// Whether the components of a match are complete, i.e. not directly preceded or followed by
+/// further bases which were left out of the match.
+fn is_clean_match(header: &str, captures: &Captures) -> bool {
+    let mut groups = captures.iter().skip(1).flatten();
+    let (Some(first), last) = (groups.next(), groups.last()) else {
+        return false;
+    };
+    let last = last.unwrap_or(first);
+
+    !is_base(header[..first.start()].chars().last())
+        && !is_base(header[last.end()..].chars().next())
+}
+
+/// Reads the headers of up to `sample_size` reads from the start of a .fastq file.
+fn sample_headers(input: &str, sample_size: usize) -> Result<Vec<String>> {
+    let mut reader = needletail::parse_fastx_file(input)
+        .with_context(|| format!("Could not open {input} to detect the header format"))?;
+
+    let mut headers = Vec::with_capacity(sample_size);
+    while let Some(record) = reader.next() {
+        if headers.len() >= sample_size {
+            break;
+        }
+        let record = record.with_context(|| format!("Could not read a record of {input}"))?;
+        headers.push(String::from_utf8_lossy(record.id()).to_string());
+    }
+
+    Ok(headers)
+}
+
+/// Scores each preset against the sampled headers, ordered from the best match to the worst.
+fn score_presets<'a>(presets: &'a [Preset], headers: &[String]) -> Result<Vec<CandidateScore<'a>>> {
+    let mut scores = presets
+        .iter()
+        .map(|preset| {
+            let re = Regex::new(&preset.regex)?;
+            let mut score = CandidateScore {
+                preset,
+                matched: 0,
+                clean: 0,
+            };
+
+            for header in headers {
+                if let Some(captures) = re.captures(header) {
+                    score.matched += 1;
+                    score.clean += is_clean_match(header, &captures) as usize;
+                }
+            }
+            Ok(score)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // the sort is stable, so ties are kept in the order that the presets are listed in
+    scores.sort_by_key(|score| std::cmp::Reverse(score.rank()));
+    Ok(scores)
+}
+
+/// Suggests a regex for headers that no preset matches, by finding runs of bases which appear in
+/// most headers after the same tag (such as `CB:Z:` or `BC=`) or delimiter (such as `_`).
+///
+/// # Returns
+///
+/// * `Option<String>` - The suggested regex, or `None` if no consistent runs of bases were found.
+fn suggest_regex(headers: &[String]) -> Option<String> {
+    let run_re = Regex::new(&format!("[ACGTN]{{{MIN_SUGGESTED_LENGTH},}}")).unwrap();
+    let tag_re = Regex::new(r"([A-Za-z]{2}:[A-Za-z]:|[A-Za-z_]+[:=])$").unwrap();
+
+    // for each context, the number of headers it appears in and the lengths of the runs after it,
+    // in the order that they were first seen
+    let mut contexts: IndexMap<String, (usize, BTreeSet<usize>)> = IndexMap::new();
+
+    for header in headers {
+        let mut seen = HashSet::new();
+
+        for run in run_re.find_iter(header) {
+            let before = header[..run.start()].chars().last();
+            let after = header[run.end()..].chars().next();
+
+            // runs which are part of a longer word, such as a hexadecimal read ID, are skipped
+            if before.is_some_and(|c| c.is_ascii_alphanumeric())
+                || after.is_some_and(|c| c.is_ascii_alphanumeric())
+            {
+                continue;
+            }
+
+            let context = match (tag_re.find(&header[..run.start()]), before) {
+                (Some(tag), _) => tag.as_str().to_string(),
+                (None, Some(c)) => c.to_string(),
+                (None, None) => "^".to_string(),
+            };
+
+            // only the first run after each context is used
+            if seen.insert(context.clone()) {
+                let (count, lengths) = contexts.entry(context).or_default();
+                *count += 1;
+                lengths.insert(run.len());
+            }
+        }
+    }
+
+    let min_count = (headers.len() as f64 * MIN_MATCH_RATE).ceil() as usize;
+    let parts = contexts
+        .iter()
+        .filter(|(_, (count, _))| *count >= min_count.max(1))
+        .map(|(context, (_, lengths))| {
+            let group = match lengths.len() {
+                1 => format!("([ACGTN]{{{}}})", lengths.first().unwrap()),
+                _ => "([ACGTN]+)".to_string(),
+            };
+            match context.as_str() {
+                "^" => format!("^{group}"),
+                context => format!("{}{group}", regex::escape(context)),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    (!parts.is_empty()).then(|| parts.join(".*?"))
+}
+
+/// Detects the header format of a .fastq file, by testing each preset against the headers of the
+/// first `sample_size` reads and choosing the one which matches best. If no preset matches enough
+/// of the headers, an error is returned which lists the match rate of each preset and suggests a
+/// regex.
+///
+/// # Arguments
+///
+/// * `input` - The path to the input .fastq file.
+/// * `presets` - The presets to test, from `preset::load_presets`.
+/// * `sample_size` - The number of reads to sample from the start of the file.
+///
+/// # Returns
+///
+/// * `Result<Preset>` - The preset which matches the headers best.
+pub fn detect_preset(input: &str, presets: &[Preset], sample_size: usize) -> Result<Preset> {
+    let headers = sample_headers(input, sample_size)?;
+    if headers.is_empty() {
+        bail!("Could not detect the header format of {input}, as it contains no reads");
+    }
+
+    let total = headers.len();
+    let rate = |n: usize| n as f64 / total as f64;
+
+    let scores = score_presets(presets, &headers)?;
+    for score in scores.iter() {
+        debug!(
+            "Preset {} matched {:.1}% of headers ({:.1}% in full)",
+            score.preset.name,
+            rate(score.matched) * 100.0,
+            rate(score.clean) * 100.0
+        );
+    }
+
+    let best = &scores[0];
+    let suggestion = suggest_regex(&headers);
+
+    if rate(best.clean) >= MIN_MATCH_RATE {
+        info!(
+            "Detected preset {} from the first {total} reads, matching {:.1}% of headers",
+            best.preset.name,
+            rate(best.clean) * 100.0
+        );
+
+        // a UMI-only preset may match headers which also contain a barcode, such as a `:UMI`
+        // suffix following a `bc:BARCODE` field, so warn if some runs of bases are not used
+        let preset_groups = Regex::new(&best.preset.regex)?.captures_len() - 1;
+        if let Some(suggestion) = suggestion {
+            let suggestion_groups = Regex::new(&suggestion)?.captures_len() - 1;
+            if suggestion_groups > preset_groups {
+                warn!(
+                    "The headers contain {suggestion_groups} barcode-like sequences, but preset {} \
+                    only extracts {preset_groups}. If this is wrong, pass --barcode-regex '{suggestion}'",
+                    best.preset.name
+                );
+            }
+        }
+
+        return Ok(best.preset.clone());
+    }
+
+    // writing to a String never fails, so the results of writeln! are ignored below
+    let mut message = format!(
+        "could not detect the header format from the first {total} reads of {input}.\n\
+        percentage of headers matched by each preset:\n"
+    );
+    for score in scores.iter() {
+        let _ = writeln!(
+            message,
+            "    {:<16}{:>6.1}%",
+            score.preset.name,
+            rate(score.clean) * 100.0
+        );
+    }
+
+    match suggestion {
+        Some(suggestion) => {
+            let re = Regex::new(&suggestion)?;
+            let matched = headers.iter().filter(|h| re.is_match(h)).count();
+            let _ = write!(
+                message,
+                "suggestion: pass --barcode-regex '{suggestion}', which matches {:.1}% of these \
+                headers, or add it as a preset using --presets",
+                rate(matched) * 100.0
+            );
+        }
+        None => {
+            let _ = write!(
+                message,
+                "suggestion: pass a --barcode-regex with a capture group for the barcode and UMI, \
+                for example headers such as\n    {}",
+                headers[0]
+            );
+        }
+    }
+
+    bail!(message)
+}
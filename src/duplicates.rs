@@ -1,11 +1,13 @@
 use crate::index::{IndexReader, IndexRecord};
 use crate::io::Record;
-use anyhow::{ensure, Context, Result};
+use crate::packed;
+use anyhow::{bail, ensure, Context, Result};
 use indexmap::IndexMap;
 use serde::de::Error;
 use serde::{Deserialize, Serialize};
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{BuildHasher, RandomState};
 use std::ops::Index;
 use std::rc::Rc;
 use std::sync::Arc;
@@ -21,82 +23,523 @@ pub struct RecordPosition {
     pub pos: usize,
     pub length: usize,
 }
-// pub type DuplicateMap = IndexMap<RecordIdentifier, Vec<RecordPosition>>;
-
+/// A map from each read to its group of duplicates, i.e. the reads which share the same sample and
+/// identifier. This is designed to stay compact for 100M+ reads: each read is stored as a file
+/// offset, length and `u32` group ID, and each group as a packed identifier (see `packed.rs`) with
+/// its reads stored contiguously.
+///
+/// Reads are added with `insert`, and `finish` must be called before the map is used.
+#[derive(Default)]
 pub struct DuplicateMap {
-    pub by_id: IndexMap<RecordIdentifier, Vec<RecordPosition>>,
-    pub pos_to_id: IndexMap<usize, RecordIdentifier>,
+    /// The position in the input file of each read, in the order that reads were inserted.
+    positions: Vec<u64>,
+    /// The length in bytes of each read in the input file.
+    lengths: Vec<u32>,
+    /// The group of each read.
+    groups: Vec<u32>,
+    /// Whether reads were not inserted in order of position, in which case `by_position` is used
+    /// to find reads by position.
+    unsorted: bool,
+    /// The reads, ordered by position. This is only used if `unsorted` is set.
+    by_position: Vec<u32>,
+
+    /// The packed identifier of every group, concatenated.
+    keys: Vec<u8>,
+    /// The start of the key of each group within `keys`.
+    key_offsets: Vec<u64>,
+    /// The interned samples, which packed identifiers refer to by index.
+    samples: Vec<String>,
+    /// The number of leading components of each identifier which identify the cell.
+    cell: usize,
+
+    /// The reads of each group, stored contiguously. The reads of group `g` are
+    /// `members[group_starts[g]..group_starts[g + 1]]`, in the order that they were inserted.
+    members: Vec<u32>,
+    group_starts: Vec<u32>,
+
+    /// Lookups which are only used while reads are inserted, and are freed by `finish`.
+    lookup: HashMap<u64, u32>,
+    collisions: HashMap<Box<[u8]>, u32>,
+    sample_lookup: HashMap<String, u32>,
+    hasher: RandomState,
+    scratch: Vec<u8>,
 }
 
 impl DuplicateMap {
     pub fn new() -> Self {
-        DuplicateMap {
-            by_id: Default::default(),
-            pos_to_id: Default::default(),
-        }
+        Self::default()
     }
 
-    pub fn insert(&mut self, record: &IndexRecord) {
-        let id = RecordIdentifier::from_string(&record.id);
+    /// Adds a record to the map, grouped by the identifier components chosen by `grouping`.
+    pub fn insert(&mut self, record: &IndexRecord, grouping: &Grouping) -> Result<()> {
+        let id = grouping.identifier(RecordIdentifier::from_string(&record.id))?;
+        self.cell = id.cell;
 
-        let rec_pos = RecordPosition {
-            pos: record.pos,
-            length: record.rec_len,
+        let sample = match self.sample_lookup.get(&record.sample) {
+            Some(&sample) => sample,
+            None => {
+                let sample = self.samples.len() as u32;
+                self.samples.push(record.sample.clone());
+                self.sample_lookup.insert(record.sample.clone(), sample);
+                sample
+            }
         };
 
-        self.pos_to_id.insert(record.pos, id.clone());
+        let mut key = std::mem::take(&mut self.scratch);
+        key.clear();
+        packed::pack_identifier(sample, &id.components, &mut key);
+        let group = self.intern(&key)?;
+        self.scratch = key;
+
+        let pos = record.pos as u64;
+        if self.positions.last().is_some_and(|&last| last >= pos) {
+            self.unsorted = true;
+        }
+        ensure!(
+            self.positions.len() < u32::MAX as usize,
+            "Indexes with more than {} reads are not supported",
+            u32::MAX
+        );
+
+        self.positions.push(pos);
+        self.lengths.push(
+            u32::try_from(record.rec_len)
+                .with_context(|| format!("Record at position {pos} is too long"))?,
+        );
+        self.groups.push(group);
+
+        Ok(())
+    }
+
+    /// Returns the group of a packed identifier, creating a new group if it has not been seen.
+    fn intern(&mut self, key: &[u8]) -> Result<u32> {
+        let hash = self.hasher.hash_one(key);
+
+        match self.lookup.get(&hash) {
+            None => {
+                let group = self.push_key(key)?;
+                self.lookup.insert(hash, group);
+                Ok(group)
+            }
+            Some(&group) if self.key(group) == key => Ok(group),
+            // a different identifier with the same hash, which is very rare
+            Some(_) => match self.collisions.get(key) {
+                Some(&group) => Ok(group),
+                None => {
+                    let group = self.push_key(key)?;
+                    self.collisions.insert(key.into(), group);
+                    Ok(group)
+                }
+            },
+        }
+    }
+
+    fn push_key(&mut self, key: &[u8]) -> Result<u32> {
+        let group = u32::try_from(self.key_offsets.len())
+            .context("Indexes with more than 2^32 UMI groups are not supported")?;
+        self.key_offsets.push(self.keys.len() as u64);
+        self.keys.extend_from_slice(key);
+        Ok(group)
+    }
+
+    fn key(&self, group: u32) -> &[u8] {
+        let group = group as usize;
+        let start = self.key_offsets[group] as usize;
+        let end = self
+            .key_offsets
+            .get(group + 1)
+            .map_or(self.keys.len(), |&end| end as usize);
+        &self.keys[start..end]
+    }
+
+    /// Groups the reads of each group together, and frees the memory used while inserting reads.
+    /// This must be called after every read has been inserted.
+    pub fn finish(&mut self) {
+        let group_count = self.group_count();
+
+        // count the reads in each group, then convert these counts into start offsets
+        let mut starts = vec![0u32; group_count + 1];
+        for &group in self.groups.iter() {
+            starts[group as usize + 1] += 1;
+        }
+        for i in 0..group_count {
+            starts[i + 1] += starts[i];
+        }
+
+        let mut next = starts.clone();
+        let mut members = vec![0u32; self.groups.len()];
+        for (read, &group) in self.groups.iter().enumerate() {
+            let slot = &mut next[group as usize];
+            members[*slot as usize] = read as u32;
+            *slot += 1;
+        }
+
+        self.group_starts = starts;
+        self.members = members;
+
+        if self.unsorted {
+            let mut by_position = (0..self.positions.len() as u32).collect::<Vec<_>>();
+            by_position.sort_by_key(|&read| self.positions[read as usize]);
+            self.by_position = by_position;
+        }
+
+        self.lookup = HashMap::new();
+        self.collisions = HashMap::new();
+        self.sample_lookup = HashMap::new();
+        self.scratch = Vec::new();
+
+        self.positions.shrink_to_fit();
+        self.lengths.shrink_to_fit();
+        self.groups.shrink_to_fit();
+        self.keys.shrink_to_fit();
+        self.key_offsets.shrink_to_fit();
+
+        info!(
+            "Duplicate map uses {:.1} MB for {} reads in {} groups ({:.1} bytes per read)",
+            self.memory_usage() as f64 / 1024f64.powi(2),
+            self.read_count(),
+            group_count,
+            self.memory_usage() as f64 / self.read_count().max(1) as f64
+        );
+    }
+
+    /// The approximate number of bytes of memory used by the map.
+    pub fn memory_usage(&self) -> usize {
+        fn vec_bytes<T>(v: &Vec<T>) -> usize {
+            v.capacity() * std::mem::size_of::<T>()
+        }
+
+        vec_bytes(&self.positions)
+            + vec_bytes(&self.lengths)
+            + vec_bytes(&self.groups)
+            + vec_bytes(&self.by_position)
+            + vec_bytes(&self.keys)
+            + vec_bytes(&self.key_offsets)
+            + vec_bytes(&self.members)
+            + vec_bytes(&self.group_starts)
+            + vec_bytes(&self.scratch)
+            + self.samples.iter().map(|s| s.capacity()).sum::<usize>()
+            // hash tables store one control byte per bucket, and have at most 8/7 buckets per entry
+            + self.lookup.capacity() * (std::mem::size_of::<(u64, u32)>() + 1) * 8 / 7
+            + self.collisions.capacity() * (std::mem::size_of::<(Box<[u8]>, u32)>() + 1) * 8 / 7
+    }
+
+    /// The number of reads in the map.
+    pub fn read_count(&self) -> usize {
+        self.positions.len()
+    }
+
+    /// The number of groups in the map. Groups are numbered from 0 in order of their first read.
+    pub fn group_count(&self) -> usize {
+        self.key_offsets.len()
+    }
+
+    /// Returns the read at a position in the input file, if it is in the map.
+    pub fn read_by_pos(&self, pos: usize) -> Option<u32> {
+        let pos = pos as u64;
+        if self.unsorted {
+            let i = self
+                .by_position
+                .binary_search_by_key(&pos, |&read| self.positions[read as usize])
+                .ok()?;
+            Some(self.by_position[i])
+        } else {
+            self.positions
+                .binary_search(&pos)
+                .ok()
+                .map(|read| read as u32)
+        }
+    }
+
+    /// Returns the group of the read at a position in the input file, if it is in the map.
+    pub fn group_by_pos(&self, pos: usize) -> Option<u32> {
+        self.read_by_pos(pos).map(|read| self.group(read))
+    }
+
+    /// The group of a read.
+    pub fn group(&self, read: u32) -> u32 {
+        self.groups[read as usize]
+    }
+
+    /// The reads of a group, in the order that they were inserted.
+    pub fn group_reads(&self, group: u32) -> &[u32] {
+        let group = group as usize;
+        &self.members[self.group_starts[group] as usize..self.group_starts[group + 1] as usize]
+    }
+
+    /// The number of reads in a group.
+    pub fn group_size(&self, group: u32) -> usize {
+        self.group_reads(group).len()
+    }
 
-        self.by_id
-            .entry(id)
-            .and_modify(|e| e.push(rec_pos))
-            .or_insert(vec![rec_pos]);
+    /// The position and length of a read in the input file.
+    pub fn record_position(&self, read: u32) -> RecordPosition {
+        RecordPosition {
+            pos: self.positions[read as usize] as usize,
+            length: self.lengths[read as usize] as usize,
+        }
     }
 
-    pub fn shrink_to_fit(&mut self) {
-        self.by_id.shrink_to_fit();
-        self.pos_to_id.shrink_to_fit();
+    /// The sample of a group.
+    pub fn sample(&self, group: u32) -> &str {
+        &self.samples[packed::unpack_sample(self.key(group)) as usize]
     }
 
-    pub fn records_by_id(&self, id: &RecordIdentifier) -> Option<&Vec<RecordPosition>> {
-        self.by_id.get(id)
+    /// Unpacks the identifier of a group.
+    pub fn identifier(&self, group: u32) -> RecordIdentifier {
+        let (sample, components) = packed::unpack_identifier(self.key(group));
+        RecordIdentifier {
+            sample: self.samples[sample as usize].clone(),
+            components,
+            cell: self.cell,
+        }
     }
 
-    pub fn records_by_pos(&self, pos: &usize) -> Option<&Vec<RecordPosition>> {
-        let id = self.pos_to_id.get(pos)?;
-        self.records_by_id(id)
+    /// Iterates over every group.
+    pub fn group_ids(&self) -> impl Iterator<Item = u32> {
+        0..self.group_count() as u32
+    }
+
+    /// Computes duplicate statistics for each cell, where a cell is the set of UMI groups which
+    /// share the same sample and cell components (the `barcode` of the `RecordIdentifier`).
+    ///
+    /// # Returns
+    ///
+    /// A vector of `CellStatistics` sorted by descending read count, so that the index of each
+    /// cell is its (0-indexed) barcode rank.
+    pub fn cell_statistics(&self) -> Vec<CellStatistics> {
+        let mut cells: IndexMap<(&str, String), CellStatistics> = IndexMap::new();
+
+        for group in self.group_ids() {
+            let id = self.identifier(group);
+            let size = self.group_size(group);
+
+            let barcode = id.barcode();
+            let cell = cells
+                .entry((self.sample(group), barcode.clone()))
+                .or_insert_with(|| CellStatistics {
+                    sample: id.sample,
+                    barcode,
+                    reads: 0,
+                    umis: 0,
+                    duplicate_reads: 0,
+                    proportion_duplicate: 0.0,
+                });
+
+            cell.reads += size;
+            cell.umis += 1;
+            if size > 1 {
+                cell.duplicate_reads += size;
+            }
+        }
+
+        let mut cells = cells
+            .into_values()
+            .map(|mut cell| {
+                cell.proportion_duplicate = cell.duplicate_reads as f64 / cell.reads as f64;
+                cell
+            })
+            .collect::<Vec<_>>();
+
+        // ties are broken by barcode so that the ranking is deterministic
+        cells.sort_by(|a, b| {
+            b.reads
+                .cmp(&a.reads)
+                .then_with(|| a.barcode.cmp(&b.barcode))
+                .then_with(|| a.sample.cmp(&b.sample))
+        });
+
+        cells
+    }
+
+    /// Computes duplicate statistics for each sample of a multi-sample library.
+    ///
+    /// # Returns
+    ///
+    /// A vector of `SampleStatistics` in the order that each sample first appears in the index,
+    /// which is empty if the index does not record samples.
+    pub fn sample_statistics(&self) -> Vec<SampleStatistics> {
+        let mut samples: IndexMap<&str, SampleStatistics> = IndexMap::new();
+        let mut cells: IndexMap<&str, HashSet<String>> = IndexMap::new();
+
+        for group in self.group_ids() {
+            let name = self.sample(group);
+            if name.is_empty() {
+                continue;
+            }
+            let size = self.group_size(group);
+
+            let sample = samples.entry(name).or_insert_with(|| SampleStatistics {
+                sample: name.to_string(),
+                ..SampleStatistics::default()
+            });
+
+            sample.reads += size;
+            sample.umi_groups += 1;
+            if size > 1 {
+                sample.duplicate_ids += 1;
+                sample.duplicate_reads += size;
+            }
+            cells
+                .entry(name)
+                .or_default()
+                .insert(self.identifier(group).barcode());
+        }
+
+        samples
+            .into_values()
+            .map(|mut sample| {
+                sample.cells = cells[sample.sample.as_str()].len();
+                sample.proportion_duplicate = sample.duplicate_reads as f64 / sample.reads as f64;
+                sample
+            })
+            .collect()
+    }
+
+    /// Computes information about the duplicates in the map. The map must be finished.
+    pub fn statistics(&self) -> DuplicateStatistics {
+        let mut stats = DuplicateStatistics {
+            total_reads: self.read_count(),
+            duplicate_reads: 0,
+            duplicate_ids: 0,
+            proportion_duplicate: 0.0,
+            distribution: BTreeMap::new(),
+        };
+
+        stats.duplicate_reads = self
+            .group_ids()
+            .map(|group| {
+                let length = self.group_size(group);
+                if length > 1 {
+                    stats.duplicate_ids += 1;
+
+                    if let Some(x) = stats.distribution.get_mut(&length) {
+                        *x += 1
+                    } else {
+                        stats.distribution.insert(length, 1);
+                    }
+                    length
+                } else {
+                    0
+                }
+            })
+            .sum();
+
+        stats
+            .distribution
+            .insert(1, stats.total_reads - stats.duplicate_reads);
+
+        stats.proportion_duplicate = stats.duplicate_reads as f64 / stats.total_reads as f64;
+
+        stats
     }
 }
 
-/// A struct representing a record identifier with a head and a tail.
+/// Duplicate statistics for a single sample of a multi-sample library.
 ///
 /// # Fields
 ///
-/// * `head` - The head part of the record identifier.
-/// * `tail` - The tail part of the record identifier.
-#[derive(Eq, PartialEq, Hash, Debug, Serialize, Deserialize, Clone)]
+/// * `sample` - The sample, as extracted from the read header or cluster file.
+/// * `reads` - The number of (non-filtered) reads from this sample.
+/// * `cells` - The number of cell barcodes within this sample.
+/// * `umi_groups` - The number of UMI groups within this sample.
+/// * `duplicate_ids` - The number of UMI groups of size > 1.
+/// * `duplicate_reads` - The number of reads in a UMI group of size > 1.
+/// * `proportion_duplicate` - The proportion of reads which are duplicate reads.
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct SampleStatistics {
+    pub sample: String,
+    pub reads: usize,
+    pub cells: usize,
+    pub umi_groups: usize,
+    pub duplicate_ids: usize,
+    pub duplicate_reads: usize,
+    pub proportion_duplicate: f64,
+}
+
+/// Duplicate statistics for a single cell barcode.
+///
+/// # Fields
+///
+/// * `sample` - The sample that the cell belongs to, which is empty if samples are not recorded.
+/// * `barcode` - The cell barcode.
+/// * `reads` - The number of (non-filtered) reads with this barcode.
+/// * `umis` - The number of unique UMIs, i.e. UMI groups, with this barcode.
+/// * `duplicate_reads` - The number of reads in a UMI group of size > 1.
+/// * `proportion_duplicate` - The proportion of reads which are duplicate reads.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CellStatistics {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub sample: String,
+    pub barcode: String,
+    pub reads: usize,
+    pub umis: usize,
+    pub duplicate_reads: usize,
+    pub proportion_duplicate: f64,
+}
+
+/// A record identifier, made up of an ordered list of components such as a cell barcode and a
+/// UMI. The names of the components are stored once in the index metadata, rather than with each
+/// identifier.
+///
+/// # Fields
+///
+/// * `sample` - The sample that the record belongs to in a multi-sample library, or empty. Reads
+///   are only grouped together if they are from the same sample. This is stored in its own column
+///   of the index, so is not part of the string form of the identifier.
+/// * `components` - The value of each component, in order.
+/// * `cell` - The number of leading components which identify the cell. The remaining components
+///   identify the molecule within the cell.
+#[derive(Eq, PartialEq, Hash, Debug, Clone)]
 pub struct RecordIdentifier {
-    pub head: String,
-    pub tail: String,
+    pub sample: String,
+    pub components: Vec<String>,
+    pub cell: usize,
 }
 
+/// The separator between components when an identifier is written as a string.
+const COMPONENT_SEPARATOR: char = '_';
+
+/// The escape character used when a component contains the separator.
+const COMPONENT_ESCAPE: char = '\\';
+
 /// Implement the `Display` trait for `RecordIdentifier`. This allows a RecordIdentifier to
 /// be converted to a string through `.to_string()` or using format macros. See `.from_string()`
 /// for the inverse function.
 impl std::fmt::Display for RecordIdentifier {
     /// Format the `RecordIdentifier` as a string.
     ///
-    /// If the `tail` is empty, only the `head` is returned.
-    /// Otherwise, the `head` and `tail` are concatenated with an underscore.
+    /// Components are joined with an underscore, e.g. `BARCODE_UMI`. Any underscores (or
+    /// backslashes) within a component are escaped with a backslash, so that the identifier can be
+    /// read back exactly.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.tail.is_empty() {
-            f.write_str(&self.head)
-        } else {
-            write!(f, "{}_{}", self.head, self.tail)
+        for (i, component) in self.components.iter().enumerate() {
+            if i > 0 {
+                write!(f, "{COMPONENT_SEPARATOR}")?;
+            }
+            for c in component.chars() {
+                if c == COMPONENT_SEPARATOR || c == COMPONENT_ESCAPE {
+                    write!(f, "{COMPONENT_ESCAPE}")?;
+                }
+                write!(f, "{c}")?;
+            }
         }
+        Ok(())
     }
 }
 
 impl RecordIdentifier {
+    /// Creates a `RecordIdentifier` from its components. Every component except the last is
+    /// taken to identify the cell, e.g. the barcode of a `BARCODE_UMI` identifier.
+    pub fn new(components: Vec<String>) -> Self {
+        let cell = components.len().saturating_sub(1);
+        RecordIdentifier {
+            sample: String::new(),
+            components,
+            cell,
+        }
+    }
+
     /// Creates a `RecordIdentifier` from a string slice. See `.to_string()` for the inverse
     /// function.
     ///
@@ -106,17 +549,160 @@ impl RecordIdentifier {
     ///
     /// # Returns
     ///
-    /// A `RecordIdentifier` with the head and tail parts extracted from the input string.
+    /// A `RecordIdentifier` with the components separated by (unescaped) underscores.
     pub fn from_string(s: &str) -> Self {
-        let split_loc = match s.find('_') {
-            Some(v) => v,
-            None => s.len() - 1,
+        let mut components = vec![String::new()];
+        let mut chars = s.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                COMPONENT_ESCAPE => {
+                    let escaped = chars.next().unwrap_or(COMPONENT_ESCAPE);
+                    components.last_mut().unwrap().push(escaped);
+                }
+                COMPONENT_SEPARATOR => components.push(String::new()),
+                c => components.last_mut().unwrap().push(c),
+            }
+        }
+
+        Self::new(components)
+    }
+
+    /// The components which identify the cell, joined by underscores.
+    pub fn barcode(&self) -> String {
+        self.components[..self.cell].join("_")
+    }
+
+    /// The components which identify the molecule within the cell, joined by underscores.
+    pub fn umi(&self) -> String {
+        self.components[self.cell..].join("_")
+    }
+}
+
+/// Returns names for identifiers with `count` components, when none were recorded: a single
+/// component is a UMI, two are a barcode and a UMI, and otherwise there are several barcodes
+/// followed by a UMI.
+pub fn default_component_names(count: usize) -> Vec<String> {
+    match count {
+        0 => vec![],
+        1 => vec!["umi".to_string()],
+        2 => vec!["barcode".to_string(), "umi".to_string()],
+        n => (1..n)
+            .map(|i| format!("barcode_{i}"))
+            .chain(std::iter::once("umi".to_string()))
+            .collect(),
+    }
+}
+
+/// The components of the identifier to group reads by, given by name.
+///
+/// # Fields
+///
+/// * `cell` - The components which define a cell. If not given, this is every component except
+///   the last, or every component which is not a molecule component.
+/// * `molecule` - The components which, along with the cell components, define a molecule. If
+///   not given, this is every component which is not a cell component.
+#[derive(Clone, Default, Debug)]
+pub struct GroupingOpts {
+    pub cell: Option<Vec<String>>,
+    pub molecule: Option<Vec<String>>,
+}
+
+/// The positions of the components used to group reads, resolved from `GroupingOpts` using the
+/// component names of an index.
+#[derive(Clone, Debug)]
+pub struct Grouping {
+    cell: Vec<usize>,
+    molecule: Vec<usize>,
+}
+
+impl Grouping {
+    /// Resolves the components to group by.
+    ///
+    /// # Arguments
+    ///
+    /// * `names` - The names of the components of each identifier in the index.
+    /// * `opts` - The components chosen to define a cell and a molecule.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self>` - The grouping, or an error if a component does not exist or is given as
+    ///   both a cell and a molecule component.
+    pub fn new(names: &[String], opts: &GroupingOpts) -> Result<Self> {
+        let position = |name: &String| {
+            names.iter().position(|n| n == name).with_context(|| {
+                format!(
+                    "Unknown identifier component `{name}`. The components of this index are: {}",
+                    names.join(", ")
+                )
+            })
         };
+        let positions =
+            |names: &Vec<String>| names.iter().map(position).collect::<Result<Vec<_>>>();
 
-        RecordIdentifier {
-            head: s[..split_loc].to_string(),
-            tail: s[(split_loc + 1)..].to_string(),
+        let all = 0..names.len();
+        let (cell, molecule) = match (&opts.cell, &opts.molecule) {
+            (Some(cell), Some(molecule)) => (positions(cell)?, positions(molecule)?),
+            (Some(cell), None) => {
+                let cell = positions(cell)?;
+                let molecule = all.filter(|i| !cell.contains(i)).collect();
+                (cell, molecule)
+            }
+            (None, Some(molecule)) => {
+                let molecule = positions(molecule)?;
+                let cell = all.filter(|i| !molecule.contains(i)).collect();
+                (cell, molecule)
+            }
+            (None, None) => {
+                let cell = (0..names.len().saturating_sub(1)).collect();
+                let molecule = names.len().saturating_sub(1)..names.len();
+                (cell, molecule.collect())
+            }
+        };
+
+        if let Some(&i) = cell.iter().find(|i| molecule.contains(i)) {
+            bail!(
+                "Identifier component `{}` cannot define both a cell and a molecule",
+                names[i]
+            );
+        }
+        if molecule.is_empty() && cell.is_empty() {
+            bail!("At least one identifier component must be used to group reads");
         }
+
+        Ok(Grouping { cell, molecule })
+    }
+
+    /// Whether every component is used, in its original order, in which case identifiers do not
+    /// need to be changed.
+    fn is_identity(&self, count: usize) -> bool {
+        self.cell.len() + 1 == count
+            && self.molecule.len() == 1
+            && self.cell.iter().copied().eq(0..count - 1)
+            && self.molecule[0] == count - 1
+    }
+
+    /// Returns the identifier that reads are grouped by: the cell components, followed by the
+    /// molecule components.
+    pub fn identifier(&self, id: RecordIdentifier) -> Result<RecordIdentifier> {
+        let count = id.components.len();
+        if self.is_identity(count) {
+            return Ok(id);
+        }
+
+        let components = self
+            .cell
+            .iter()
+            .chain(self.molecule.iter())
+            .map(|&i| id.components.get(i).cloned())
+            .collect::<Option<Vec<_>>>()
+            .with_context(|| format!("Identifier {id} has fewer components than expected"))?;
+
+        Ok(RecordIdentifier {
+            sample: id.sample,
+            components,
+            cell: self.cell.len(),
+        })
     }
 }
 
@@ -150,14 +736,7 @@ impl IndexReader {
         info!("Reading index file...");
 
         let mut map = DuplicateMap::new();
-
-        let mut stats = DuplicateStatistics {
-            total_reads: 0,
-            duplicate_reads: 0,
-            duplicate_ids: 0,
-            proportion_duplicate: 0.0,
-            distribution: BTreeMap::new(),
-        };
+        let grouping = Grouping::new(&self.component_names()?, &self.grouping)?;
 
         // Parse each row of the reader
         for read in self.index_records()? {
@@ -166,40 +745,11 @@ impl IndexReader {
                 continue;
             }
 
-            stats.total_reads += 1;
-
-            map.insert(&record);
+            map.insert(&record, &grouping)?;
         }
 
-        map.shrink_to_fit(); // optimise memory usage
-
-        // Compute information about the duplicates
-        stats.duplicate_ids = 0;
-        stats.duplicate_reads = map
-            .by_id
-            .values()
-            .map(|v| {
-                let length = v.len();
-                if length > 1 {
-                    stats.duplicate_ids += 1;
-
-                    if let Some(x) = stats.distribution.get_mut(&length) {
-                        *x += 1
-                    } else {
-                        stats.distribution.insert(length, 1);
-                    }
-                    length
-                } else {
-                    0
-                }
-            })
-            .sum();
-
-        stats
-            .distribution
-            .insert(1, stats.total_reads - stats.duplicate_reads);
-
-        stats.proportion_duplicate = stats.duplicate_reads as f64 / stats.total_reads as f64;
+        map.finish();
+        let stats = map.statistics();
 
         info!("Generated duplicate map from index file");
 
@@ -0,0 +1,571 @@
+use crate::index::{IndexReader, IndexRecord};
+use crate::io::Record;
+use crate::qc::{CalledHeader, CalledType};
+use anyhow::{bail, Context, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+
+/// The minimum width of the band used when computing edit distances.
+const MIN_BAND_WIDTH: usize = 16;
+
+/// Where the predicted grouping of reads is read from.
+pub enum GroupingSource {
+    /// An index, along with the .fastq it was created from. Reads are grouped by identifier.
+    Index { index: String, input: String },
+
+    /// The output of `group`, where reads are grouped by their `UG` tag.
+    Grouped(String),
+}
+
+/// A row of the truth table written by `simulate`. Any other columns are ignored.
+#[derive(Deserialize)]
+struct TruthRecord {
+    read_id: String,
+    molecule: usize,
+    transcript: String,
+}
+
+/// How well the predicted grouping matches the true molecule of each read. Precision and recall
+/// are computed over pairs of reads: a pair is a true positive if both reads are from the same
+/// molecule and are in the same predicted group.
+///
+/// # Fields
+///
+/// * `reads` - The number of reads which are in both the truth table and the predicted grouping.
+/// * `ungrouped_reads` - The number of reads in the truth table which were not grouped, e.g.
+///   because they were filtered or did not match the barcode format.
+/// * `true_molecules` - The number of distinct molecules among the grouped reads.
+/// * `predicted_groups` - The number of predicted groups.
+/// * `precision` - The proportion of pairs in the same predicted group which are from the same
+///   molecule. Low precision indicates over-merging.
+/// * `recall` - The proportion of pairs from the same molecule which are in the same predicted
+///   group. Low recall indicates under-merging.
+/// * `f1` - The harmonic mean of precision and recall.
+/// * `over_merged_groups` - The number of predicted groups containing reads from more than one
+///   molecule.
+/// * `under_merged_molecules` - The number of molecules whose reads are split across more than
+///   one predicted group.
+#[derive(Serialize, Debug)]
+pub struct GroupingAccuracy {
+    pub reads: usize,
+    pub ungrouped_reads: usize,
+    pub true_molecules: usize,
+    pub predicted_groups: usize,
+    pub precision: f64,
+    pub recall: f64,
+    pub f1: f64,
+    pub over_merged_groups: usize,
+    pub under_merged_molecules: usize,
+}
+
+/// The identity of reads to the transcript of their true molecule, where
+/// `identity = 1 - edit distance / max(read length, transcript length)`.
+///
+/// # Fields
+///
+/// * `raw_reads` - The number of raw reads compared.
+/// * `mean_raw_identity` - The mean identity of all raw reads.
+/// * `mean_raw_duplicate_identity` - The mean identity of raw reads in a predicted group of size
+///   > 1, which are the reads that consensus reads are called from.
+/// * `consensus_reads` - The number of consensus (`CON`) reads compared.
+/// * `mean_consensus_identity` - The mean identity of consensus reads.
+/// * `single_reads` - The number of singleton (`SIN`) reads compared.
+/// * `mean_single_identity` - The mean identity of singleton reads.
+/// * `consensus_identity_by_group_size` - The mean identity of consensus reads for each group
+///   size.
+#[derive(Serialize, Debug, Default)]
+pub struct IdentityStatistics {
+    pub raw_reads: usize,
+    pub mean_raw_identity: f64,
+    pub mean_raw_duplicate_identity: f64,
+    pub consensus_reads: usize,
+    pub mean_consensus_identity: f64,
+    pub single_reads: usize,
+    pub mean_single_identity: f64,
+    pub consensus_identity_by_group_size: BTreeMap<usize, f64>,
+}
+
+/// The result of evaluating nailpolish against a truth table.
+#[derive(Serialize, Debug)]
+pub struct Evaluation {
+    pub grouping: GroupingAccuracy,
+    pub identity: Option<IdentityStatistics>,
+}
+
+/// Evaluates the grouping (and optionally consensus calling) of reads against the true molecule
+/// of each read, as written by `simulate`.
+///
+/// # Arguments
+///
+/// * `truth` - The path to the truth table.
+/// * `source` - Where the predicted grouping is read from.
+/// * `consensus` - The path to the output of `call`. Consensus identity is only reported if this
+///   and `reference` are given.
+/// * `reference` - A .fasta file of the transcripts named in the truth table. Identities are only
+///   reported if this is given.
+pub fn evaluate(
+    truth: &str,
+    source: &GroupingSource,
+    consensus: &Option<String>,
+    reference: &Option<String>,
+) -> Result<Evaluation> {
+    let truth = read_truth(truth)?;
+    info!("Read {} reads from the truth table", truth.len());
+
+    let grouped = read_grouping(source)?;
+    let grouping = grouping_accuracy(&truth, &grouped)?;
+
+    let identity = match reference {
+        Some(reference) => Some(identity_statistics(
+            &truth,
+            &grouped,
+            source,
+            consensus,
+            &read_reference(reference)?,
+        )?),
+        None => {
+            if consensus.is_some() {
+                warn!("Consensus identity can only be reported if --reference is given");
+            }
+            None
+        }
+    };
+
+    Ok(Evaluation { grouping, identity })
+}
+
+/// A read, along with the key of its predicted group.
+struct GroupedRead {
+    name: String,
+    group: String,
+    record: Option<Record>,
+}
+
+fn read_truth(path: &str) -> Result<HashMap<String, TruthRecord>> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .delimiter(b'\t')
+        .from_path(path)
+        .with_context(|| format!("Could not open truth table at {path}"))?;
+
+    rdr.deserialize::<TruthRecord>()
+        .map(|row| {
+            let row = row.context("Could not parse truth table")?;
+            Ok((row.read_id.clone(), row))
+        })
+        .collect()
+}
+
+fn read_reference(path: &str) -> Result<HashMap<String, Vec<u8>>> {
+    let mut reader = needletail::parse_fastx_file(path)
+        .with_context(|| format!("Could not open reference at {path}"))?;
+
+    let mut reference = HashMap::new();
+    while let Some(rec) = reader.next() {
+        let rec = rec.context("Could not parse reference")?;
+        reference.insert(
+            read_name(&String::from_utf8_lossy(rec.id())),
+            rec.seq().to_ascii_uppercase(),
+        );
+    }
+
+    Ok(reference)
+}
+
+/// The name of a read, which is the first word of its header.
+fn read_name(header: &str) -> String {
+    header
+        .trim_start_matches('@')
+        .split_whitespace()
+        .next()
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// The key of a predicted group taken from an index. Reads are only grouped together if they are
+/// from the same sample, so the key is made up of both the sample and the group identifier.
+fn index_group_key(sample: &str, id: &str) -> String {
+    format!("{sample}\t{id}")
+}
+
+/// Reads the predicted group of every grouped read.
+fn read_grouping(source: &GroupingSource) -> Result<Vec<GroupedRead>> {
+    let mut reads = Vec::new();
+
+    match source {
+        GroupingSource::Index { index, input } => {
+            let mut index = IndexReader::from_path(index)?;
+            let mut fastq = BufReader::new(
+                File::open(input).with_context(|| format!("Could not open input at {input}"))?,
+            );
+
+            for record in index.index_records()? {
+                let record: IndexRecord = record?;
+                if record.ignored {
+                    continue;
+                }
+
+                // the index does not store read names, so they are read from the .fastq
+                fastq.seek(SeekFrom::Start(record.pos as u64))?;
+                let mut header = String::new();
+                fastq.read_line(&mut header)?;
+
+                reads.push(GroupedRead {
+                    name: read_name(&header),
+                    group: index_group_key(&record.sample, &record.id),
+                    record: None,
+                });
+            }
+        }
+        GroupingSource::Grouped(path) => {
+            let mut reader = needletail::parse_fastx_file(path)
+                .with_context(|| format!("Could not open grouped reads at {path}"))?;
+
+            while let Some(rec) = reader.next() {
+                let rec = Record::try_from(rec.context("Could not parse record")?)
+                    .context("Record is not valid UTF-8")?;
+                let header = CalledHeader::parse(&rec.id)?;
+
+                reads.push(GroupedRead {
+                    name: read_name(&rec.id),
+                    group: header.group.to_string(),
+                    record: Some(rec),
+                });
+            }
+        }
+    }
+
+    info!("Read the predicted groups of {} reads", reads.len());
+
+    Ok(reads)
+}
+
+/// Returns `n choose 2`, the number of pairs among `n` items.
+fn pairs(n: usize) -> f64 {
+    (n * n.saturating_sub(1)) as f64 / 2.0
+}
+
+fn grouping_accuracy(
+    truth: &HashMap<String, TruthRecord>,
+    grouped: &[GroupedRead],
+) -> Result<GroupingAccuracy> {
+    // the number of reads from each (predicted group, true molecule) pair
+    let mut contingency: HashMap<(&str, usize), usize> = HashMap::new();
+    let mut missing = 0;
+
+    for read in grouped {
+        match truth.get(&read.name) {
+            Some(t) => *contingency.entry((&read.group, t.molecule)).or_default() += 1,
+            None => missing += 1,
+        }
+    }
+
+    if missing > 0 {
+        warn!("{missing} grouped reads are not in the truth table, and were not evaluated");
+    }
+    if contingency.is_empty() {
+        bail!("None of the grouped reads are in the truth table; are the read names the same?");
+    }
+
+    let mut group_sizes: HashMap<&str, usize> = HashMap::new();
+    let mut molecule_sizes: HashMap<usize, usize> = HashMap::new();
+    let mut molecules_per_group: HashMap<&str, usize> = HashMap::new();
+    let mut groups_per_molecule: HashMap<usize, usize> = HashMap::new();
+
+    for (&(group, molecule), &n) in contingency.iter() {
+        *group_sizes.entry(group).or_default() += n;
+        *molecule_sizes.entry(molecule).or_default() += n;
+        *molecules_per_group.entry(group).or_default() += 1;
+        *groups_per_molecule.entry(molecule).or_default() += 1;
+    }
+
+    let true_positives = contingency.values().map(|&n| pairs(n)).sum::<f64>();
+    let predicted_pairs = group_sizes.values().map(|&n| pairs(n)).sum::<f64>();
+    let true_pairs = molecule_sizes.values().map(|&n| pairs(n)).sum::<f64>();
+
+    // with no pairs, there can be no incorrect pairs
+    let ratio = |a: f64, b: f64| if b == 0.0 { 1.0 } else { a / b };
+    let precision = ratio(true_positives, predicted_pairs);
+    let recall = ratio(true_positives, true_pairs);
+
+    let reads = molecule_sizes.values().sum::<usize>();
+
+    Ok(GroupingAccuracy {
+        reads,
+        ungrouped_reads: truth.len().saturating_sub(reads),
+        true_molecules: molecule_sizes.len(),
+        predicted_groups: group_sizes.len(),
+        precision,
+        recall,
+        f1: ratio(2.0 * precision * recall, precision + recall),
+        over_merged_groups: molecules_per_group.values().filter(|&&n| n > 1).count(),
+        under_merged_molecules: groups_per_molecule.values().filter(|&&n| n > 1).count(),
+    })
+}
+
+fn identity_statistics(
+    truth: &HashMap<String, TruthRecord>,
+    grouped: &[GroupedRead],
+    source: &GroupingSource,
+    consensus: &Option<String>,
+    reference: &HashMap<String, Vec<u8>>,
+) -> Result<IdentityStatistics> {
+    let mut stats = IdentityStatistics::default();
+
+    let transcript_of = |read: &TruthRecord| {
+        reference.get(&read.transcript).with_context(|| {
+            format!(
+                "Transcript {} is not in the reference; was the same reference used to simulate reads?",
+                read.transcript
+            )
+        })
+    };
+
+    // the true molecules of each predicted group, which are used to find the molecule that each
+    // consensus read should match
+    let mut group_molecules: HashMap<&str, HashMap<usize, usize>> = HashMap::new();
+    for read in grouped {
+        if let Some(t) = truth.get(&read.name) {
+            *group_molecules
+                .entry(&read.group)
+                .or_default()
+                .entry(t.molecule)
+                .or_default() += 1;
+        }
+    }
+    let molecule_reads = truth
+        .values()
+        .map(|t| (t.molecule, t))
+        .collect::<HashMap<_, _>>();
+    let group_size = |group: &str| group_molecules.get(group).map_or(0, |m| m.values().sum());
+
+    // raw reads are taken from the grouped reads if present, or otherwise the original .fastq
+    let mut raw = Vec::new();
+    match source {
+        GroupingSource::Grouped(_) => {
+            for read in grouped {
+                if let (Some(rec), Some(t)) = (&read.record, truth.get(&read.name)) {
+                    raw.push((rec.seq.as_bytes().to_vec(), t, group_size(&read.group)));
+                }
+            }
+        }
+        GroupingSource::Index { input, .. } => {
+            let sizes = grouped
+                .iter()
+                .map(|r| (r.name.as_str(), group_size(&r.group)))
+                .collect::<HashMap<_, _>>();
+
+            let mut reader = needletail::parse_fastx_file(input)?;
+            while let Some(rec) = reader.next() {
+                let rec = rec?;
+                let name = read_name(&String::from_utf8_lossy(rec.id()));
+                if let Some(t) = truth.get(&name) {
+                    let size = sizes.get(name.as_str()).copied().unwrap_or(0);
+                    raw.push((rec.seq().to_vec(), t, size));
+                }
+            }
+        }
+    }
+
+    // computing identities is by far the slowest step, so is done in parallel
+    let identities = raw
+        .par_iter()
+        .map(|(seq, t, size)| Ok((identity(seq, transcript_of(t)?), *size)))
+        .collect::<Result<Vec<_>>>()?;
+
+    let (mut total, mut duplicate_total, mut duplicates) = (0.0, 0.0, 0);
+    for (id, size) in identities {
+        total += id;
+        if size > 1 {
+            duplicate_total += id;
+            duplicates += 1;
+        }
+    }
+    stats.raw_reads = raw.len();
+    stats.mean_raw_identity = total / raw.len().max(1) as f64;
+    stats.mean_raw_duplicate_identity = duplicate_total / duplicates.max(1) as f64;
+
+    let Some(consensus) = consensus else {
+        return Ok(stats);
+    };
+
+    let mut reader = needletail::parse_fastx_file(consensus)
+        .with_context(|| format!("Could not open consensus reads at {consensus}"))?;
+
+    let (mut single_total, mut consensus_total) = (0.0, 0.0);
+    let mut by_size: BTreeMap<usize, (f64, usize)> = BTreeMap::new();
+
+    while let Some(rec) = reader.next() {
+        let rec = Record::try_from(rec.context("Could not parse record")?)?;
+        let header = CalledHeader::parse(&rec.id)?;
+        let name = read_name(&rec.id);
+
+        match header.read_type {
+            CalledType::Single => {
+                let Some(t) = truth.get(&name) else { continue };
+                single_total += identity(rec.seq.as_bytes(), transcript_of(t)?);
+                stats.single_reads += 1;
+            }
+            CalledType::Consensus(size) => {
+                // consensus reads are named by their group identifier and tagged with their
+                // sample, or when grouping with `group`, matched by their UG tag
+                let key = match source {
+                    GroupingSource::Index { .. } => index_group_key(&header.sample, &name),
+                    GroupingSource::Grouped(_) => header.group.to_string(),
+                };
+
+                // the consensus read should match the most common molecule in its group
+                let Some(molecule) = group_molecules
+                    .get(key.as_str())
+                    .and_then(|m| m.iter().max_by_key(|(_, &n)| n).map(|(&m, _)| m))
+                else {
+                    continue;
+                };
+                let Some(t) = molecule_reads.get(&molecule) else {
+                    continue;
+                };
+
+                let id = identity(rec.seq.as_bytes(), transcript_of(t)?);
+                consensus_total += id;
+                stats.consensus_reads += 1;
+
+                let entry = by_size.entry(size).or_default();
+                entry.0 += id;
+                entry.1 += 1;
+            }
+            CalledType::Original(_) | CalledType::Ignored => {}
+        }
+    }
+
+    stats.mean_single_identity = single_total / stats.single_reads.max(1) as f64;
+    stats.mean_consensus_identity = consensus_total / stats.consensus_reads.max(1) as f64;
+    stats.consensus_identity_by_group_size = by_size
+        .into_iter()
+        .map(|(size, (total, n))| (size, total / n as f64))
+        .collect();
+
+    Ok(stats)
+}
+
+/// Returns `1 - edit distance / max(len(a), len(b))`.
+fn identity(a: &[u8], b: &[u8]) -> f64 {
+    let len = a.len().max(b.len());
+    if len == 0 {
+        return 1.0;
+    }
+    1.0 - edit_distance(a, b) as f64 / len as f64
+}
+
+/// Computes the edit (Levenshtein) distance between two sequences. Only cells within a diagonal
+/// band are computed, which is wide enough to contain the optimal alignment of sequences with
+/// an error rate of up to 20%; beyond this, the distance may be overestimated.
+fn edit_distance(a: &[u8], b: &[u8]) -> usize {
+    let (n, m) = (a.len(), b.len());
+    let band = n.abs_diff(m) + n.max(m) / 5 + MIN_BAND_WIDTH;
+    let unreachable = n + m;
+
+    let mut prev = (0..=m)
+        .map(|j| if j <= band { j } else { unreachable })
+        .collect::<Vec<_>>();
+    let mut curr = vec![unreachable; m + 1];
+
+    for i in 1..=n {
+        let lo = i.saturating_sub(band).max(1);
+        let hi = (i + band).min(m);
+
+        // the next row only reads this one from lo - 1 to hi + 1, so only the cells either side
+        // of the band need to be reset, rather than the whole row
+        curr[lo - 1] = if lo == 1 && i <= band { i } else { unreachable };
+        if hi < m {
+            curr[hi + 1] = unreachable;
+        }
+
+        for j in lo..=hi {
+            let substitution = prev[j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            curr[j] = substitution.min(prev[j] + 1).min(curr[j - 1] + 1);
+        }
+
+        std::mem::swap(&mut prev, &mut curr);
+    }
+
+    prev[m]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The edit distance computed over the whole dynamic programming matrix.
+    fn full_edit_distance(a: &[u8], b: &[u8]) -> usize {
+        let mut prev = (0..=b.len()).collect::<Vec<_>>();
+        for (i, x) in a.iter().enumerate() {
+            let mut curr = vec![i + 1; b.len() + 1];
+            for (j, y) in b.iter().enumerate() {
+                curr[j + 1] = (prev[j] + usize::from(x != y))
+                    .min(prev[j + 1] + 1)
+                    .min(curr[j] + 1);
+            }
+            prev = curr;
+        }
+        prev[b.len()]
+    }
+
+    /// A copy of the sequence, with the given proportion of its bases mutated.
+    fn mutate(rng: &mut fastrand::Rng, seq: &[u8], rate: f64, indels: bool) -> Vec<u8> {
+        let base = |rng: &mut fastrand::Rng| b"ACGT"[rng.usize(..4)];
+        let mut mutated = Vec::with_capacity(seq.len());
+        for &b in seq {
+            if rng.f64() >= rate {
+                mutated.push(b);
+                continue;
+            }
+            match if indels { rng.usize(..3) } else { 0 } {
+                0 => mutated.push(b"ACGT".iter().copied().find(|&c| c != b).unwrap()),
+                1 => mutated.extend([b, base(rng)]),
+                _ => {}
+            }
+        }
+        mutated
+    }
+
+    #[test]
+    fn edit_distance_exact() {
+        assert_eq!(edit_distance(b"", b""), 0);
+        assert_eq!(edit_distance(b"ACGT", b""), 4);
+        assert_eq!(edit_distance(b"", b"ACGT"), 4);
+        assert_eq!(edit_distance(b"ACGTACGT", b"ACGTACGT"), 0);
+        // a substitution, a deletion and an insertion
+        assert_eq!(edit_distance(b"GATTACA", b"GACTAAT"), 3);
+
+        // reads much longer than the band, and of different lengths
+        let mut rng = fastrand::Rng::with_seed(1);
+        let seq = (0..2000)
+            .map(|_| b"ACGT"[rng.usize(..4)])
+            .collect::<Vec<_>>();
+        let read = mutate(&mut rng, &seq, 0.1, true);
+        assert_ne!(read.len(), seq.len());
+        assert_eq!(edit_distance(&seq, &read), full_edit_distance(&seq, &read));
+        assert_eq!(edit_distance(&read, &seq), full_edit_distance(&read, &seq));
+    }
+
+    #[test]
+    fn edit_distance_high_error_rate() {
+        let mut rng = fastrand::Rng::with_seed(2);
+        let seq = (0..1000)
+            .map(|_| b"ACGT"[rng.usize(..4)])
+            .collect::<Vec<_>>();
+
+        // substitutions keep the alignment on the diagonal, so the distance is still exact
+        let read = mutate(&mut rng, &seq, 0.5, false);
+        assert_eq!(edit_distance(&seq, &read), full_edit_distance(&seq, &read));
+
+        // with indels the alignment may leave the band, but the distance is never underestimated
+        let read = mutate(&mut rng, &seq, 0.5, true);
+        let (banded, full) = (edit_distance(&seq, &read), full_edit_distance(&seq, &read));
+        assert!(banded >= full);
+        assert!(banded <= seq.len().max(read.len()));
+        assert!((0.0..=1.0).contains(&identity(&seq, &read)));
+    }
+}
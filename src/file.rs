@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Default)]
+#[derive(Serialize, Deserialize, Default, Clone)]
 pub struct ReadFileMetadata {
     pub nailpolish_version: String,
     pub file_path: String,
@@ -13,4 +13,82 @@ pub struct ReadFileMetadata {
     pub avg_qual: f64,
     pub avg_len: f64,
     pub filtered_reads: usize,
+
+    /// the names of the components of each identifier, e.g. `barcode` and `umi`. indexes created
+    /// by older versions do not record these
+    #[serde(default)]
+    pub components: Vec<String>,
+
+    /// the options used to create the index. indexes created by older versions do not record
+    /// these, in which case they are left empty
+    #[serde(default)]
+    pub parameters: IndexParameters,
+
+    /// the filters applied to the index by `filter`, in order, starting with the filters that the
+    /// index was created with. this is empty if the index has not been filtered again
+    #[serde(default)]
+    pub filter_history: Vec<AppliedFilter>,
+}
+
+/// A length and quality filter which was applied to an index.
+///
+/// # Fields
+///
+/// * `len` - The read length filter, as given to `--len`.
+/// * `qual` - The average read quality filter, as given to `--qual`.
+/// * `filtered_reads` - The number of reads which were filtered out.
+/// * `date` - When the filter was applied.
+/// * `command_line` - The full command line used to apply the filter.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct AppliedFilter {
+    pub len: String,
+    pub qual: String,
+    pub filtered_reads: usize,
+    pub date: String,
+    pub command_line: String,
+}
+
+/// The options that an index was created with, so that it can later be told how an index was
+/// built.
+///
+/// # Fields
+///
+/// * `barcode_regex` - The regex used to extract barcodes and UMIs from read headers.
+/// * `preset` - The preset that the barcode regex was taken from, if one was used.
+/// * `clusters` - The file of pre-clustered reads, if one was used instead of the barcode regex.
+/// * `skip_unmatched` - Whether reads which did not match were skipped.
+/// * `len` - The read length filter, as given to `--len`.
+/// * `qual` - The average read quality filter, as given to `--qual`.
+/// * `command_line` - The full command line used to create the index.
+/// * `sample_column` - Whether the cluster file has a sample column after the read ID.
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct IndexParameters {
+    pub barcode_regex: String,
+    pub preset: Option<String>,
+    pub clusters: Option<String>,
+    pub skip_unmatched: bool,
+    pub len: String,
+    pub qual: String,
+    pub command_line: String,
+    #[serde(default)]
+    pub sample_column: bool,
+}
+
+impl IndexParameters {
+    /// Whether the parameters were recorded, which is not the case for older indexes.
+    pub fn is_recorded(&self) -> bool {
+        !self.command_line.is_empty()
+    }
+
+    /// Describes how barcodes were found, e.g. `preset bc-umi (^([ATCG]{16})_([ATCG]{12}))`.
+    pub fn barcode_format(&self) -> String {
+        match (&self.clusters, &self.preset) {
+            (Some(clusters), _) if self.sample_column => {
+                format!("cluster file {clusters} (with sample column)")
+            }
+            (Some(clusters), _) => format!("cluster file {clusters}"),
+            (None, Some(preset)) => format!("preset {preset} ({})", self.barcode_regex),
+            (None, None) => self.barcode_regex.clone(),
+        }
+    }
 }
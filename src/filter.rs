@@ -1,11 +1,180 @@
 use crate::cli::ArgInterval;
+use crate::file::AppliedFilter;
+use crate::index::{IndexReader, IndexRecord, IndexWriter};
 use crate::io::Record;
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
 
+#[derive(Clone)]
 pub struct FilterOpts {
     pub len: ArgInterval,
     pub quality: ArgInterval,
 }
 
-pub fn filter(read: &Record, opts: &FilterOpts) -> bool {
-    opts.len.contains(read.len() as f64) && opts.quality.contains(read.phred_quality_avg())
+/// The reason that a read is ignored, which is recorded in the index.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterReason {
+    /// the read header did not match the barcode format, or the read was not in the cluster file
+    Unmatched,
+    /// the read is shorter than the length filter
+    TooShort,
+    /// the read is longer than the length filter
+    TooLong,
+    /// the average quality of the read is below the quality filter
+    LowQuality,
+    /// the average quality of the read is above the quality filter
+    HighQuality,
+}
+
+impl std::fmt::Display for FilterReason {
+    /// Formats the reason as it is written in the index, e.g. `too_short`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            FilterReason::Unmatched => "unmatched",
+            FilterReason::TooShort => "too_short",
+            FilterReason::TooLong => "too_long",
+            FilterReason::LowQuality => "low_quality",
+            FilterReason::HighQuality => "high_quality",
+        };
+        write!(f, "{reason}")
+    }
+}
+
+/// Applies the length and quality filters to a read.
+///
+/// # Returns
+///
+/// * `Option<FilterReason>` - The reason that the read fails the filters, or `None` if it passes.
+pub fn filter(read: &Record, opts: &FilterOpts) -> Option<FilterReason> {
+    filter_values(read.len(), read.phred_quality_avg(), opts)
+}
+
+/// Applies the length and quality filters to a record of an index, using the length and average
+/// quality recorded when the index was created.
+///
+/// # Returns
+///
+/// * `Option<FilterReason>` - The reason that the read fails the filters, or `None` if it passes.
+pub fn filter_index_record(record: &IndexRecord, opts: &FilterOpts) -> Option<FilterReason> {
+    filter_values(record.n_bases, record.avg_qual, opts)
+}
+
+fn filter_values(len: usize, quality: f64, opts: &FilterOpts) -> Option<FilterReason> {
+    let len = len as f64;
+    if !opts.len.contains(len) {
+        return Some(if len <= opts.len.min {
+            FilterReason::TooShort
+        } else {
+            FilterReason::TooLong
+        });
+    }
+
+    if !opts.quality.contains(quality) {
+        return Some(if quality <= opts.quality.min {
+            FilterReason::LowQuality
+        } else {
+            FilterReason::HighQuality
+        });
+    }
+
+    None
+}
+
+/// Returns the given filter, or otherwise the filter recorded in the index.
+fn filter_or_recorded(
+    given: Option<ArgInterval>,
+    recorded: &str,
+    option: &str,
+) -> Result<ArgInterval> {
+    match given {
+        Some(interval) => Ok(interval),
+        None if !recorded.is_empty() => {
+            ArgInterval::try_from(recorded).map_err(|err| anyhow!("{err}"))
+        }
+        None => bail!(
+            "The index does not record its {option} filter, as it was created by an older \
+            version, so --{option} must be given"
+        ),
+    }
+}
+
+/// Applies new length and quality filters to an existing index, without reading the .fastq again.
+/// The `ignored` column of each record is rewritten, and the new filters are recorded in the
+/// metadata of the index along with the filters which were applied before.
+///
+/// Reads which did not match the barcode format remain ignored, whatever the new filters are.
+///
+/// # Arguments
+///
+/// * `index` - The path to the index to filter.
+/// * `output` - The path to write the filtered index to, which may be the same as `index`.
+/// * `len` - The new length filter, or `None` to keep the current one.
+/// * `quality` - The new average quality filter, or `None` to keep the current one.
+///
+/// # Returns
+///
+/// * `Result<usize>` - The number of reads which are now filtered out.
+pub fn refilter_index(
+    index: &str,
+    output: &str,
+    len: Option<ArgInterval>,
+    quality: Option<ArgInterval>,
+) -> Result<usize> {
+    let mut reader = IndexReader::from_path(index)?;
+    let mut metadata = reader.metadata.clone();
+
+    let opts = FilterOpts {
+        len: filter_or_recorded(len, &metadata.parameters.len, "len")?,
+        quality: filter_or_recorded(quality, &metadata.parameters.qual, "qual")?,
+    };
+
+    // the index is only overwritten once every record has been read
+    let mut wtr = IndexWriter::new(output)?;
+    let mut filtered = 0;
+    for record in reader.index_records()? {
+        let mut record: IndexRecord = record?;
+
+        // reads which failed to match are not counted as filtered when the index is created
+        if record.reason != Some(FilterReason::Unmatched) {
+            let reason = filter_index_record(&record, &opts);
+            filtered += reason.is_some() as usize;
+            record.ignored = reason.is_some();
+            record.reason = reason;
+        }
+        wtr.write_index_record(&record)?;
+    }
+
+    info!(
+        "{} reads are filtered, compared to {} before",
+        filtered, metadata.filtered_reads
+    );
+
+    // the history starts with the filters that the index was created with
+    if metadata.filter_history.is_empty() && metadata.parameters.is_recorded() {
+        metadata.filter_history.push(AppliedFilter {
+            len: metadata.parameters.len.clone(),
+            qual: metadata.parameters.qual.clone(),
+            filtered_reads: metadata.filtered_reads,
+            date: metadata.index_date.clone(),
+            command_line: metadata.parameters.command_line.clone(),
+        });
+    }
+    metadata.filter_history.push(AppliedFilter {
+        len: opts.len.to_string(),
+        qual: opts.quality.to_string(),
+        filtered_reads: filtered,
+        date: format!("{:?}", chrono::offset::Local::now()),
+        command_line: std::env::args().collect::<Vec<_>>().join(" "),
+    });
+
+    metadata.filtered_reads = filtered;
+    metadata.parameters.len = opts.len.to_string();
+    metadata.parameters.qual = opts.quality.to_string();
+
+    // the index date is kept, as the .fastq is unchanged
+    wtr.metadata = metadata;
+    wtr.finish_write()?;
+
+    Ok(filtered)
 }
@@ -1,24 +1,41 @@
 use crate::duplicates::DuplicateMap;
-use crate::io::{ReadType, UMIGroupCollection};
+use crate::io::{ReadType, UMIGroup, UMIGroupCollection};
+use crate::sort::{ExternalSorter, GroupOrder};
+
+use rayon::prelude::*;
 
 use std::io::prelude::*;
+use std::process::{Command, ExitStatus, Stdio};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use thiserror::Error;
 
 /// Adds tags to duplicate reads from the input to show what group they are in.
 ///
 /// # Arguments
 ///
-/// * `input` - A string slice that holds the name of the input file.
+/// * `collection` - The UMI groups to process.
 /// * `writer` - A mutable reference to an object that implements the `Write` trait, used to write the output.
-/// * `duplicates` - A `DuplicateMap` containing the duplicate reads.
+/// * `order` - The order in which groups should be written.
+/// * `sort_memory` - The maximum memory, in bytes, to use for sorting before spilling to disk.
 ///
 /// # Returns
 ///
 /// * `Result<()>` - Returns `Ok(())` if successful, or an error if an error occurs during processing.
-pub fn group(collection: &mut UMIGroupCollection, writer: &mut impl Write) -> Result<()> {
+pub fn group(
+    collection: &mut UMIGroupCollection,
+    writer: &mut impl Write,
+    order: GroupOrder,
+    sort_memory: usize,
+) -> Result<()> {
     let mut duplicate_iterator = collection.stream_iter(false);
 
+    // when not in input order, every read is passed through an external sort
+    let mut sorter = match order {
+        GroupOrder::Input => None,
+        _ => Some(ExternalSorter::new(sort_memory)),
+    };
+
     let mut count = 0usize;
 
     let mut first = true;
@@ -30,16 +47,221 @@ pub fn group(collection: &mut UMIGroupCollection, writer: &mut impl Write) -> Re
 
         let group_size = group.records.len();
         for (idx, rec) in group.records.iter_mut().enumerate() {
+            rec.add_metadata(
+                group.index,
+                &group.id.sample,
+                ReadType::Original,
+                idx + 1,
+                group_size,
+                0.0,
+            );
+
+            if let Some(ref mut sorter) = sorter {
+                let key = order.sort_key(&group.id, group_size, group.index, idx);
+                let mut value = Vec::new();
+                rec.write_fastq(&mut value)?;
+                sorter.push(key, value)?;
+                continue;
+            }
+
             if first {
                 first = false
             } else {
                 writer.write_all(b"\n")?
             }
-
-            rec.add_metadata(group.index, ReadType::Original, idx + 1, group_size, 0.0);
             rec.write_fastq(writer)?;
         }
     }
 
+    if let Some(sorter) = sorter {
+        info!("Writing groups in {order:?} order");
+
+        sorter.finish(|value| {
+            if first {
+                first = false
+            } else {
+                writer.write_all(b"\n")?
+            }
+            writer.write_all(value)?;
+            Ok(())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// Options for running an external command on each UMI group.
+pub struct CommandOpts {
+    /// The command to run, which is passed to the shell as a single string
+    pub command: String,
+    /// The shell used to run the command, e.g. `bash`
+    pub shell: String,
+    /// The number of processes to run at once
+    pub threads: usize,
+    /// Whether to skip, instead of error, on groups where the command fails
+    pub keep_going: bool,
+}
+
+#[derive(Error, Debug)]
+enum GroupCommandErr {
+    #[error("could not start command `{command}` using shell `{shell}`")]
+    Spawn { command: String, shell: String },
+
+    #[error("command exited with {status} for group {group} ({id})")]
+    NonZeroExit {
+        status: ExitStatus,
+        group: usize,
+        id: String,
+    },
+}
+
+/// Pipes each UMI group to an external command as .fastq standard input, and writes the standard
+/// output of each command to the writer in group order. Commands are run in parallel, with up to
+/// `opts.threads` processes at once.
+///
+/// # Arguments
+///
+/// * `collection` - The UMI groups to process.
+/// * `writer` - A mutable reference to an object that implements the `Write` trait, used to write the output.
+/// * `opts` - The command to run, and how to run it.
+///
+/// # Returns
+///
+/// * `Result<()>` - Returns `Ok(())` if successful, or an error if a command could not be run or
+///   exited unsuccessfully (unless `opts.keep_going` is set).
+pub fn group_with_command(
+    collection: &mut UMIGroupCollection,
+    writer: &mut impl Write,
+    opts: &CommandOpts,
+) -> Result<()> {
+    info!(
+        "Running `{}` for each group with {} processes",
+        opts.command, opts.threads
+    );
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(opts.threads)
+        .build()?;
+
+    let mut duplicate_iterator = collection.stream_iter(false);
+
+    let chunk_size = 16usize * opts.threads;
+    let mut buffer = Vec::with_capacity(chunk_size);
+
+    let mut count = 0usize;
+    let mut failed = 0usize;
+
+    loop {
+        let group = duplicate_iterator.next()?;
+        let end_of_buffer = group.is_none();
+
+        if let Some(group) = group {
+            count += 1;
+            if count.is_multiple_of(100000) {
+                info!("Processed: {} groups", count);
+            }
+
+            buffer.push(group);
+        }
+
+        // if we have filled the buffer OR are at the end, run this chunk
+        if (buffer.len() == chunk_size) || end_of_buffer {
+            let outputs = pool.install(|| {
+                buffer
+                    .par_iter_mut()
+                    .map(|group| run_command(group, opts))
+                    .collect::<Vec<_>>()
+            });
+
+            // outputs are in the same order as the buffer, so groups are written in order
+            for output in outputs {
+                match output {
+                    Ok(stdout) => writer.write_all(&stdout)?,
+                    Err(e) if opts.keep_going => {
+                        warn!("{e}; skipping");
+                        failed += 1;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+
+            buffer.clear();
+        }
+
+        if end_of_buffer {
+            break;
+        }
+    }
+
+    if failed > 0 {
+        warn!("The command failed for {failed} of {count} groups");
+    }
+
     Ok(())
 }
+
+/// Runs the command for a single UMI group, returning its standard output.
+fn run_command(group: &mut UMIGroup, opts: &CommandOpts) -> Result<Vec<u8>> {
+    let group_size = group.records.len();
+
+    // write the group as a .fastq, with each read tagged in the same way as `group`
+    let mut input = Vec::new();
+    for (idx, rec) in group.records.iter_mut().enumerate() {
+        rec.add_metadata(
+            group.index,
+            &group.id.sample,
+            ReadType::Original,
+            idx + 1,
+            group_size,
+            0.0,
+        );
+        rec.write_fastq(&mut input)?;
+        input.push(b'\n');
+    }
+
+    let mut child = Command::new(&opts.shell)
+        .arg("-c")
+        .arg(&opts.command)
+        .env("NAILPOLISH_UG", group.index.to_string())
+        .env("NAILPOLISH_ID", group.id.to_string())
+        .env("NAILPOLISH_SAMPLE", &group.id.sample)
+        .env("NAILPOLISH_BC", group.id.barcode())
+        .env("NAILPOLISH_UMI", group.id.umi())
+        .env("NAILPOLISH_SIZE", group_size.to_string())
+        .env("NAILPOLISH_AVG_QUAL", format!("{:.2}", group.avg_qual))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| GroupCommandErr::Spawn {
+            command: opts.command.clone(),
+            shell: opts.shell.clone(),
+        })?;
+
+    // write standard input from a separate thread, so that a command which produces a lot of
+    // output before consuming all of its input cannot deadlock
+    let mut stdin = child
+        .stdin
+        .take()
+        .context("Could not open standard input")?;
+    let feeder = std::thread::spawn(move || stdin.write_all(&input));
+
+    let output = child.wait_with_output()?;
+
+    // the command is allowed to exit without reading all of its input (e.g. `head`)
+    if let Err(e) = feeder.join().expect("Input thread should not panic") {
+        if e.kind() != std::io::ErrorKind::BrokenPipe {
+            return Err(e.into());
+        }
+    }
+
+    if !output.status.success() {
+        bail!(GroupCommandErr::NonZeroExit {
+            status: output.status,
+            group: group.index,
+            id: group.id.to_string(),
+        })
+    }
+
+    Ok(output.stdout)
+}
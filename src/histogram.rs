@@ -0,0 +1,225 @@
+use crate::duplicates::DuplicateMap;
+use crate::filter::FilterReason;
+use crate::index::{IndexReader, IndexRecord};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// The width of each read length bin, in bases
+const LENGTH_BIN_WIDTH: f64 = 100.0;
+
+/// The width of each average quality bin, in PHRED units
+const QUALITY_BIN_WIDTH: f64 = 1.0;
+
+/// A histogram with fixed-width bins starting from zero.
+///
+/// # Fields
+///
+/// * `bin_width` - The width of each bin.
+/// * `bins` - A map from bin index `i` to the number of values in `[i * bin_width, (i + 1) * bin_width)`.
+/// * `count` - The total number of values.
+/// * `mean` - The mean of all values.
+#[derive(Serialize, Debug, Clone)]
+pub struct Histogram {
+    pub bin_width: f64,
+    pub bins: BTreeMap<usize, usize>,
+    pub count: usize,
+    pub mean: f64,
+}
+
+impl Histogram {
+    pub fn new(bin_width: f64) -> Self {
+        Histogram {
+            bin_width,
+            bins: BTreeMap::new(),
+            count: 0,
+            mean: 0.0,
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        let bin = (value.max(0.0) / self.bin_width) as usize;
+        *self.bins.entry(bin).or_insert(0) += 1;
+
+        // update the mean incrementally, to avoid overflow for large datasets
+        self.count += 1;
+        self.mean += (value - self.mean) / self.count as f64;
+    }
+
+    /// Returns the `(bin centre, proportion of values)` of each bin, so that histograms with
+    /// different numbers of values can be compared on the same axes.
+    pub fn proportions(&self) -> Vec<(f64, f64)> {
+        self.bins
+            .iter()
+            .map(|(&bin, &n)| {
+                (
+                    (bin as f64 + 0.5) * self.bin_width,
+                    n as f64 / self.count as f64,
+                )
+            })
+            .collect()
+    }
+}
+
+/// Histograms of a single read property, with reads split into categories.
+///
+/// # Fields
+///
+/// * `singleton` - Reads which passed filtering, and are the only read in their UMI group.
+/// * `duplicate` - Reads which passed filtering, and are in a UMI group of size > 1.
+/// * `filtered` - Reads which were removed by the length or quality filters.
+#[derive(Serialize, Debug, Clone)]
+pub struct CategoryHistograms {
+    pub singleton: Histogram,
+    pub duplicate: Histogram,
+    pub filtered: Histogram,
+}
+
+impl CategoryHistograms {
+    fn new(bin_width: f64) -> Self {
+        CategoryHistograms {
+            singleton: Histogram::new(bin_width),
+            duplicate: Histogram::new(bin_width),
+            filtered: Histogram::new(bin_width),
+        }
+    }
+
+    /// Returns a histogram of every read which passed filtering.
+    pub fn kept(&self) -> Histogram {
+        let mut kept = self.singleton.clone();
+
+        for (&bin, &n) in self.duplicate.bins.iter() {
+            *kept.bins.entry(bin).or_insert(0) += n;
+        }
+
+        kept.count += self.duplicate.count;
+        if kept.count > 0 {
+            kept.mean = (self.singleton.mean * self.singleton.count as f64
+                + self.duplicate.mean * self.duplicate.count as f64)
+                / kept.count as f64;
+        }
+
+        kept
+    }
+}
+
+/// The distributions of read length and average read quality.
+#[derive(Serialize, Debug, Clone)]
+pub struct ReadDistributions {
+    pub length: CategoryHistograms,
+    pub quality: CategoryHistograms,
+}
+
+impl ReadDistributions {
+    fn new() -> Self {
+        ReadDistributions {
+            length: CategoryHistograms::new(LENGTH_BIN_WIDTH),
+            quality: CategoryHistograms::new(QUALITY_BIN_WIDTH),
+        }
+    }
+
+    /// Adds a read to the histograms of its category.
+    ///
+    /// # Arguments
+    ///
+    /// * `n_bases` - The length of the read.
+    /// * `avg_qual` - The average quality of the read.
+    /// * `group_size` - The size of the read's UMI group, or `None` if the read was filtered.
+    fn add(&mut self, n_bases: usize, avg_qual: f64, group_size: Option<usize>) {
+        let (length, quality) = match group_size {
+            None => (&mut self.length.filtered, &mut self.quality.filtered),
+            Some(size) if size > 1 => (&mut self.length.duplicate, &mut self.quality.duplicate),
+            Some(_) => (&mut self.length.singleton, &mut self.quality.singleton),
+        };
+
+        length.add(n_bases as f64);
+        quality.add(avg_qual);
+    }
+}
+
+impl IndexReader {
+    /// Computes histograms of the length and average quality of every read in the index, split by
+    /// whether each read was filtered, and otherwise whether it is a singleton or a duplicate.
+    ///
+    /// # Arguments
+    ///
+    /// * `duplicates` - The `DuplicateMap` of this index, as returned by `get_duplicates()`.
+    pub fn read_distributions(&mut self, duplicates: &DuplicateMap) -> Result<ReadDistributions> {
+        let mut dist = ReadDistributions::new();
+
+        for read in self.index_records()? {
+            let record: IndexRecord = read?;
+
+            // reads which did not match the barcode format are not part of the library
+            if record.reason == Some(FilterReason::Unmatched) {
+                continue;
+            }
+
+            let group_size = (!record.ignored).then(|| {
+                duplicates
+                    .group_by_pos(record.pos)
+                    .map_or(1, |group| duplicates.group_size(group))
+            });
+            dist.add(record.n_bases, record.avg_qual, group_size);
+        }
+
+        Ok(dist)
+    }
+}
+
+/// Computes the read distributions of an index while it is written, instead of reading the index
+/// again with `read_distributions()`. Whether a read is a singleton or a duplicate is only known
+/// once every read has been grouped, so the length and quality of each read which passed filtering
+/// is kept until `finish` is called.
+pub struct ReadDistributionsBuilder {
+    dist: ReadDistributions,
+    lengths: Vec<u32>,
+    qualities: Vec<f64>,
+}
+
+impl Default for ReadDistributionsBuilder {
+    fn default() -> Self {
+        ReadDistributionsBuilder {
+            dist: ReadDistributions::new(),
+            lengths: Vec::new(),
+            qualities: Vec::new(),
+        }
+    }
+}
+
+impl ReadDistributionsBuilder {
+    /// Adds a record of the index. Records must be added in the same order that the reads which
+    /// passed filtering are inserted into the `DuplicateMap` given to `finish`.
+    pub fn add(&mut self, record: &IndexRecord) -> Result<()> {
+        // reads which did not match the barcode format are not part of the library
+        if record.reason == Some(FilterReason::Unmatched) {
+            return Ok(());
+        }
+
+        if record.ignored {
+            self.dist.add(record.n_bases, record.avg_qual, None);
+        } else {
+            self.lengths.push(
+                u32::try_from(record.n_bases)
+                    .with_context(|| format!("Record at position {} is too long", record.pos))?,
+            );
+            self.qualities.push(record.avg_qual);
+        }
+
+        Ok(())
+    }
+
+    /// Returns the read distributions, once every read has been grouped.
+    ///
+    /// # Arguments
+    ///
+    /// * `duplicates` - The finished `DuplicateMap` of the reads which were added.
+    pub fn finish(mut self, duplicates: &DuplicateMap) -> ReadDistributions {
+        for (read, (&length, &quality)) in self.lengths.iter().zip(&self.qualities).enumerate() {
+            let group_size = duplicates.group_size(duplicates.group(read as u32));
+            self.dist.add(length as usize, quality, Some(group_size));
+        }
+
+        self.dist
+    }
+}
@@ -14,20 +14,34 @@ use needletail::FastxReader;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::duplicates::RecordIdentifier;
-use crate::file::ReadFileMetadata;
-use crate::filter::{filter, FilterOpts};
+use crate::duplicates::{
+    default_component_names, DuplicateMap, DuplicateStatistics, Grouping, GroupingOpts,
+    RecordIdentifier,
+};
+use crate::file::{IndexParameters, ReadFileMetadata};
+use crate::filter::{filter, FilterOpts, FilterReason};
+use crate::histogram::{ReadDistributions, ReadDistributionsBuilder};
 use crate::io::Record;
+use crate::preset::BarcodeFormat;
 use tempfile::tempfile_in;
 
+/// The name of the identifier component which holds the sample of a multi-sample library.
+pub const SAMPLE_COMPONENT: &str = "sample";
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct IndexRecord {
     pub id: String,
+    /// indexes created by older versions, or without samples, have no sample column
+    #[serde(default)]
+    pub sample: String,
     pub pos: usize,
     pub avg_qual: f64,
     pub n_bases: usize,
     pub rec_len: usize,
     pub ignored: bool,
+    /// why the read is ignored. indexes created by older versions do not record this
+    #[serde(default)]
+    pub reason: Option<FilterReason>,
 }
 
 pub struct IndexWriter {
@@ -35,6 +49,63 @@ pub struct IndexWriter {
     temp_file: File,
     out_file: String,
     pub metadata: ReadFileMetadata,
+    duplicates: Option<DuplicateCollector>,
+}
+
+/// The duplicates of an index and the distributions of its reads, which are otherwise found by
+/// reading the index with `get_duplicates()` and `read_distributions()`.
+pub struct IndexDuplicates {
+    pub duplicates: DuplicateMap,
+    pub statistics: DuplicateStatistics,
+    pub reads: ReadDistributions,
+}
+
+/// Finds the duplicates of an index as it is written. See `IndexWriter::with_duplicates`.
+struct DuplicateCollector {
+    opts: GroupingOpts,
+    /// The grouping is resolved once the first read is added, as the components of an index are
+    /// not always known before then.
+    grouping: Option<Grouping>,
+    map: DuplicateMap,
+    reads: ReadDistributionsBuilder,
+}
+
+impl DuplicateCollector {
+    fn add(&mut self, record: &IndexRecord, components: &[String]) -> Result<()> {
+        if !record.ignored {
+            let grouping = match self.grouping {
+                Some(ref grouping) => grouping,
+                None => {
+                    let names = match components {
+                        [] => default_component_names(
+                            RecordIdentifier::from_string(&record.id).components.len(),
+                        ),
+                        _ => components.to_vec(),
+                    };
+                    self.grouping.insert(Grouping::new(&names, &self.opts)?)
+                }
+            };
+            self.map.insert(record, grouping)?;
+        }
+
+        self.reads.add(record)
+    }
+
+    fn finish(mut self, components: &[String]) -> Result<IndexDuplicates> {
+        // check the grouping even if no reads were grouped, as `get_duplicates()` does
+        if self.grouping.is_none() {
+            Grouping::new(components, &self.opts)?;
+        }
+
+        self.map.finish();
+        info!("Generated duplicate map while writing the index");
+
+        Ok(IndexDuplicates {
+            statistics: self.map.statistics(),
+            reads: self.reads.finish(&self.map),
+            duplicates: self.map,
+        })
+    }
 }
 
 impl IndexWriter {
@@ -61,9 +132,32 @@ impl IndexWriter {
                 index_date: format!("{:?}", chrono::offset::Local::now()),
                 ..ReadFileMetadata::default()
             },
+            duplicates: None,
         })
     }
 
+    /// Finds the duplicates of the index as records are written, grouped by the given components,
+    /// so that they can be used without reading the index again. They are returned by
+    /// `finish_duplicates`.
+    pub fn with_duplicates(mut self, grouping: GroupingOpts) -> Self {
+        self.duplicates = Some(DuplicateCollector {
+            opts: grouping,
+            grouping: None,
+            map: DuplicateMap::new(),
+            reads: ReadDistributionsBuilder::default(),
+        });
+        self
+    }
+
+    /// Returns the duplicates found since `with_duplicates` was called, once every record has
+    /// been written.
+    pub fn finish_duplicates(&mut self) -> Result<Option<IndexDuplicates>> {
+        self.duplicates
+            .take()
+            .map(|duplicates| duplicates.finish(&self.metadata.components))
+            .transpose()
+    }
+
     /// Finalizes the writing process by flushing the writer, writing metadata,
     /// and copying the temporary file contents to the final output file.
     pub fn finish_write(&mut self) -> Result<()> {
@@ -91,29 +185,44 @@ impl IndexWriter {
     /// # Arguments
     ///
     /// * `wtr` - A mutable reference to a CSV writer.
+    /// * `sample` - The sample of the record, or an empty string.
     /// * `pos` - The position of the record in the file.
     /// * `file_len` - The bytes consumed by the record in the file (the _length_ on _file_)
+    /// * `reason` - Why the record is ignored, or `None` if it is not.
     pub fn write_record(
         &mut self,
         rec: &Record,
+        sample: &str,
         pos: usize,
         file_len: usize,
-        ignored: bool,
-    ) -> csv::Result<()> {
-        self.wtr.serialize(IndexRecord {
+        reason: Option<FilterReason>,
+    ) -> Result<()> {
+        self.write_index_record(&IndexRecord {
             id: rec.id.clone(),
+            sample: sample.to_string(),
             pos,
             avg_qual: rec.phred_quality_avg(),
             n_bases: rec.len(),
             rec_len: file_len,
-            ignored,
+            ignored: reason.is_some(),
+            reason,
         })
     }
+
+    /// Writes a record which has already been indexed, e.g. one read from another index.
+    pub fn write_index_record(&mut self, record: &IndexRecord) -> Result<()> {
+        if let Some(duplicates) = &mut self.duplicates {
+            duplicates.add(record, &self.metadata.components)?;
+        }
+        self.wtr.serialize(record)?;
+        Ok(())
+    }
 }
 
 pub struct IndexReader {
     path: String,
     pub(crate) metadata: ReadFileMetadata,
+    pub(crate) grouping: GroupingOpts,
 }
 
 pub type IndexReaderRecords = DeserializeRecordsIntoIter<BufReader<File>, IndexRecord>;
@@ -123,6 +232,7 @@ impl IndexReader {
         let mut rdr = Self {
             path: path.to_string(),
             metadata: ReadFileMetadata::default(),
+            grouping: GroupingOpts::default(),
         };
 
         rdr.metadata = rdr.create_reader()?.0;
@@ -130,6 +240,35 @@ impl IndexReader {
         Ok(rdr)
     }
 
+    /// Sets the identifier components which define a cell and a molecule when grouping reads.
+    pub fn with_grouping(mut self, grouping: GroupingOpts) -> Self {
+        self.grouping = grouping;
+        self
+    }
+
+    /// Returns the names of the identifier components of this index. Older indexes do not record
+    /// these, so default names are given based on the number of components of the first record.
+    pub fn component_names(&mut self) -> Result<Vec<String>> {
+        if !self.metadata.components.is_empty() {
+            return Ok(self.metadata.components.clone());
+        }
+
+        // unmatched reads have no identifier
+        let mut records = self.index_records()?;
+        let count = loop {
+            match records.next() {
+                Some(record) => {
+                    let record = record?;
+                    if record.reason != Some(FilterReason::Unmatched) {
+                        break RecordIdentifier::from_string(&record.id).components.len();
+                    }
+                }
+                None => break 0,
+            }
+        };
+        Ok(default_component_names(count))
+    }
+
     fn create_reader(&self) -> Result<(ReadFileMetadata, Reader<BufReader<File>>)> {
         let file = File::open(&self.path)?;
         let mut file = BufReader::new(file);
@@ -167,6 +306,7 @@ impl IndexReader {
 /// * `reader` - A `BufReader` for the input FASTQ file.
 /// * `wtr` - A mutable reference to a CSV writer.
 /// * `re` - A reference to a `Regex` for extracting barcodes from read headers.
+/// * `sample_group` - The capture group (counting from 0) which contains the sample, if any.
 /// * `skip_invalid_ids` - A boolean indicating whether to skip invalid IDs.
 /// * `info` - A mutable `FastqFile` struct containing information about the FASTQ file.
 ///
@@ -182,6 +322,7 @@ fn iter_lines_with_regex(
     reader: BufReader<File>,
     wtr: &mut IndexWriter,
     re: &Regex,
+    sample_group: Option<usize>,
     skip_invalid_ids: bool,
     filter_opts: FilterOpts,
 ) -> Result<()> {
@@ -204,10 +345,6 @@ fn iter_lines_with_regex(
         let file_len = sequence_rec.all().len() + 1;
         let mut rec = Record::try_from(sequence_rec)?;
 
-        // apply any filters
-        let ignored = !filter(&rec, &filter_opts);
-        wtr.metadata.filtered_reads += ignored as usize;
-
         let bc = extract_bc_from_header(&rec.id, re, position);
 
         // if this did not succeed...
@@ -216,10 +353,12 @@ fn iter_lines_with_regex(
                 bail!(e)
             }
             wtr.metadata.unmatched_read_count += 1;
+            write_unmatched_record(wtr, rec, position, file_len)?;
             continue;
         }
 
-        let (len, identifier) = bc?;
+        let mut components = bc?;
+        let len = components.len();
 
         // check that the number of barcode groups is the same
         let expected_len = *expected_len.get_or_insert(len);
@@ -233,9 +372,18 @@ fn iter_lines_with_regex(
             })
         }
 
-        rec.id = identifier.to_string();
+        // the sample is stored in its own column, rather than as part of the identifier
+        let sample = match sample_group {
+            Some(i) if i < len => components.remove(i),
+            _ => String::new(),
+        };
+        rec.id = RecordIdentifier::new(components).to_string();
 
-        wtr.write_record(&rec, position, file_len, ignored)?;
+        // apply any filters. unmatched reads are not counted as filtered, as they are already
+        // ignored
+        let reason = filter(&rec, &filter_opts);
+        wtr.metadata.filtered_reads += reason.is_some() as usize;
+        wtr.write_record(&rec, &sample, position, file_len, reason)?;
         total_quality += rec.phred_quality_total();
         total_len += rec.len();
         wtr.metadata.matched_read_count += 1;
@@ -256,6 +404,7 @@ fn iter_lines_with_regex(
 /// * `reader` - A `BufReader` for the input FASTQ file.
 /// * `wtr` - A mutable reference to a CSV writer.
 /// * `clusters` - A mutable reference to a CSV reader for the cluster file.
+/// * `sample_column` - Whether the first column after the read ID is the sample.
 /// * `skip_invalid_ids` - A boolean indicating whether to skip invalid IDs.
 /// * `info` - A mutable `FastqFile` struct containing information about the FASTQ file.
 ///
@@ -272,6 +421,7 @@ fn iter_lines_with_cluster_file(
     reader: BufReader<File>,
     wtr: &mut IndexWriter,
     clusters: &mut Reader<File>,
+    sample_column: bool,
     skip_invalid_ids: bool,
     filter_opts: FilterOpts,
 ) -> Result<()> {
@@ -284,22 +434,31 @@ fn iter_lines_with_cluster_file(
         let record = result?;
 
         let read_id = record[0].to_string();
-        let identifier = match record.len() {
-            // in this case, there is just one identifier (no BC and UMI) so we read the first
-            // column directly as the 'identifier'
-            2 => record[1].to_string(),
-
-            // in this case, there are two identifiers (i.e. BC and UMI) so we combine them to
-            // produce an 'identifier'
-            3 => format!("{}_{}", &record[1], &record[2]),
 
-            // doesn't make sense
-            _ => bail!(InvalidClusterRow {
-                row: record.as_slice().to_string()
-            }),
+        // every column after the read ID (and sample) is a component of the identifier, e.g. BC
+        // and UMI
+        let sample = if sample_column {
+            record.get(1).unwrap_or_default().to_string()
+        } else {
+            String::new()
         };
+        let columns = record
+            .iter()
+            .skip(1 + sample_column as usize)
+            .map(String::from)
+            .collect::<Vec<_>>();
+        if columns.is_empty() {
+            bail!(InvalidClusterRow {
+                row: record.as_slice().to_string()
+            });
+        }
+        let columns_len = columns.len();
+        let identifier = RecordIdentifier::new(columns).to_string();
 
-        cluster_map.insert(read_id, identifier);
+        if wtr.metadata.components.is_empty() {
+            wtr.metadata.components = default_component_names(columns_len);
+        }
+        cluster_map.insert(read_id, (sample, identifier));
     }
 
     info!("Finished reading clusters. ");
@@ -314,7 +473,7 @@ fn iter_lines_with_cluster_file(
         wtr.metadata.read_count += 1;
 
         // print progress notification
-        if wtr.metadata.read_count % 50000 == 0 {
+        if wtr.metadata.read_count.is_multiple_of(50000) {
             info!("Processed: {}", wtr.metadata.read_count);
         }
 
@@ -323,21 +482,23 @@ fn iter_lines_with_cluster_file(
         let file_len = sequence_rec.all().len() + 1;
         let mut rec = Record::try_from(sequence_rec)?;
 
-        // apply any filters
-        let ignored = !filter(&rec, &filter_opts);
-        wtr.metadata.filtered_reads += ignored as usize;
-
-        let Some(identifier) = cluster_map.get(&rec.id) else {
+        let Some((sample, identifier)) = cluster_map.get(&rec.id) else {
             if !skip_invalid_ids {
                 bail!(RowNotInClusters { header: rec.id })
             }
             wtr.metadata.unmatched_read_count += 1;
+            write_unmatched_record(wtr, rec, position, file_len)?;
             continue;
         };
         wtr.metadata.matched_read_count += 1;
 
         rec.id = identifier.clone();
-        wtr.write_record(&rec, position, file_len, ignored)?;
+
+        // apply any filters. unmatched reads are not counted as filtered, as they are already
+        // ignored
+        let reason = filter(&rec, &filter_opts);
+        wtr.metadata.filtered_reads += reason.is_some() as usize;
+        wtr.write_record(&rec, sample, position, file_len, reason)?;
 
         total_quality += rec.phred_quality_total();
         total_len += rec.len();
@@ -351,6 +512,18 @@ fn iter_lines_with_cluster_file(
     Ok(())
 }
 
+/// Records a read which did not match the barcode format or cluster file in the index, so that it
+/// can still be written by `--unmatched-output`. The read has no identifier, and is ignored.
+fn write_unmatched_record(
+    wtr: &mut IndexWriter,
+    mut rec: Record,
+    position: usize,
+    file_len: usize,
+) -> Result<()> {
+    rec.id = String::new();
+    wtr.write_record(&rec, "", position, file_len, Some(FilterReason::Unmatched))
+}
+
 /// Extracts barcodes from a read header using a regex pattern.
 ///
 /// # Arguments
@@ -361,17 +534,12 @@ fn iter_lines_with_cluster_file(
 ///
 /// # Returns
 ///
-/// Returns a `Result` containing a tuple with the number of captures and the
-/// concatenated barcode string (identifier).
+/// Returns a `Result` containing the value of each capture group which matched.
 ///
 /// # Errors
 ///
 /// This function will return an error if the regex does not match the header.
-fn extract_bc_from_header(
-    header: &str,
-    re: &Regex,
-    pos: usize,
-) -> Result<(usize, RecordIdentifier)> {
+fn extract_bc_from_header(header: &str, re: &Regex, pos: usize) -> Result<Vec<String>> {
     let Some(captures) = re.captures(header) else {
         bail!(IndexGenerationErr::NoMatch {
             header: String::from(header.trim()),
@@ -380,20 +548,30 @@ fn extract_bc_from_header(
         });
     };
 
-    let captures = captures
+    Ok(captures
         .iter()
         .skip(1)
         .flatten()
-        .map(|m| m.as_str())
-        .collect::<Vec<_>>();
-
-    Ok((
-        captures.len(),
-        RecordIdentifier {
-            head: captures[0].to_string(),
-            tail: captures[1..].join("_"),
-        },
-    ))
+        .map(|m| m.as_str().to_string())
+        .collect())
+}
+
+/// Returns the names of the components extracted by a barcode regex. These are the names of the
+/// capture groups if every group is named, e.g. `(?<barcode>...)_(?<umi>...)`, then the component
+/// names of the preset, and otherwise default names.
+fn component_names(re: &Regex, format: &BarcodeFormat) -> Vec<String> {
+    let count = re.captures_len() - 1;
+
+    let named = re.capture_names().skip(1).flatten().collect::<Vec<_>>();
+    if count > 0 && named.len() == count {
+        return named.into_iter().map(String::from).collect();
+    }
+
+    if format.components.len() == count {
+        return format.components.clone();
+    }
+
+    default_component_names(count)
 }
 
 /// Constructs an index from a FASTQ file and writes the results to an output file.
@@ -405,14 +583,17 @@ fn extract_bc_from_header(
 /// # Arguments
 ///
 /// * `infile` - A string slice representing the path to the input FASTQ file.
-/// * `outfile` - A string slice representing the path to the output file.
-/// * `barcode_regex` - A string slice representing the regex pattern for extracting barcodes.
+/// * `wtr` - The writer of the output file. If it was created `with_duplicates`, the duplicates of
+///   the index are found as it is written.
+/// * `format` - The barcode format, containing the regex for extracting barcodes.
 /// * `skip_unmatched` - A boolean indicating whether to skip unmatched reads.
 /// * `clusters` - An optional string representing the path to the cluster file.
+/// * `sample_column` - Whether the cluster file has a sample column after the read ID.
+/// * `filter_opts` - The length and quality filters to apply to each read.
 ///
 /// # Returns
 ///
-/// Returns a `Result` indicating success or failure.
+/// Returns the metadata of the index, and its duplicates if they were found.
 ///
 /// # Errors
 ///
@@ -420,12 +601,13 @@ fn extract_bc_from_header(
 /// or processing the data fails.
 pub fn construct_index(
     infile: &str,
-    outfile: &str,
-    barcode_regex: &str,
+    mut wtr: IndexWriter,
+    format: &BarcodeFormat,
     skip_unmatched: bool,
     clusters: &Option<String>,
+    sample_column: bool,
     filter_opts: FilterOpts,
-) -> Result<()> {
+) -> Result<(ReadFileMetadata, Option<IndexDuplicates>)> {
     // time everything!
     let now = std::time::Instant::now();
 
@@ -433,11 +615,17 @@ pub fn construct_index(
     let f = File::open(infile).expect("File could not be opened");
     let reader = BufReader::new(f);
 
-    // create the index file writer
-    let mut wtr = IndexWriter::new(outfile)?;
     wtr.metadata.file_path = std::fs::canonicalize(infile)?.display().to_string();
-
-    let re = Regex::new(barcode_regex)?;
+    wtr.metadata.parameters = IndexParameters {
+        barcode_regex: format.regex.clone(),
+        preset: format.preset.clone(),
+        clusters: clusters.clone(),
+        sample_column,
+        skip_unmatched,
+        len: filter_opts.len.to_string(),
+        qual: filter_opts.quality.to_string(),
+        command_line: std::env::args().collect::<Vec<_>>().join(" "),
+    };
 
     if let Some(filepath) = clusters {
         // parse identifier from a separate clusters file
@@ -450,12 +638,31 @@ pub fn construct_index(
             reader,
             &mut wtr,
             &mut cluster_rdr,
+            sample_column,
             skip_unmatched,
             filter_opts,
         )?
     } else {
         // parse the identifier from the header
-        iter_lines_with_regex(reader, &mut wtr, &re, skip_unmatched, filter_opts)?
+        let re = Regex::new(&format.regex)?;
+        let mut components = component_names(&re, format);
+
+        // a component named `sample` is the sample of a multi-sample library
+        let sample_group = components.iter().position(|c| c == SAMPLE_COMPONENT);
+        if let Some(i) = sample_group {
+            info!("Reading the sample of each read from the `{SAMPLE_COMPONENT}` component");
+            components.remove(i);
+        }
+        wtr.metadata.components = components;
+
+        iter_lines_with_regex(
+            reader,
+            &mut wtr,
+            &re,
+            sample_group,
+            skip_unmatched,
+            filter_opts,
+        )?
     }
 
     // amount of time passed
@@ -477,7 +684,10 @@ pub fn construct_index(
         )
     }
 
-    wtr.finish_write()
+    let duplicates = wtr.finish_duplicates()?;
+    wtr.finish_write()?;
+
+    Ok((wtr.metadata, duplicates))
 }
 
 #[derive(Error, Debug)]
@@ -516,7 +726,8 @@ using capture group
         "invalid cluster row: should be of the format
   `READ_ID;BC;UMI`
 or
-  `READ_ID;BC`, but instead got
+  `READ_ID;BC`, or have further components as in
+  `READ_ID;SAMPLE;BC1;BC2;UMI`, but instead got
 {row}"
     )]
     InvalidClusterRow { row: String },
@@ -1,12 +1,15 @@
+use crate::compress::OutputWriter;
 use crate::duplicates::{DuplicateMap, RecordIdentifier, RecordPosition};
+use crate::filter::FilterReason;
 use anyhow::{Context, Result};
 use needletail::parser::SequenceRecord;
 use needletail::{parse_fastx_reader, parser::FastqReader, FastxReader};
-use std::collections::HashSet;
 use std::fmt::Write as FmtWrite;
 // needed for write! to be implemented on Strings
 use crate::index::{IndexReader, IndexReaderRecords, IndexRecord};
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 use std::fs::File;
 use std::io::{BufReader, Read, Seek, SeekFrom, Write};
 use std::iter::Map;
@@ -85,6 +88,7 @@ impl Record {
     /// # Arguments
     ///
     /// * `umi_group` - The UMI group identifier.
+    /// * `sample` - The sample of the UMI group, which is only written if it is not empty.
     /// * `read_type` - The type of read (Consensus, Original, Ignored).
     /// * `group_idx` - The index of the read in the group.
     /// * `group_size` - The size of the group.
@@ -95,6 +99,7 @@ impl Record {
     pub fn add_metadata(
         &mut self,
         umi_group: usize,
+        sample: &str,
         read_type: ReadType,
         group_idx: usize,
         group_size: usize,
@@ -113,6 +118,10 @@ impl Record {
         write!(self.id, " UT:Z:{read_type_label} UG:i:{umi_group}")
             .expect("String writing should not error");
 
+        if !sample.is_empty() {
+            write!(self.id, " SM:Z:{sample}").expect("String writing should not error");
+        }
+
         // don't report the group average quality if the readtype is Original or Ignored
         if !matches!(read_type, ReadType::Original | ReadType::Ignored) {
             write!(self.id, " QL:f:{avg_qual:.2}").expect("String writing should not error");
@@ -120,6 +129,104 @@ impl Record {
     }
 }
 
+impl Record {
+    /// Adds metadata to a read which was ignored when the index was created, and so is not in any
+    /// UMI group, through an in-place modify.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - Why the read was ignored, which older indexes do not record.
+    pub fn add_ignored_metadata(&mut self, reason: Option<FilterReason>) {
+        write!(self.id, " UT:Z:IGN").expect("String writing should not error");
+
+        if let Some(reason) = reason {
+            write!(self.id, " FR:Z:{reason}").expect("String writing should not error");
+        }
+    }
+}
+
+/// A .fastq writer which separates records with newlines.
+pub struct FastqWriter {
+    writer: OutputWriter,
+    first: bool,
+}
+
+impl FastqWriter {
+    pub fn new(writer: OutputWriter) -> Self {
+        FastqWriter {
+            writer,
+            first: true,
+        }
+    }
+
+    pub fn write(&mut self, rec: &Record) -> Result<()> {
+        // add a newline at the start, unless this is the first line in the file
+        if !self.first {
+            self.writer.write_all(b"\n")?;
+        }
+        self.first = false;
+
+        rec.write_fastq(&mut self.writer)?;
+        Ok(())
+    }
+
+    /// Flushes and finishes the writer.
+    pub fn finish(self) -> Result<()> {
+        self.writer.finish()
+    }
+}
+
+/// Writers for the reads which are ignored when iterating over UMI groups, because they were
+/// filtered out or did not match the barcode format when the index was created.
+#[derive(Default)]
+pub struct IgnoredOutputs {
+    filtered: Option<FastqWriter>,
+    unmatched: Option<FastqWriter>,
+}
+
+impl IgnoredOutputs {
+    /// Creates writers for ignored reads.
+    ///
+    /// # Arguments
+    ///
+    /// * `filtered` - The writer for reads which failed the length or quality filters.
+    /// * `unmatched` - The writer for reads which did not match the barcode format or clusters.
+    pub fn new(filtered: Option<OutputWriter>, unmatched: Option<OutputWriter>) -> Self {
+        IgnoredOutputs {
+            filtered: filtered.map(FastqWriter::new),
+            unmatched: unmatched.map(FastqWriter::new),
+        }
+    }
+
+    /// Whether any ignored reads are written.
+    fn is_empty(&self) -> bool {
+        self.filtered.is_none() && self.unmatched.is_none()
+    }
+
+    /// Labels an ignored read with `IGN` and the reason it was ignored, and writes it to the
+    /// output for that reason, if one was given.
+    fn write(&mut self, mut rec: Record, reason: Option<FilterReason>) -> Result<()> {
+        let writer = match reason {
+            Some(FilterReason::Unmatched) => &mut self.unmatched,
+            _ => &mut self.filtered,
+        };
+
+        if let Some(writer) = writer {
+            rec.add_ignored_metadata(reason);
+            writer.write(&rec)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes and finishes each writer.
+    pub fn finish(self) -> Result<()> {
+        for writer in [self.filtered, self.unmatched].into_iter().flatten() {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
 pub struct UMIGroup {
     /// The "Identifier" of this group, typically a "BC_UMI" string
     pub id: RecordIdentifier,
@@ -184,16 +291,122 @@ pub fn get_record_from_position<R: Read + Seek + Send>(
     Record::try_from(rec).context("Could not perform utf8 conversions")
 }
 
+/// Reads records at known positions of the input. Reads do not share a file cursor, so records can
+/// be read concurrently from many threads.
+pub enum RandomReader {
+    /// Positional reads (`pread`) from the input file.
+    File(File),
+    /// A memory map of the whole input file, which avoids a system call for each read.
+    Mmap(memmap2::Mmap),
+}
+
+impl RandomReader {
+    /// Opens the input for reading records, either as a file or as a memory map.
+    pub fn open(input: &str, mmap: bool) -> Result<Self> {
+        let file = File::open(input).with_context(|| format!("Unable to open file {input}"))?;
+        if !mmap {
+            return Ok(RandomReader::File(file));
+        }
+
+        // safety: the map is only valid while the input is not modified, which is already
+        // required for the positions in the index to be valid
+        let map = unsafe { memmap2::Mmap::map(&file) }
+            .with_context(|| format!("Unable to memory map file {input}"))?;
+
+        // reads are fetched from random positions, so reading ahead is wasted
+        #[cfg(unix)]
+        map.advise(memmap2::Advice::Random)
+            .with_context(|| format!("Unable to memory map file {input}"))?;
+
+        Ok(RandomReader::Mmap(map))
+    }
+
+    /// Reads the record at a position in the input.
+    pub fn get_record(&self, pos: &RecordPosition) -> Result<Record> {
+        let context = || {
+            format!(
+                "Could not read {} bytes at position {}",
+                pos.length, pos.pos
+            )
+        };
+
+        let bytes = match self {
+            RandomReader::File(file) => {
+                let mut bytes = vec![0; pos.length];
+                read_exact_at(file, &mut bytes, pos.pos as u64).with_context(context)?;
+                std::borrow::Cow::Owned(bytes)
+            }
+            RandomReader::Mmap(map) => std::borrow::Cow::Borrowed(
+                map.get(pos.pos..pos.pos + pos.length)
+                    .with_context(context)?,
+            ),
+        };
+
+        // create a needletail 'reader' with the file at this location
+        let mut fq_reader = FastqReader::new(&bytes[..]);
+
+        let rec = fq_reader.next().context("Unexpected EOF")??;
+
+        Record::try_from(rec).context("Could not perform utf8 conversions")
+    }
+}
+
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    while !buf.is_empty() {
+        match std::os::windows::fs::FileExt::seek_read(file, buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
 pub struct UMIGroupCollection {
     seq_parser: Box<dyn FastxReader>,
-    rnd_reader: File,
-    index: IndexReader,
+    rnd_reader: RandomReader,
     duplicates: DuplicateMap,
-    records: IndexReaderRecords,
+    /// The records of the index, read alongside the input. This is `None` if the collection was
+    /// created with `from_duplicates`.
+    records: Option<IndexReaderRecords>,
+    ignored_outputs: IgnoredOutputs,
 }
 
 impl UMIGroupCollection {
-    pub fn new(mut index: IndexReader, input: &str) -> Result<Self> {
+    /// Creates a collection of the UMI groups of an index.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The index of the input.
+    /// * `input` - The path to the .fastq file which the index was created from.
+    /// * `mmap` - Whether to memory map the input to read duplicates from, instead of reading
+    ///   from the file.
+    pub fn new(mut index: IndexReader, input: &str, mmap: bool) -> Result<Self> {
+        let (duplicates, _) = index.get_duplicates()?;
+        let mut collection = Self::from_duplicates(input, duplicates, mmap)?;
+        collection.records = Some(index.index_records()?);
+        Ok(collection)
+    }
+
+    /// Creates a collection from the duplicates of an index, without reading the index. Reads which
+    /// are not in `duplicates` are ignored, but why each read was ignored is not known, so ignored
+    /// outputs should only be used with a collection created by `new`.
+    ///
+    /// # Arguments
+    ///
+    /// * `input` - The path to the .fastq file which the index was created from.
+    /// * `duplicates` - The `DuplicateMap` of the index.
+    /// * `mmap` - Whether to memory map the input to read duplicates from, instead of reading
+    ///   from the file.
+    pub fn from_duplicates(input: &str, duplicates: DuplicateMap, mmap: bool) -> Result<Self> {
         let file = File::open(input).with_context(|| format!("Unable to open file {input}"))?;
 
         // create a sequential reader with a buffer size of BUF_CAPACITY
@@ -204,61 +417,58 @@ impl UMIGroupCollection {
 
         // create a random access reader. we don't want a buffer as we plan to read a fixed amount of
         // bytes randomly
-        let mut rnd_reader =
-            File::open(input).with_context(|| format!("Unable to open file {input}"))?;
-
-        let (duplicates, _) = index.get_duplicates()?;
-        let records = index.index_records()?;
+        let rnd_reader = RandomReader::open(input, mmap)?;
 
         Ok(UMIGroupCollection {
             seq_parser,
             rnd_reader,
-            index,
             duplicates,
-            records,
+            records: None,
+            ignored_outputs: IgnoredOutputs::default(),
         })
     }
 
-    /// Retrieves the next record from the sequence parser and the corresponding index record.
+    /// Writes the reads which are ignored, because they were filtered out or did not match when
+    /// the index was created, to `outputs` while iterating over UMI groups. The outputs should be
+    /// finished with `finish_ignored_outputs` after iterating.
+    pub fn with_ignored_outputs(mut self, outputs: IgnoredOutputs) -> Self {
+        self.ignored_outputs = outputs;
+        self
+    }
+
+    /// Flushes and finishes the writers given to `with_ignored_outputs`.
+    pub fn finish_ignored_outputs(&mut self) -> Result<()> {
+        std::mem::take(&mut self.ignored_outputs).finish()
+    }
+
+    /// Retrieves the next record from the sequence parser, along with whether it is ignored and,
+    /// if the index is being read, why it is ignored.
     ///
     /// # Errors
     ///
     /// This function will return an error if:
     /// * The sequence parser encounters an error while reading the next record.
     /// * The index reader encounters an error while reading the next index item.
-    pub fn next_record(&mut self) -> Result<Option<(IndexRecord, SequenceRecord)>> {
+    pub fn next_record(&mut self) -> Result<Option<(bool, Option<FilterReason>, SequenceRecord)>> {
         let Some(rec) = self.seq_parser.next() else {
             return Ok(None);
         };
         let rec = rec?;
-        let idx = self
-            .records
-            .next()
-            .context("No corresponding index record")??;
-
-        Ok(Some((idx, rec)))
-    }
-
-    pub fn get_rec_random(&mut self, pos: &RecordPosition) -> Result<Record> {
-        self.rnd_reader
-            .seek(SeekFrom::Start(pos.pos as u64))
-            .with_context(|| format!("Unable to seek file at position {}", pos.pos))?;
-
-        // read the exact number of bytes
-        let mut bytes = vec![0; pos.length];
-        self.rnd_reader.read_exact(&mut bytes).with_context(|| {
-            format!(
-                "Could not read {} lines at position {}",
-                pos.length, pos.pos
-            )
-        })?;
 
-        // create a needletail 'reader' with the file at this location
-        let mut fq_reader = FastqReader::new(&bytes[..]);
-
-        let rec = fq_reader.next().context("Unexpected EOF")??;
+        // without the index, the reads which are not in the duplicate map are those ignored
+        let (ignored, reason) = match &mut self.records {
+            Some(records) => {
+                let idx: IndexRecord =
+                    records.next().context("No corresponding index record")??;
+                (idx.ignored, idx.reason)
+            }
+            None => {
+                let position = rec.position().byte() as usize;
+                (self.duplicates.read_by_pos(position).is_none(), None)
+            }
+        };
 
-        Record::try_from(rec).context("Could not perform utf8 conversions")
+        Ok(Some((ignored, reason, rec)))
     }
 
     /// Creates a _streaming_ iterator over UMI groups in the collection.
@@ -273,25 +483,58 @@ impl UMIGroupCollection {
     ///
     /// This function returns an iterator over `UMIGroupCollectionIter` which returns `UMIGroup`.
     pub fn stream_iter(&mut self, duplicates_only: bool) -> UMIGroupCollectionIter {
+        let visited_reads = ReadSet::new(self.duplicates.read_count());
         UMIGroupCollectionIter {
             collection: self,
-            visited_reads: HashSet::new(),
+            visited_reads,
             duplicates_only,
             current_idx: 0,
+            pending: VecDeque::new(),
+        }
+    }
+}
+
+/// A set of reads in a `DuplicateMap`, stored as a bitset with one bit per read.
+struct ReadSet {
+    bits: Vec<u64>,
+}
+
+impl ReadSet {
+    fn new(read_count: usize) -> Self {
+        ReadSet {
+            bits: vec![0; read_count.div_ceil(64)],
         }
     }
+
+    fn insert(&mut self, read: u32) {
+        self.bits[read as usize / 64] |= 1 << (read % 64);
+    }
+
+    fn contains(&self, read: u32) -> bool {
+        self.bits[read as usize / 64] & (1 << (read % 64)) != 0
+    }
 }
 
+/// The number of reads to gather into a batch of groups before the reads of every group in the
+/// batch are fetched in parallel.
+const FETCH_BATCH_READS: usize = 4096;
+
 pub struct UMIGroupCollectionIter<'a> {
     collection: &'a mut UMIGroupCollection,
-    visited_reads: HashSet<usize>,
+    visited_reads: ReadSet,
     duplicates_only: bool,
     current_idx: usize,
+    /// Groups whose reads have all been fetched, in the order they are returned.
+    pending: VecDeque<UMIGroup>,
 }
 
 impl UMIGroupCollectionIter<'_> {
     /// Iterates over records in a FASTQ file by UMI group.
     ///
+    /// Groups are found by reading the input in order, and are returned in the order of their
+    /// first read. The other reads of each group are fetched from their positions in the input;
+    /// this is done for a batch of groups at a time, in parallel across the rayon thread pool.
+    ///
     /// # Returns
     /// This function returns an iterator of Results. When an Error is encountered,
     /// the caller should immediately stop. See the documentation for `until_err` to see an example.
@@ -306,57 +549,124 @@ impl UMIGroupCollectionIter<'_> {
     /// * There are issues reading the read at at the specified position. See the documentation for
     ///   `get_read_at_position` for more.
     pub fn next(&mut self) -> Result<Option<UMIGroup>> {
-        let Some((idx, rec)) = self.collection.next_record()? else {
-            return Ok(None);
-        };
-        // note: we don't need to add this to visited_reads, since traversal is in order
-        let position = rec.position().byte() as usize;
-
-        // if this is marked to ignore or we have already visited this, we can skip
-        if self.visited_reads.contains(&position) || idx.ignored {
-            return self.next();
+        if self.pending.is_empty() {
+            self.fetch_batch()?;
         }
+        Ok(self.pending.pop_front())
+    }
 
-        let rec = Record::try_from(rec).context("Could not perform utf8 conversions")?;
-        // get the corresponding entry in duplicates
-        let id = RecordIdentifier::from_string(&idx.id);
-        let group = self
-            .collection
-            .duplicates
-            .records_by_pos(&position)
-            .context("Could not find")?
-            .clone();
-
-        // skip over group sizes which are more than 1
-        let group_size = group.len();
-        if self.duplicates_only && group_size == 1 {
-            return self.next();
+    /// Finds the next batch of groups, and fetches the reads of every group in parallel.
+    fn fetch_batch(&mut self) -> Result<()> {
+        let mut batch = Vec::new();
+        let mut positions = Vec::new();
+        let mut batch_reads = 0;
+
+        while batch_reads < FETCH_BATCH_READS {
+            let Some((group, others)) = self.next_group()? else {
+                break;
+            };
+
+            batch_reads += others.len() + 1;
+            positions.extend(
+                others
+                    .iter()
+                    .map(|&read| self.collection.duplicates.record_position(read)),
+            );
+            batch.push((group, others.len()));
         }
 
-        let mut records = Vec::with_capacity(group_size);
-        records.push(rec);
-
-        // get all the other records as well - skip the first one, that's `rec`
-        for pos in group.iter().skip(1) {
-            self.visited_reads.insert(pos.pos);
-
-            let rec = self.collection.get_rec_random(pos)?;
-            records.push(rec)
+        let reader = &self.collection.rnd_reader;
+        let fetched = positions
+            .par_iter()
+            .map(|pos| reader.get_record(pos))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut fetched = fetched.into_iter();
+        for (mut group, count) in batch {
+            group.records.extend(fetched.by_ref().take(count));
+            group.avg_qual = group
+                .records
+                .iter()
+                .map(|r| r.phred_quality_avg())
+                .sum::<f64>()
+                / (group.records.len() as f64);
+
+            self.pending.push_back(group);
         }
 
-        let avg_qual =
-            records.iter().map(|r| r.phred_quality_avg()).sum::<f64>() / (records.len() as f64);
-
-        let umigroup = UMIGroup {
-            id,
-            index: self.current_idx,
-            records,
-            avg_qual,
-            ignore: false,
-            consensus: None,
-        };
-        self.current_idx += 1;
+        Ok(())
+    }
 
-        Ok(Some(umigroup))
+    /// Finds the next group in the input, which has not been returned already.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<(UMIGroup, Vec<u32>)>` - The group, with only its first read, and the other reads
+    ///   of the group which are yet to be fetched.
+    fn next_group(&mut self) -> Result<Option<(UMIGroup, Vec<u32>)>> {
+        let write_ignored = !self.collection.ignored_outputs.is_empty();
+
+        loop {
+            let Some((ignored, reason, rec)) = self.collection.next_record()? else {
+                return Ok(None);
+            };
+            // note: we don't need to add this to visited_reads, since traversal is in order
+            let position = rec.position().byte() as usize;
+
+            // if this is marked to ignore, we can skip, after writing it to any ignored output
+            if ignored {
+                if write_ignored {
+                    let rec =
+                        Record::try_from(rec).context("Could not perform utf8 conversions")?;
+                    self.collection.ignored_outputs.write(rec, reason)?;
+                }
+                continue;
+            }
+            let rec = Record::try_from(rec).context("Could not perform utf8 conversions")?;
+
+            // get the corresponding entry in duplicates
+            let duplicates = &self.collection.duplicates;
+            let read = duplicates
+                .read_by_pos(position)
+                .context("Could not find a record in the duplicate map")?;
+
+            // if we have already visited this, we can skip
+            if self.visited_reads.contains(read) {
+                continue;
+            }
+
+            // the identifier is the one reads were grouped by, which may differ from the index
+            let group_id = duplicates
+                .group_by_pos(position)
+                .expect("read is in the map");
+            let group = duplicates.group_reads(group_id);
+
+            // skip over group sizes which are more than 1
+            let group_size = group.len();
+            if self.duplicates_only && group_size == 1 {
+                continue;
+            }
+
+            // the other records are fetched later - skip the first one, that's `rec`
+            let others = group[1..].to_vec();
+            for &read in others.iter() {
+                self.visited_reads.insert(read);
+            }
+
+            let mut records = Vec::with_capacity(group_size);
+            records.push(rec);
+
+            let umigroup = UMIGroup {
+                id: duplicates.identifier(group_id),
+                index: self.current_idx,
+                records,
+                avg_qual: 0.0,
+                ignore: false,
+                consensus: None,
+            };
+            self.current_idx += 1;
+
+            return Ok(Some((umigroup, others)));
+        }
     }
 }
@@ -6,50 +6,107 @@ extern crate env_logger;
 extern crate log;
 use std::{
     fs::File,
-    io::{prelude::*, stdout, BufWriter},
+    io::{prelude::*, stdout},
     path::Path,
 };
 
 use anyhow::Result;
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
 
+#[cfg(feature = "benchmark")]
+mod benchmark;
 mod call;
 mod cli;
+mod compress;
+mod config;
+mod detect;
 mod duplicates;
+mod evaluate;
 mod file;
 mod filter;
 mod group;
+mod histogram;
 mod index;
 mod io;
+mod packed;
+mod plot;
 mod preset;
+mod qc;
+mod query;
+mod run;
+mod saturation;
+mod simulate;
+mod sort;
 mod summary;
 
-use crate::io::UMIGroupCollection;
-use cli::{Cli, Commands};
+use crate::compress::{Compression, OutputWriter};
+use crate::duplicates::GroupingOpts;
+use crate::io::{IgnoredOutputs, UMIGroupCollection};
+use cli::{Cli, Commands, PresetsCommands};
 
-/// Creates a `BufWriter` for the given output option. This allows for an output file to be passed
-/// or otherwise will default to using standard output.
+/// Creates an `OutputWriter` for the given output option. This allows for an output file to be
+/// passed or otherwise will default to using standard output.
 ///
-/// If `output` is `Some`, it creates a file at the specified path and returns a `BufWriter` for it.
-/// If `output` is `None`, it returns a `BufWriter` for the standard output.
+/// If `output` is `Some`, it creates a file at the specified path and returns a writer for it.
+/// If `output` is `None`, it returns a writer for the standard output.
 ///
 /// # Arguments
 ///
 /// * `output` - An `Option` containing the path to the output file as a `String`.
+/// * `compress` - The compression format to use. If `None`, this is inferred from the extension
+///   of `output`, or no compression is used when writing to standard output.
+/// * `threads` - The number of threads to use for compression.
 ///
 /// # Returns
 ///
-/// A `Result` containing a `BufWriter` that implements `Write`.
-fn get_writer(output: &Option<String>) -> Result<impl Write> {
-    // get output as a BufWriter - equal to stdout if None
-    let writer = BufWriter::new(match output {
+/// A `Result` containing an `OutputWriter`, which should be `finish`ed after writing.
+fn get_writer(
+    output: &Option<String>,
+    compress: &Option<Compression>,
+    threads: usize,
+) -> Result<OutputWriter> {
+    let compression = compress.unwrap_or_else(|| match output {
+        Some(ref x) => Compression::from_path(x),
+        None => Compression::None,
+    });
+
+    // get output as a Write - equal to stdout if None
+    let inner = match output {
         Some(ref x) => {
             let file = File::create(Path::new(x))?;
             Box::new(file) as Box<dyn Write + Send>
         }
         None => Box::new(stdout()) as Box<dyn Write + Send>,
-    });
-    Ok(writer)
+    };
+
+    if compression != Compression::None {
+        info!("Compressing output as {compression:?} with {threads} threads");
+    }
+
+    OutputWriter::new(inner, compression, threads)
+}
+
+/// Creates the writers for reads which are ignored by `call` and `group`. Compression of each
+/// output is inferred from its file extension.
+///
+/// # Arguments
+///
+/// * `filtered` - The path to write reads which were filtered out to, if any.
+/// * `unmatched` - The path to write reads which did not match to, if any.
+/// * `threads` - The number of threads to use for compression.
+fn get_ignored_outputs(
+    filtered: &Option<String>,
+    unmatched: &Option<String>,
+    threads: usize,
+) -> Result<IgnoredOutputs> {
+    let writer = |path: &Option<String>| -> Result<Option<OutputWriter>> {
+        match path {
+            Some(_) => Ok(Some(get_writer(path, &None, threads)?)),
+            None => Ok(None),
+        }
+    };
+
+    Ok(IgnoredOutputs::new(writer(filtered)?, writer(unmatched)?))
 }
 
 fn try_main() -> Result<()> {
@@ -57,52 +114,128 @@ fn try_main() -> Result<()> {
         .format_target(false)
         .init();
 
-    let cli = Cli::parse();
+    // options from a `--config` file are added to the arguments before they are parsed
+    let args = config::apply_config(Cli::command(), std::env::args_os().collect())?;
+    let matches = Cli::command().get_matches_from(args);
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
 
-    println!("nailpolish v{}", cli::VERSION);
+    // print to stderr, so that output written to stdout (which may be compressed) is not corrupted
+    eprintln!("nailpolish v{}", cli::VERSION);
 
     match &cli.command {
-        Commands::Summary { index, output } => {
-            summary::summarize(index, output)?;
+        Commands::Summary {
+            index,
+            samples,
+            output,
+            format,
+            cell_output,
+            cell,
+            molecule,
+        } => {
+            let grouping = GroupingOpts {
+                cell: cell.clone(),
+                molecule: molecule.clone(),
+            };
+            summary::summarize(index, samples, output, *format, cell_output, &grouping)?;
         }
         Commands::Index {
             file,
             output,
             preset,
+            presets,
+            detect_reads,
             barcode_regex,
             clusters,
+            sample_column,
             skip_unmatched,
             len,
             qual,
+            dump_config,
         } => {
-            let barcode_regex = match barcode_regex {
-                Some(v) => {
-                    info!("Using specified barcode format: {v}");
-                    v.clone()
-                }
-                None => {
-                    let regex = preset::get_barcode_regex(preset);
-                    info!("Using preset barcode format {regex}");
-                    regex
-                }
-            };
+            if let Some(path) = dump_config {
+                config::dump_config(&Cli::command(), &matches, path)?;
+            }
+
+            let format = preset::resolve_barcode_format(
+                barcode_regex,
+                preset,
+                presets,
+                file,
+                *detect_reads,
+            )?;
 
             let filter_opts = filter::FilterOpts {
-                len: len.clone(),
-                quality: qual.clone(),
+                len: *len,
+                quality: *qual,
             };
 
             index::construct_index(
                 file,
-                output,
-                &barcode_regex,
+                index::IndexWriter::new(output)?,
+                &format,
                 *skip_unmatched,
                 clusters,
+                *sample_column,
                 filter_opts,
             )?;
 
             info!("Completed index generation to {output}");
         }
+        Commands::Run {
+            file,
+            preset,
+            presets,
+            detect_reads,
+            output,
+            clusters,
+            sample_column,
+            barcode_regex,
+            skip_unmatched,
+            len,
+            qual,
+            format,
+            threads,
+            duplicates_only,
+            report_original_reads,
+            compress,
+            cell,
+            molecule,
+            mmap,
+        } => {
+            let barcode_format = preset::resolve_barcode_format(
+                barcode_regex,
+                preset,
+                presets,
+                file,
+                *detect_reads,
+            )?;
+
+            let opts = run::RunOpts {
+                output_dir: output.clone(),
+                barcode_format,
+                skip_unmatched: *skip_unmatched,
+                clusters: clusters.clone(),
+                sample_column: *sample_column,
+                filter_opts: filter::FilterOpts {
+                    len: *len,
+                    quality: *qual,
+                },
+                format: *format,
+                threads: *threads,
+                duplicates_only: *duplicates_only,
+                report_original_reads: *report_original_reads,
+                compress: *compress,
+                grouping: GroupingOpts {
+                    cell: cell.clone(),
+                    molecule: molecule.clone(),
+                },
+                mmap: *mmap,
+            };
+
+            run::run(file, &opts)?;
+
+            info!("Completed successfully.")
+        }
         Commands::Call {
             index,
             input,
@@ -110,10 +243,28 @@ fn try_main() -> Result<()> {
             threads,
             duplicates_only,
             report_original_reads,
+            compress,
+            cell,
+            molecule,
+            filtered_output,
+            unmatched_output,
+            mmap,
+            dump_config,
         } => {
-            let index = index::IndexReader::from_path(index)?;
-            let mut collection = UMIGroupCollection::new(index, input)?;
-            let mut writer = get_writer(output)?;
+            if let Some(path) = dump_config {
+                config::dump_config(&Cli::command(), &matches, path)?;
+            }
+
+            let mut index = index::IndexReader::from_path(index)?.with_grouping(GroupingOpts {
+                cell: cell.clone(),
+                molecule: molecule.clone(),
+            });
+            call::check_index_parameters(&mut index, input, filtered_output, unmatched_output)?;
+
+            let ignored = get_ignored_outputs(filtered_output, unmatched_output, *threads)?;
+            let mut collection =
+                UMIGroupCollection::new(index, input, *mmap)?.with_ignored_outputs(ignored);
+            let mut writer = get_writer(output, compress, *threads)?;
 
             call::consensus(
                 &mut collection,
@@ -122,23 +273,235 @@ fn try_main() -> Result<()> {
                 *duplicates_only,
                 *report_original_reads,
             )?;
+            writer.finish()?;
+            collection.finish_ignored_outputs()?;
 
             info!("Completed successfully.")
         }
+        Commands::Presets { command } => match command {
+            PresetsCommands::List { presets } => preset::list_presets(presets, &mut stdout())?,
+        },
+        Commands::Qc {
+            input,
+            output,
+            json,
+        } => {
+            qc::summarize_call(input, output, json)?;
+        }
+        Commands::Simulate {
+            output,
+            truth,
+            transcripts,
+            transcript_count,
+            transcript_length,
+            cells,
+            umis,
+            barcode_length,
+            umi_length,
+            duplication,
+            mean_duplicates,
+            substitution_rate,
+            insertion_rate,
+            deletion_rate,
+            barcode_error_rate,
+            umi_error_rate,
+            reference_output,
+            seed,
+        } => {
+            let opts = simulate::SimulateOpts {
+                transcripts: transcripts.clone(),
+                transcript_count: *transcript_count,
+                transcript_length: *transcript_length,
+                cells: *cells,
+                umis: *umis,
+                barcode_length: *barcode_length,
+                umi_length: *umi_length,
+                duplication: *duplication,
+                mean_duplicates: *mean_duplicates,
+                substitution_rate: *substitution_rate,
+                insertion_rate: *insertion_rate,
+                deletion_rate: *deletion_rate,
+                barcode_error_rate: *barcode_error_rate,
+                umi_error_rate: *umi_error_rate,
+                reference_output: reference_output.clone(),
+                seed: *seed,
+            };
+
+            let mut writer = get_writer(output, &None, 1)?;
+            simulate::simulate(&opts, &mut writer, truth)?;
+            writer.finish()?;
+
+            info!("Wrote truth table to {truth}");
+        }
+        Commands::Evaluate {
+            truth,
+            index,
+            input,
+            grouped,
+            consensus,
+            reference,
+            output,
+        } => {
+            let source = match (index, input, grouped) {
+                (Some(index), Some(input), _) => evaluate::GroupingSource::Index {
+                    index: index.clone(),
+                    input: input.clone(),
+                },
+                (_, _, Some(grouped)) => evaluate::GroupingSource::Grouped(grouped.clone()),
+                _ => unreachable!("clap requires either --index and --input, or --grouped"),
+            };
+
+            let evaluation = evaluate::evaluate(truth, &source, consensus, reference)?;
+
+            let mut writer = get_writer(output, &None, 1)?;
+            serde_json::to_writer_pretty(&mut writer, &evaluation)?;
+            writeln!(writer)?;
+            writer.finish()?;
+
+            let grouping = &evaluation.grouping;
+            info!(
+                "Grouping precision {:.4}, recall {:.4}",
+                grouping.precision, grouping.recall
+            );
+        }
+        #[cfg(feature = "benchmark")]
+        Commands::Benchmark {
+            reads,
+            cells,
+            mean_duplicates,
+            seed,
+        } => {
+            let opts = benchmark::BenchmarkOpts {
+                reads: *reads,
+                cells: *cells,
+                mean_duplicates: *mean_duplicates,
+                seed: *seed,
+            };
+            benchmark::benchmark(&opts, &mut stdout())?;
+        }
         Commands::Group {
             index,
             input,
             output,
+            threads,
+            compress,
+            sort,
+            sort_memory,
+            shell,
+            keep_going,
+            cell,
+            molecule,
+            mmap,
+            filtered_output,
+            unmatched_output,
+            command,
         } => {
-            let index = index::IndexReader::from_path(index)?;
-            let mut collection = UMIGroupCollection::new(index, input)?;
+            // the reads of each group are fetched in parallel using the global thread pool
+            rayon::ThreadPoolBuilder::new()
+                .num_threads(*threads)
+                .build_global()?;
+
+            let index = index::IndexReader::from_path(index)?.with_grouping(GroupingOpts {
+                cell: cell.clone(),
+                molecule: molecule.clone(),
+            });
+            let ignored = get_ignored_outputs(filtered_output, unmatched_output, *threads)?;
+            let mut collection =
+                UMIGroupCollection::new(index, input, *mmap)?.with_ignored_outputs(ignored);
 
-            let mut writer = get_writer(output)?;
+            let mut writer = get_writer(output, compress, *threads)?;
 
-            group::group(&mut collection, &mut writer)?;
+            if command.is_empty() {
+                group::group(
+                    &mut collection,
+                    &mut writer,
+                    *sort,
+                    sort_memory * 1024usize.pow(2),
+                )?;
+            } else {
+                let opts = group::CommandOpts {
+                    command: command.join(" "),
+                    shell: shell.clone(),
+                    threads: *threads,
+                    keep_going: *keep_going,
+                };
+                group::group_with_command(&mut collection, &mut writer, &opts)?;
+            }
+            writer.finish()?;
+            collection.finish_ignored_outputs()?;
 
             info!("Completed successfully.")
         }
+        Commands::Query {
+            index,
+            input,
+            output,
+            sample,
+            barcode,
+            umi,
+            id,
+            ids,
+            include_ignored,
+        } => {
+            let mut selection = query::Selection {
+                samples: sample.iter().cloned().collect(),
+                barcodes: barcode.iter().cloned().collect(),
+                umis: umi.iter().cloned().collect(),
+                ids: id.iter().cloned().collect(),
+                include_ignored: *include_ignored,
+                ..Default::default()
+            };
+            if let Some(path) = ids {
+                selection.ids.extend(query::read_values(path)?);
+            }
+
+            let mut index = index::IndexReader::from_path(index)?;
+            let mut writer = io::FastqWriter::new(get_writer(output, &None, 1)?);
+            let count = query::query(&mut index, input, &selection, &mut writer)?;
+            writer.finish()?;
+
+            info!("Wrote {count} reads");
+        }
+        Commands::Filter {
+            index,
+            output,
+            len,
+            qual,
+        } => {
+            let output = output.as_ref().unwrap_or(index);
+            let filtered = filter::refilter_index(index, output, *len, *qual)?;
+
+            info!("Wrote the index with {filtered} filtered reads to {output}");
+        }
+        Commands::Subset {
+            index,
+            input,
+            output,
+            index_output,
+            sample,
+            barcode,
+            barcodes,
+            fraction,
+            seed,
+        } => {
+            // filtered reads are kept, so that the subset index is filtered in the same way
+            let mut selection = query::Selection {
+                samples: sample.iter().cloned().collect(),
+                barcodes: barcode.iter().cloned().collect(),
+                fraction: *fraction,
+                seed: *seed,
+                include_ignored: true,
+                ..Default::default()
+            };
+            if let Some(path) = barcodes {
+                selection.barcodes.extend(query::read_values(path)?);
+            }
+
+            let mut index = index::IndexReader::from_path(index)?;
+            let count = query::subset(&mut index, input, &selection, output, index_output)?;
+
+            info!("Wrote {count} reads to {output} and {index_output}");
+        }
     };
     Ok(())
 }
@@ -0,0 +1,122 @@
+/// The longest component which is packed at 2 bits per base. Longer components are stored as-is.
+const MAX_PACKED_LENGTH: usize = 127;
+
+/// Set in the first byte of a component which is packed at 2 bits per base, along with its length.
+/// Components which are stored as-is start with a zero byte instead.
+const PACKED_FLAG: u8 = 0x80;
+
+/// The bases which can be packed, in the order of their 2-bit codes.
+const BASES: [u8; 4] = *b"ACGT";
+
+fn base_code(base: u8) -> Option<u8> {
+    match base {
+        b'A' => Some(0),
+        b'C' => Some(1),
+        b'G' => Some(2),
+        b'T' => Some(3),
+        _ => None,
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut n: usize) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_varint(bytes: &[u8], i: &mut usize) -> usize {
+    let mut n = 0;
+    let mut shift = 0;
+    loop {
+        let byte = bytes[*i];
+        *i += 1;
+        n |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return n;
+        }
+        shift += 7;
+    }
+}
+
+/// Appends a single component to a packed identifier. Components made up only of `ACGT`, such as
+/// barcodes and UMIs, are packed at 4 bases per byte, and any other component is stored as-is.
+fn pack_component(component: &str, out: &mut Vec<u8>) {
+    let bytes = component.as_bytes();
+    let packable = !bytes.is_empty()
+        && bytes.len() <= MAX_PACKED_LENGTH
+        && bytes.iter().all(|&b| base_code(b).is_some());
+
+    if !packable {
+        out.push(0);
+        write_varint(out, bytes.len());
+        out.extend_from_slice(bytes);
+        return;
+    }
+
+    out.push(PACKED_FLAG | bytes.len() as u8);
+    for chunk in bytes.chunks(4) {
+        let byte = chunk.iter().enumerate().fold(0u8, |byte, (i, &b)| {
+            byte | (base_code(b).expect("component is packable") << (2 * i))
+        });
+        out.push(byte);
+    }
+}
+
+/// Packs an identifier into a compact byte string, which is unique to the identifier and can be
+/// compared or hashed directly. See `unpack_identifier` for the inverse function.
+///
+/// # Arguments
+///
+/// * `sample` - The interned index of the sample of the identifier.
+/// * `components` - The components of the identifier, in order.
+/// * `out` - The buffer to append the packed identifier to.
+pub fn pack_identifier(sample: u32, components: &[String], out: &mut Vec<u8>) {
+    write_varint(out, sample as usize);
+    for component in components {
+        pack_component(component, out);
+    }
+}
+
+/// Returns the interned sample index of a packed identifier, without unpacking its components.
+pub fn unpack_sample(bytes: &[u8]) -> u32 {
+    read_varint(bytes, &mut 0) as u32
+}
+
+/// Unpacks an identifier which was packed by `pack_identifier`.
+///
+/// # Returns
+///
+/// * `(u32, Vec<String>)` - The interned index of the sample, and the components of the
+///   identifier.
+pub fn unpack_identifier(bytes: &[u8]) -> (u32, Vec<String>) {
+    let mut i = 0;
+    let sample = read_varint(bytes, &mut i) as u32;
+
+    let mut components = Vec::new();
+    while i < bytes.len() {
+        let header = bytes[i];
+        i += 1;
+
+        if header & PACKED_FLAG == 0 {
+            let len = read_varint(bytes, &mut i);
+            components.push(String::from_utf8_lossy(&bytes[i..i + len]).to_string());
+            i += len;
+            continue;
+        }
+
+        let len = (header & !PACKED_FLAG) as usize;
+        let component = (0..len)
+            .map(|j| BASES[((bytes[i + j / 4] >> (2 * (j % 4))) & 0b11) as usize] as char)
+            .collect();
+        components.push(component);
+        i += len.div_ceil(4);
+    }
+
+    (sample, components)
+}
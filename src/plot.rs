@@ -0,0 +1,358 @@
+use std::fmt::Write;
+
+/// The colours used for each series, in order.
+const PALETTE: [&str; 8] = [
+    "#8e5ea2", "#3e95cd", "#e8c3b9", "#3cba9f", "#c45850", "#ffa600", "#58508d", "#7a7a7a",
+];
+
+const MARGIN_LEFT: f64 = 70.0;
+const MARGIN_RIGHT: f64 = 20.0;
+const MARGIN_TOP: f64 = 30.0;
+const MARGIN_BOTTOM: f64 = 50.0;
+
+/// How a series is drawn.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum SeriesKind {
+    /// One bar per point, centred on the x value
+    Bar,
+    /// Points joined by straight lines
+    Line,
+}
+
+/// A labelled series of `(x, y)` points.
+pub struct Series {
+    pub label: String,
+    pub kind: SeriesKind,
+    pub points: Vec<(f64, f64)>,
+}
+
+impl Series {
+    pub fn bar(label: &str, points: Vec<(f64, f64)>) -> Self {
+        Series {
+            label: label.to_string(),
+            kind: SeriesKind::Bar,
+            points,
+        }
+    }
+
+    pub fn line(label: &str, points: Vec<(f64, f64)>) -> Self {
+        Series {
+            label: label.to_string(),
+            kind: SeriesKind::Line,
+            points,
+        }
+    }
+}
+
+/// A chart made up of one or more series sharing the same axes, rendered to an inline `<svg>`.
+/// Charts are rendered entirely within the binary, so that summary reports can be viewed without
+/// any external JavaScript or network access.
+pub struct Plot {
+    x_label: String,
+    y_label: String,
+    log_x: bool,
+    log_y: bool,
+    width: f64,
+    height: f64,
+    series: Vec<Series>,
+}
+
+/// A mapping from data coordinates to pixel coordinates along one axis.
+struct Axis {
+    min: f64,
+    max: f64,
+    log: bool,
+    /// the pixel coordinates corresponding to `min` and `max`
+    range: (f64, f64),
+}
+
+impl Axis {
+    fn new(values: impl Iterator<Item = f64>, log: bool, range: (f64, f64), pad: f64) -> Self {
+        let (mut min, mut max) = values
+            .filter(|v| v.is_finite() && (!log || *v > 0.0))
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| {
+                (lo.min(v), hi.max(v))
+            });
+
+        if !min.is_finite() {
+            (min, max) = (if log { 1.0 } else { 0.0 }, 1.0);
+        }
+
+        if log {
+            // always show at least one decade
+            min = 10f64.powf(min.log10().floor());
+            max = 10f64.powf(max.log10().ceil().max(min.log10() + 1.0));
+        } else {
+            // linear axes should start at zero, unless there are negative values
+            min = min.min(0.0) - pad;
+            max += pad;
+            if max <= min {
+                max = min + 1.0;
+            }
+        }
+
+        Axis {
+            min,
+            max,
+            log,
+            range,
+        }
+    }
+
+    fn transform(&self, v: f64) -> f64 {
+        if self.log {
+            v.log10()
+        } else {
+            v
+        }
+    }
+
+    /// Converts a data coordinate into a pixel coordinate.
+    fn scale(&self, v: f64) -> f64 {
+        let (lo, hi) = (self.transform(self.min), self.transform(self.max));
+        let frac = (self.transform(v) - lo) / (hi - lo);
+        self.range.0 + frac * (self.range.1 - self.range.0)
+    }
+
+    /// Returns the positions of tick marks along the axis.
+    fn ticks(&self) -> Vec<f64> {
+        if self.log {
+            let (lo, hi) = (self.min.log10() as i32, self.max.log10() as i32);
+            return (lo..=hi).map(|e| 10f64.powi(e)).collect();
+        }
+
+        // choose a 'nice' step of 1, 2 or 5 x 10^k, giving roughly 5 ticks
+        let raw_step = (self.max - self.min) / 5.0;
+        let magnitude = 10f64.powf(raw_step.log10().floor());
+        let step = [1.0, 2.0, 5.0, 10.0]
+            .iter()
+            .map(|m| m * magnitude)
+            .find(|s| *s >= raw_step)
+            .unwrap_or(raw_step);
+
+        let mut ticks = Vec::new();
+        let mut v = (self.min / step).ceil() * step;
+        while v <= self.max + step * 1e-9 {
+            ticks.push(v);
+            v += step;
+        }
+        ticks
+    }
+}
+
+/// Formats a number for an axis label, using SI suffixes for large values.
+pub fn format_number(v: f64) -> String {
+    let abs = v.abs();
+    if abs >= 1e9 {
+        format!("{}G", trim_float(v / 1e9))
+    } else if abs >= 1e6 {
+        format!("{}M", trim_float(v / 1e6))
+    } else if abs >= 1e4 {
+        format!("{}k", trim_float(v / 1e3))
+    } else {
+        trim_float(v)
+    }
+}
+
+/// Formats a float to at most 3 decimal places, removing any trailing zeros.
+fn trim_float(v: f64) -> String {
+    let s = format!("{:.3}", v);
+    let s = s.trim_end_matches('0').trim_end_matches('.');
+    if s == "-0" {
+        "0".to_string()
+    } else {
+        s.to_string()
+    }
+}
+
+/// Escapes text for inclusion in SVG.
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+impl Plot {
+    pub fn new(x_label: &str, y_label: &str) -> Self {
+        Plot {
+            x_label: x_label.to_string(),
+            y_label: y_label.to_string(),
+            log_x: false,
+            log_y: false,
+            width: 800.0,
+            height: 400.0,
+            series: Vec::new(),
+        }
+    }
+
+    /// Use a logarithmic x axis. Any non-positive values are not drawn.
+    pub fn log_x(mut self) -> Self {
+        self.log_x = true;
+        self
+    }
+
+    /// Use a logarithmic y axis. Any non-positive values are not drawn.
+    pub fn log_y(mut self) -> Self {
+        self.log_y = true;
+        self
+    }
+
+    /// Sets the width of the chart in pixels.
+    pub fn width(mut self, width: f64) -> Self {
+        self.width = width;
+        self
+    }
+
+    pub fn series(mut self, series: Series) -> Self {
+        self.series.push(series);
+        self
+    }
+
+    /// Renders the chart as an SVG string, which can be embedded directly into HTML.
+    pub fn render(&self) -> String {
+        let (w, h) = (self.width, self.height);
+        let x_range = (MARGIN_LEFT, w - MARGIN_RIGHT);
+        let y_range = (h - MARGIN_BOTTOM, MARGIN_TOP);
+
+        let all_points = || self.series.iter().flat_map(|s| s.points.iter());
+        let has_bars = self.series.iter().any(|s| s.kind == SeriesKind::Bar);
+
+        // bars are centred on their x value, so leave half a bar's space at either end
+        let x_pad = if has_bars && !self.log_x { 0.5 } else { 0.0 };
+        let x_axis = Axis::new(all_points().map(|p| p.0), self.log_x, x_range, x_pad);
+
+        // bars should always start from zero, so the y axis needs to include zero
+        let y_values = all_points()
+            .map(|p| p.1)
+            .chain((has_bars && !self.log_y).then_some(0.0));
+        let y_axis = Axis::new(y_values, self.log_y, y_range, 0.0);
+
+        let mut svg = String::new();
+
+        // writing to a String never fails, so the results of write! are ignored below
+        let _ = write!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" font-family="Arial, sans-serif" font-size="12">"#
+        );
+
+        self.render_axes(&mut svg, &x_axis, &y_axis);
+
+        for (i, series) in self.series.iter().enumerate() {
+            let colour = PALETTE[i % PALETTE.len()];
+            let points = series.points.iter().filter(|(x, y)| {
+                x.is_finite()
+                    && y.is_finite()
+                    && (!self.log_x || *x > 0.0)
+                    && (!self.log_y || *y > 0.0)
+            });
+
+            match series.kind {
+                SeriesKind::Bar => {
+                    // bars have a width of one x unit (less a small gap), and are translucent
+                    // when several series overlap
+                    let bar_width = if self.log_x {
+                        4.0
+                    } else {
+                        ((x_axis.scale(1.0) - x_axis.scale(0.0)) * 0.8).max(1.0)
+                    };
+                    let opacity = if self.series.len() > 1 { 0.6 } else { 1.0 };
+                    let baseline = if self.log_y { y_axis.min } else { 0.0 };
+
+                    for (x, y) in points {
+                        let (top, bottom) = (y_axis.scale(*y), y_axis.scale(baseline));
+                        let _ = write!(
+                            svg,
+                            r#"<rect x="{:.2}" y="{:.2}" width="{:.2}" height="{:.2}" fill="{colour}" fill-opacity="{opacity}"><title>{}: {}, {}</title></rect>"#,
+                            x_axis.scale(*x) - bar_width / 2.0,
+                            top.min(bottom),
+                            bar_width,
+                            (bottom - top).abs(),
+                            escape(&series.label),
+                            trim_float(*x),
+                            trim_float(*y),
+                        );
+                    }
+                }
+                SeriesKind::Line => {
+                    let path = points
+                        .map(|(x, y)| format!("{:.2},{:.2}", x_axis.scale(*x), y_axis.scale(*y)))
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    let _ = write!(
+                        svg,
+                        r#"<polyline points="{path}" fill="none" stroke="{colour}" stroke-width="2"><title>{}</title></polyline>"#,
+                        escape(&series.label)
+                    );
+                }
+            }
+        }
+
+        self.render_legend(&mut svg);
+
+        svg.push_str("</svg>");
+        svg
+    }
+
+    fn render_axes(&self, svg: &mut String, x_axis: &Axis, y_axis: &Axis) {
+        let h = self.height;
+        let (x0, x1) = x_axis.range;
+        let (y0, y1) = y_axis.range;
+
+        for tick in x_axis.ticks() {
+            let x = x_axis.scale(tick);
+            let _ = write!(
+                svg,
+                r##"<line x1="{x:.2}" y1="{y0}" x2="{x:.2}" y2="{:.2}" stroke="#000"/><text x="{x:.2}" y="{:.2}" text-anchor="middle">{}</text>"##,
+                y0 + 5.0,
+                y0 + 18.0,
+                format_number(tick)
+            );
+        }
+
+        for tick in y_axis.ticks() {
+            let y = y_axis.scale(tick);
+            let _ = write!(
+                svg,
+                r##"<line x1="{x0}" y1="{y:.2}" x2="{x1}" y2="{y:.2}" stroke="#e5e5e5"/><text x="{:.2}" y="{:.2}" text-anchor="end">{}</text>"##,
+                x0 - 6.0,
+                y + 4.0,
+                format_number(tick)
+            );
+        }
+
+        let _ = write!(
+            svg,
+            r##"<line x1="{x0}" y1="{y0}" x2="{x1}" y2="{y0}" stroke="#000"/><line x1="{x0}" y1="{y0}" x2="{x0}" y2="{y1}" stroke="#000"/>"##
+        );
+
+        let _ = write!(
+            svg,
+            r#"<text x="{:.2}" y="{:.2}" text-anchor="middle">{}</text><text transform="translate(15,{:.2}) rotate(-90)" text-anchor="middle">{}</text>"#,
+            (x0 + x1) / 2.0,
+            h - 10.0,
+            escape(&self.x_label),
+            (y0 + y1) / 2.0,
+            escape(&self.y_label),
+        );
+    }
+
+    fn render_legend(&self, svg: &mut String) {
+        // a legend is only useful when there is more than one series
+        if self.series.len() < 2 {
+            return;
+        }
+
+        let mut x = MARGIN_LEFT;
+        for (i, series) in self.series.iter().enumerate() {
+            let colour = PALETTE[i % PALETTE.len()];
+            let _ = write!(
+                svg,
+                r#"<rect x="{x:.2}" y="8" width="12" height="12" fill="{colour}"/><text x="{:.2}" y="18">{}</text>"#,
+                x + 16.0,
+                escape(&series.label)
+            );
+            x += 16.0 + 7.0 * series.label.chars().count() as f64 + 20.0;
+        }
+    }
+}
@@ -1,29 +1,363 @@
-/// Enum representing different preset barcode formats.
-#[derive(clap::ValueEnum, Clone)]
-pub enum PresetBarcodeFormats {
-    /// @BARCODE_UMI format as produced by Flexiplex for 10x3 chemistry
-    BcUmi,
+use crate::detect::{detect_preset, AUTO_PRESET};
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
 
-    /// `_<UMI>` format as produced by `umi-tools extract`.
-    UmiTools,
+/// A named header format, describing how the barcode and UMI are extracted from read headers.
+///
+/// # Fields
+///
+/// * `name` - The name used to select the preset, e.g. `bc-umi`.
+/// * `description` - A short description of where headers of this format come from.
+/// * `regex` - The regex used to extract the identifier, with one capture group per component.
+/// * `components` - The name of each capture group in `regex`, in order, e.g. `barcode` and `umi`.
+/// * `umi_length` - The expected length of the UMI, if it has a fixed length.
+/// * `example` - An example read header, without the leading `@`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Preset {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub regex: String,
+    #[serde(default)]
+    pub components: Vec<String>,
+    #[serde(default)]
+    pub umi_length: Option<usize>,
+    #[serde(default)]
+    pub example: Option<String>,
+}
+
+/// The header format used to create an index.
+///
+/// # Fields
+///
+/// * `regex` - The regex used to extract the identifier from each read header.
+/// * `preset` - The name of the preset that the regex was taken from, if any.
+/// * `components` - The names of the components of the identifier, if known.
+#[derive(Clone, Debug)]
+pub struct BarcodeFormat {
+    pub regex: String,
+    pub preset: Option<String>,
+    pub components: Vec<String>,
+}
 
-    /// bcl2fastq format, which has `:<UMI>` at the end of the read ID.
-    Illumina,
+/// The layout of a presets file, which contains a list of `[[preset]]` tables.
+#[derive(Deserialize)]
+struct PresetsFile {
+    #[serde(default)]
+    preset: Vec<Preset>,
 }
 
-/// Returns a regular expression string for barcode presets.
+impl Preset {
+    fn builtin(
+        name: &str,
+        description: &str,
+        regex: &str,
+        components: &[&str],
+        umi_length: Option<usize>,
+        example: &str,
+    ) -> Self {
+        Preset {
+            name: name.to_string(),
+            description: description.to_string(),
+            regex: regex.to_string(),
+            components: components.iter().map(|c| c.to_string()).collect(),
+            umi_length,
+            example: Some(example.to_string()),
+        }
+    }
+
+    /// Extracts the components of a header using the preset regex, returning (name, value) pairs,
+    /// or `None` if the header does not match.
+    pub fn extract(&self, re: &Regex, header: &str) -> Option<Vec<(String, String)>> {
+        let captures = re.captures(header)?;
+
+        let values = captures.iter().skip(1).flatten().map(|m| m.as_str());
+        let names = self
+            .components
+            .iter()
+            .cloned()
+            .chain((self.components.len()..).map(|i| format!("component {}", i + 1)));
+
+        Some(names.zip(values.map(String::from)).collect())
+    }
+
+    /// Checks that the preset is consistent: the regex must compile with one capture group per
+    /// component, and the example header (if given) must match with a UMI of the expected length.
+    fn validate(&self) -> Result<()> {
+        let re = Regex::new(&self.regex)
+            .with_context(|| format!("Preset `{}` has an invalid regex", self.name))?;
+
+        let groups = re.captures_len() - 1;
+        if !self.components.is_empty() && self.components.len() != groups {
+            bail!(
+                "Preset `{}` names {} components, but its regex has {groups} capture groups",
+                self.name,
+                self.components.len()
+            );
+        }
+
+        let Some(example) = &self.example else {
+            return Ok(());
+        };
+        let Some(components) = self.extract(&re, example) else {
+            bail!(
+                "The example header of preset `{}` does not match its regex",
+                self.name
+            );
+        };
+
+        if let (Some(umi_length), Some((_, umi))) = (self.umi_length, components.last()) {
+            if umi.len() != umi_length {
+                bail!(
+                    "The example header of preset `{}` has a UMI of length {}, but {umi_length} was expected",
+                    self.name,
+                    umi.len()
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the presets which are built into nailpolish.
+pub fn builtin_presets() -> Vec<Preset> {
+    vec![
+        Preset::builtin(
+            "bc-umi",
+            "BARCODE_UMI format as produced by Flexiplex for 10x 3' chemistry",
+            r"^([ATCG]{16})_([ATCG]{12})",
+            &["barcode", "umi"],
+            Some(12),
+            "AAACCCAAGAAACACT_CCTTAGGCTGAA#3f1a9c2e-7b4d-4e8a-9c1f-2d6b8e0a5c47_+1of1",
+        ),
+        Preset::builtin(
+            "10x-5p",
+            "BARCODE_UMI format as produced by Flexiplex for 10x 5' chemistry",
+            r"^([ATCG]{16})_([ATCG]{12})",
+            &["barcode", "umi"],
+            Some(12),
+            "AAACCTGAGAAGGCCT_CTAGTTCAGGTA#9e2b4d71-0c3a-4f5e-8b6d-1a7c9e3f2b08_+1of1",
+        ),
+        Preset::builtin(
+            "bd-rhapsody",
+            "BARCODE_UMI format for BD Rhapsody, with the three 9bp cell label sections joined",
+            r"^([ATCG]{27})_([ATCG]{8})",
+            &["barcode", "umi"],
+            Some(8),
+            "GTCGCTATAACATTCGGTGTCAGATAC_TTGCAGTA#c4e8a1b7-5d2f-4a9c-8e3b-6f0d2a7c1e95",
+        ),
+        Preset::builtin(
+            "split-seq",
+            "BARCODE_UMI format for SPLiT-seq, with each of the three 8bp round barcodes as a component",
+            r"^([ATCG]{8})([ATCG]{8})([ATCG]{8})_([ATCG]{10})",
+            &["round1", "round2", "round3", "umi"],
+            Some(10),
+            "CATTCCTAAACATCGGATGAATCC_GTTACAGCAT#7a3e9c1d-2b8f-4d6a-9e5c-0f1b7d3a8c26",
+        ),
+        Preset::builtin(
+            "umi-tools",
+            "`_<UMI>` format as produced by `umi-tools extract`",
+            r"_([ATCG]+)$",
+            &["umi"],
+            None,
+            "SRR5665260.1.1_TTCGCTCACGTT",
+        ),
+        Preset::builtin(
+            "illumina",
+            "bcl2fastq format, which has `:<UMI>` at the end of the read ID",
+            r":([ATCG]+)$",
+            &["umi"],
+            None,
+            "NB551608:199:HGKWNBGXB:1:11101:10433:1048:ATCACGTT",
+        ),
+        Preset::builtin(
+            "cb-ub-tags",
+            "corrected CB:Z: and UB:Z: tags, as written by `samtools fastq -T CB,UB`",
+            r"CB:Z:([ACGTN]+)(?:-\d+)?\s.*?UB:Z:([ACGTN]+)",
+            &["barcode", "umi"],
+            None,
+            "A00228:279:HFWFVDMXX:1:1101:1000:1000\tCB:Z:AAACCCAAGAAACACT-1\tUB:Z:CCTTAGGCTGAA",
+        ),
+        Preset::builtin(
+            "cr-ur-tags",
+            "uncorrected CR:Z: and UR:Z: tags, as written by `samtools fastq -T CR,UR`",
+            r"CR:Z:([ACGTN]+)\s.*?UR:Z:([ACGTN]+)",
+            &["barcode", "umi"],
+            None,
+            "A00228:279:HFWFVDMXX:1:1101:1000:1000\tCR:Z:AAACCCAAGAAACACT\tUR:Z:CCTTAGGCTGAA",
+        ),
+        Preset::builtin(
+            "bc-umi-fields",
+            "BC= and UMI= (or UB=, RX=) fields in the read description",
+            r"\bBC=([ACGTN]+).*?\b(?:UMI|UB|RX)=([ACGTN]+)",
+            &["barcode", "umi"],
+            None,
+            "read_1 BC=AAACCCAAGAAACACT UMI=CCTTAGGCTGAA",
+        ),
+    ]
+}
+
+/// Returns every preset: the built-in presets, followed by those in the user presets file (if
+/// given). A user preset with the same name as a built-in preset replaces it.
 ///
 /// # Arguments
 ///
-/// * `preset` - A reference to a `PresetBarcodeFormats` enum variant.
+/// * `presets_file` - An optional TOML or YAML file of user presets.
 ///
 /// # Returns
 ///
-/// A `String` containing the regular expression for the specified barcode format.
-pub fn get_barcode_regex(preset: &PresetBarcodeFormats) -> String {
-    match preset {
-        PresetBarcodeFormats::BcUmi => String::from(r"^([ATCG]{16})_([ATCG]{12})"),
-        PresetBarcodeFormats::UmiTools => String::from(r"_([ATCG]+)$"),
-        PresetBarcodeFormats::Illumina => String::from(r":([ATCG]+)$"),
+/// * `Result<Vec<Preset>>` - The available presets, or an error if the presets file is invalid.
+pub fn load_presets(presets_file: &Option<String>) -> Result<Vec<Preset>> {
+    let mut presets = builtin_presets();
+
+    let Some(path) = presets_file else {
+        return Ok(presets);
+    };
+
+    let file: PresetsFile = crate::config::read_file(path)?;
+    for preset in file.preset {
+        preset
+            .validate()
+            .with_context(|| format!("Invalid preset in {path}"))?;
+
+        match presets.iter_mut().find(|p| p.name == preset.name) {
+            Some(existing) => {
+                info!(
+                    "Preset `{}` from {path} replaces the built-in preset",
+                    preset.name
+                );
+                *existing = preset;
+            }
+            None => presets.push(preset),
+        }
     }
+
+    Ok(presets)
+}
+
+/// Finds a preset by name.
+///
+/// # Arguments
+///
+/// * `name` - The name of the preset.
+/// * `presets` - The available presets, from `load_presets`.
+///
+/// # Returns
+///
+/// * `Result<&Preset>` - The preset, or an error listing the available presets.
+pub fn find_preset<'a>(name: &str, presets: &'a [Preset]) -> Result<&'a Preset> {
+    presets.iter().find(|p| p.name == name).with_context(|| {
+        let names = presets.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+        format!(
+            "Unknown preset `{name}`. The available presets are: {}",
+            names.join(", ")
+        )
+    })
+}
+
+/// Returns the barcode format to use, which is `barcode_regex` if given and otherwise the regex of
+/// the preset. If the preset is `auto`, the preset is detected from the headers of the input.
+///
+/// # Arguments
+///
+/// * `barcode_regex` - A custom barcode regex, which overrides the preset.
+/// * `preset` - The name of the preset, or `auto`.
+/// * `presets_file` - An optional TOML or YAML file of user presets.
+/// * `input` - The input .fastq file, which is sampled if the preset is `auto`.
+/// * `detect_reads` - The number of reads to sample if the preset is `auto`.
+///
+/// # Returns
+///
+/// * `Result<BarcodeFormat>` - The barcode format, or an error if the preset does not exist or
+///   could not be detected.
+pub fn resolve_barcode_format(
+    barcode_regex: &Option<String>,
+    preset: &str,
+    presets_file: &Option<String>,
+    input: &str,
+    detect_reads: usize,
+) -> Result<BarcodeFormat> {
+    if let Some(v) = barcode_regex {
+        info!("Using specified barcode format: {v}");
+        return Ok(BarcodeFormat {
+            regex: v.clone(),
+            preset: None,
+            components: vec![],
+        });
+    }
+
+    let presets = load_presets(presets_file)?;
+    let preset = match preset {
+        AUTO_PRESET => detect_preset(input, &presets, detect_reads)?,
+        name => find_preset(name, &presets)?.clone(),
+    };
+
+    info!("Using preset barcode format {}", preset.regex);
+    Ok(BarcodeFormat {
+        regex: preset.regex,
+        preset: Some(preset.name),
+        components: preset.components,
+    })
+}
+
+/// Writes each preset with its regex, components and an example header, along with the
+/// components extracted from the example.
+///
+/// # Arguments
+///
+/// * `presets_file` - An optional TOML or YAML file of user presets.
+/// * `writer` - The writer to list the presets to.
+///
+/// # Returns
+///
+/// * `Result<()>` - Returns an `Ok(())` if successful, or an `anyhow::Error` if an error occurs.
+pub fn list_presets(presets_file: &Option<String>, writer: &mut impl Write) -> Result<()> {
+    let builtin = builtin_presets();
+
+    for (i, preset) in load_presets(presets_file)?.iter().enumerate() {
+        if i > 0 {
+            writeln!(writer)?;
+        }
+
+        let is_builtin = builtin
+            .iter()
+            .any(|b| b.name == preset.name && b.regex == preset.regex);
+        let source = if is_builtin { "built-in" } else { "user" };
+
+        writeln!(writer, "{} ({source})", preset.name)?;
+        if !preset.description.is_empty() {
+            writeln!(writer, "  {}", preset.description)?;
+        }
+        writeln!(writer, "  {:<12}{}", "regex", preset.regex)?;
+
+        if !preset.components.is_empty() {
+            writeln!(
+                writer,
+                "  {:<12}{}",
+                "components",
+                preset.components.join(", ")
+            )?;
+        }
+        if let Some(umi_length) = preset.umi_length {
+            writeln!(writer, "  {:<12}{umi_length}", "UMI length")?;
+        }
+
+        if let Some(example) = &preset.example {
+            writeln!(writer, "  {:<12}@{example}", "example")?;
+
+            let re = Regex::new(&preset.regex)?;
+            if let Some(components) = preset.extract(&re, example) {
+                let extracted = components
+                    .iter()
+                    .map(|(name, value)| format!("{name}={value}"))
+                    .collect::<Vec<_>>();
+                writeln!(writer, "  {:<12}{}", "", extracted.join(" "))?;
+            }
+        }
+    }
+
+    Ok(())
 }
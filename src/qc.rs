@@ -0,0 +1,403 @@
+use crate::histogram::Histogram;
+use crate::io::Record;
+use crate::plot::{Plot, Series};
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+// encode the template HTML file at compile time as a string literal
+const TEMPLATE_HTML: &str = include_str!("qc_template.html");
+
+/// The width of each read length bin, in bases
+const LENGTH_BIN_WIDTH: f64 = 100.0;
+
+/// The type of a record in a consensus .fastq, as given by its `UT` tag.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CalledType {
+    /// `CON_{size}`: the consensus of a UMI group of the given size
+    Consensus(usize),
+    /// `SIN`: the only read of a UMI group
+    Single,
+    /// `ORIG_{idx}_OF_{size}`: an original read of a UMI group
+    Original(usize),
+    /// `IGN`: a read which was ignored
+    Ignored,
+}
+
+/// The metadata which `call` adds to the header of each record.
+pub struct CalledHeader {
+    pub read_type: CalledType,
+    pub group: usize,
+    /// the sample of the group, which is empty if the index does not record samples
+    pub sample: String,
+    pub avg_qual: Option<f64>,
+}
+
+impl CalledHeader {
+    /// Parses the `UT`, `UG`, `SM` and `QL` tags from a record header, which are written by
+    /// `Record::add_metadata`.
+    pub fn parse(id: &str) -> Result<Self> {
+        let mut read_type = None;
+        let mut group = None;
+        let mut sample = String::new();
+        let mut avg_qual = None;
+
+        for tag in id.split_ascii_whitespace().skip(1) {
+            if let Some(v) = tag.strip_prefix("UT:Z:") {
+                read_type =
+                    Some(parse_read_type(v).with_context(|| {
+                        format!("Could not parse read type `{v}` of record {id}")
+                    })?);
+            } else if let Some(v) = tag.strip_prefix("UG:i:") {
+                group = Some(
+                    v.parse()
+                        .with_context(|| format!("Could not parse UMI group of record {id}"))?,
+                );
+            } else if let Some(v) = tag.strip_prefix("SM:Z:") {
+                sample = v.to_string();
+            } else if let Some(v) = tag.strip_prefix("QL:f:") {
+                avg_qual = Some(
+                    v.parse()
+                        .with_context(|| format!("Could not parse quality of record {id}"))?,
+                );
+            }
+        }
+
+        let (Some(read_type), Some(group)) = (read_type, group) else {
+            bail!(
+                "Record {id} does not have UT and UG tags; was it produced by `nailpolish call`?"
+            );
+        };
+
+        Ok(CalledHeader {
+            read_type,
+            group,
+            sample,
+            avg_qual,
+        })
+    }
+}
+
+/// Parses the value of a `UT` tag.
+fn parse_read_type(v: &str) -> Result<CalledType> {
+    let read_type = if let Some(size) = v.strip_prefix("CON_") {
+        CalledType::Consensus(size.parse()?)
+    } else if let Some(rest) = v.strip_prefix("ORIG_") {
+        let Some((_, size)) = rest.split_once("_OF_") else {
+            bail!("Expected ORIG_{{idx}}_OF_{{size}}");
+        };
+        CalledType::Original(size.parse()?)
+    } else if v == "SIN" {
+        CalledType::Single
+    } else if v == "IGN" {
+        CalledType::Ignored
+    } else {
+        bail!("Unknown read type");
+    };
+
+    Ok(read_type)
+}
+
+/// Statistics about the consensus reads of UMI groups of a single size.
+///
+/// # Fields
+///
+/// * `group_size` - The number of reads in each UMI group.
+/// * `groups` - The number of consensus reads called from groups of this size.
+/// * `mean_consensus_length` - The mean length of the consensus reads.
+/// * `mean_member_length` - The mean length of the original reads in these groups, if the
+///   original reads were reported.
+/// * `mean_consensus_quality` - The mean average quality of the consensus reads.
+/// * `mean_member_quality` - The mean average quality of the original reads in these groups.
+#[derive(Serialize, Debug, Clone)]
+pub struct GroupSizeStatistics {
+    pub group_size: usize,
+    pub groups: usize,
+    pub mean_consensus_length: f64,
+    pub mean_member_length: Option<f64>,
+    pub mean_consensus_quality: f64,
+    pub mean_member_quality: f64,
+}
+
+/// Statistics about the output of `call`.
+///
+/// # Fields
+///
+/// * `consensus_reads` - The number of consensus (`CON`) records.
+/// * `single_reads` - The number of singleton (`SIN`) records.
+/// * `original_reads` - The number of original (`ORIG`) records, which are only present if
+///   `call` was run with `--report-original-reads`.
+/// * `ignored_reads` - The number of ignored (`IGN`) records.
+/// * `reads_in` - The number of input reads represented by the output, i.e. the total size of
+///   each UMI group.
+/// * `molecules_out` - The number of molecules in the output, i.e. consensus and singleton reads.
+/// * `reads_per_molecule` - The mean number of input reads per output molecule.
+/// * `mean_length_difference` - The mean difference between the length of each consensus read and
+///   the mean length of its original reads, if the original reads were reported.
+/// * `mean_quality_uplift` - The mean difference between the average quality of each consensus
+///   read and the average quality of its original reads.
+/// * `consensus_length` - The length distribution of consensus reads.
+/// * `member_length` - The length distribution of original reads.
+/// * `single_length` - The length distribution of singleton reads.
+/// * `by_group_size` - Consensus statistics for each UMI group size.
+#[derive(Serialize, Debug, Clone)]
+pub struct CallStatistics {
+    pub consensus_reads: usize,
+    pub single_reads: usize,
+    pub original_reads: usize,
+    pub ignored_reads: usize,
+    pub reads_in: usize,
+    pub molecules_out: usize,
+    pub reads_per_molecule: f64,
+    pub mean_length_difference: Option<f64>,
+    pub mean_quality_uplift: f64,
+    pub consensus_length: Histogram,
+    pub member_length: Histogram,
+    pub single_length: Histogram,
+    pub by_group_size: Vec<GroupSizeStatistics>,
+}
+
+/// Running totals for one UMI group size.
+#[derive(Default)]
+struct GroupSizeTotals {
+    groups: usize,
+    consensus_length: f64,
+    consensus_quality: f64,
+    member_quality: f64,
+    /// the total member length and number of members, over groups whose originals were reported
+    member_length: f64,
+    members: usize,
+}
+
+/// Computes statistics from the output of `call`. The records of each group are expected to be
+/// in the order that `call` writes them, i.e. any original reads are immediately followed by the
+/// consensus read of their group.
+///
+/// # Arguments
+///
+/// * `input` - The path to a .fastq file produced by `nailpolish call`, which may be compressed.
+pub fn call_statistics(input: &str) -> Result<CallStatistics> {
+    let mut reader = needletail::parse_fastx_file(input)
+        .with_context(|| format!("Could not open consensus file at {input}"))?;
+
+    let mut stats = CallStatistics {
+        consensus_reads: 0,
+        single_reads: 0,
+        original_reads: 0,
+        ignored_reads: 0,
+        reads_in: 0,
+        molecules_out: 0,
+        reads_per_molecule: 0.0,
+        mean_length_difference: None,
+        mean_quality_uplift: 0.0,
+        consensus_length: Histogram::new(LENGTH_BIN_WIDTH),
+        member_length: Histogram::new(LENGTH_BIN_WIDTH),
+        single_length: Histogram::new(LENGTH_BIN_WIDTH),
+        by_group_size: Vec::new(),
+    };
+
+    let mut totals: BTreeMap<usize, GroupSizeTotals> = BTreeMap::new();
+
+    // the (group, total length, count) of the original reads seen since the last consensus read
+    let mut members: Option<(usize, usize, usize)> = None;
+
+    // the (total, count) of the per-group differences between consensus and original reads
+    let mut length_difference = (0.0, 0usize);
+    let mut quality_uplift = (0.0, 0usize);
+
+    while let Some(rec) = reader.next() {
+        let rec = Record::try_from(rec.context("Could not parse record")?)
+            .context("Record is not valid UTF-8")?;
+        let header = CalledHeader::parse(&rec.id)?;
+        let length = rec.len();
+
+        match header.read_type {
+            CalledType::Consensus(size) => {
+                stats.consensus_reads += 1;
+                stats.reads_in += size;
+                stats.consensus_length.add(length as f64);
+
+                let quality = mean_quality(&rec);
+                let member_quality = header.avg_qual.unwrap_or(quality);
+                quality_uplift.0 += quality - member_quality;
+                quality_uplift.1 += 1;
+
+                let group = totals.entry(size).or_default();
+                group.groups += 1;
+                group.consensus_length += length as f64;
+                group.consensus_quality += quality;
+                group.member_quality += member_quality;
+
+                if let Some((_, total, count)) = members.take().filter(|m| m.0 == header.group) {
+                    let mean = total as f64 / count as f64;
+                    length_difference.0 += length as f64 - mean;
+                    length_difference.1 += 1;
+                    group.member_length += total as f64;
+                    group.members += count;
+                }
+            }
+            CalledType::Single => {
+                stats.single_reads += 1;
+                stats.reads_in += 1;
+                stats.single_length.add(length as f64);
+            }
+            CalledType::Original(_) => {
+                stats.original_reads += 1;
+                stats.member_length.add(length as f64);
+
+                members = match members {
+                    Some((group, total, count)) if group == header.group => {
+                        Some((group, total + length, count + 1))
+                    }
+                    _ => Some((header.group, length, 1)),
+                };
+            }
+            CalledType::Ignored => {
+                stats.ignored_reads += 1;
+            }
+        }
+    }
+
+    stats.molecules_out = stats.consensus_reads + stats.single_reads;
+    if stats.molecules_out > 0 {
+        stats.reads_per_molecule = stats.reads_in as f64 / stats.molecules_out as f64;
+    }
+    if quality_uplift.1 > 0 {
+        stats.mean_quality_uplift = quality_uplift.0 / quality_uplift.1 as f64;
+    }
+    if length_difference.1 > 0 {
+        stats.mean_length_difference = Some(length_difference.0 / length_difference.1 as f64);
+    }
+
+    stats.by_group_size = totals
+        .into_iter()
+        .map(|(group_size, t)| {
+            let n = t.groups as f64;
+            GroupSizeStatistics {
+                group_size,
+                groups: t.groups,
+                mean_consensus_length: t.consensus_length / n,
+                mean_member_length: (t.members > 0).then(|| t.member_length / t.members as f64),
+                mean_consensus_quality: t.consensus_quality / n,
+                mean_member_quality: t.member_quality / n,
+            }
+        })
+        .collect();
+
+    Ok(stats)
+}
+
+/// Returns the average PHRED quality of a record, or 0 if the record is empty.
+fn mean_quality(rec: &Record) -> f64 {
+    if rec.len() == 0 {
+        0.0
+    } else {
+        rec.phred_quality_avg()
+    }
+}
+
+/// Summarizes the output of `call`, writing an HTML report and a JSON file.
+///
+/// # Arguments
+///
+/// * `input` - The path to a .fastq file produced by `nailpolish call`.
+/// * `output` - The path to write the HTML report to.
+/// * `json_output` - The path to write the statistics to as JSON.
+///
+/// # Returns
+///
+/// * `Result<()>` - Returns an `Ok(())` if successful, or an `anyhow::Error` if an error occurs.
+pub fn summarize_call(input: &str, output: &str, json_output: &str) -> Result<()> {
+    info!("Summarising consensus output at {input}");
+    let stats = call_statistics(input)?;
+
+    let file = std::fs::File::create(json_output)
+        .with_context(|| format!("Could not create JSON output at {json_output}"))?;
+    serde_json::to_writer_pretty(file, &stats)?;
+    info!("Wrote JSON summary to {json_output}");
+
+    write_html(input, &stats, output)?;
+    info!("Wrote HTML summary to {output}");
+
+    Ok(())
+}
+
+/// Writes a self-contained HTML report of the output of `call`.
+fn write_html(input: &str, stats: &CallStatistics, output: &str) -> Result<()> {
+    let mut data = json!({
+        "nailpolish_version": crate::cli::VERSION,
+        "input": input,
+        "consensus_reads": stats.consensus_reads,
+        "single_reads": stats.single_reads,
+        "original_reads": stats.original_reads,
+        "ignored_reads": stats.ignored_reads,
+        "reads_in": stats.reads_in,
+        "molecules_out": stats.molecules_out,
+        "reads_per_molecule": format!("{:.3}", stats.reads_per_molecule),
+        "mean_quality_uplift": format!("{:+.2}", stats.mean_quality_uplift),
+        "mean_length_difference": stats
+            .mean_length_difference
+            .map(|v| format!("{v:+.1}")),
+        "mean_consensus_length": format!("{:.1}", stats.consensus_length.mean),
+        "mean_single_length": format!("{:.1}", stats.single_length.mean),
+    });
+
+    data["group_sizes"] = json!(stats
+        .by_group_size
+        .iter()
+        .map(|g| json!({
+            "group_size": g.group_size,
+            "groups": g.groups,
+            "consensus_length": format!("{:.1}", g.mean_consensus_length),
+            "member_length": g.mean_member_length.map_or("-".to_string(), |v| format!("{v:.1}")),
+            "consensus_quality": format!("{:.2}", g.mean_consensus_quality),
+            "member_quality": format!("{:.2}", g.mean_member_quality),
+            "uplift": format!("{:+.2}", g.mean_consensus_quality - g.mean_member_quality),
+        }))
+        .collect::<Vec<_>>());
+
+    // charts are rendered as inline SVG, so that the report can be viewed offline
+    let mut length_chart = Plot::new("Read length", "Proportion of reads")
+        .series(Series::line(
+            "Consensus",
+            stats.consensus_length.proportions(),
+        ))
+        .series(Series::line("Singleton", stats.single_length.proportions()));
+    if stats.member_length.count > 0 {
+        length_chart =
+            length_chart.series(Series::line("Original", stats.member_length.proportions()));
+    }
+    data["length_chart"] = json!(length_chart.render());
+
+    let by_size = |f: &dyn Fn(&GroupSizeStatistics) -> f64| {
+        stats
+            .by_group_size
+            .iter()
+            .map(|g| (g.group_size as f64, f(g)))
+            .collect::<Vec<_>>()
+    };
+    data["quality_chart"] = json!(Plot::new("UMI group size", "Average read quality")
+        .series(Series::line(
+            "Consensus",
+            by_size(&|g| g.mean_consensus_quality)
+        ))
+        .series(Series::line(
+            "Original",
+            by_size(&|g| g.mean_member_quality)
+        ))
+        .render());
+    data["molecule_chart"] = json!(Plot::new("UMI group size", "Consensus reads")
+        .series(Series::bar(
+            "Consensus reads",
+            by_size(&|g| g.groups as f64)
+        ))
+        .render());
+
+    let file = std::fs::File::create(output)
+        .with_context(|| format!("Could not create HTML output at {output}"))?;
+    let reg = handlebars::Handlebars::new();
+    reg.render_template_to_write(TEMPLATE_HTML, &data, file)?;
+
+    Ok(())
+}
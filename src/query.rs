@@ -0,0 +1,279 @@
+use crate::duplicates::{RecordIdentifier, RecordPosition};
+use crate::filter::FilterReason;
+use crate::index::{IndexReader, IndexRecord, IndexWriter};
+use crate::io::{FastqWriter, RandomReader, Record};
+use anyhow::{bail, ensure, Context, Result};
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+
+/// The number of reads which are read from the input in parallel at a time.
+const FETCH_BATCH_READS: usize = 4096;
+
+/// Chooses reads from an index by their identifier. A read is selected if, for each kind of
+/// value which is given, its identifier matches one of the values.
+///
+/// # Fields
+///
+/// * `samples` - The samples to select.
+/// * `barcodes` - The barcodes to select, i.e. the cell components of the identifier joined by
+///   underscores.
+/// * `umis` - The UMIs to select.
+/// * `ids` - The full identifiers to select, e.g. `BARCODE_UMI`.
+/// * `fraction` - If given, the fraction of molecules to select at random. A molecule is either
+///   selected with all of its reads, or not at all.
+/// * `seed` - The random seed used to select molecules.
+/// * `include_ignored` - Whether to select reads which were filtered out when the index was
+///   created. Reads which did not match the barcode format are never selected.
+#[derive(Default)]
+pub struct Selection {
+    pub samples: HashSet<String>,
+    pub barcodes: HashSet<String>,
+    pub umis: HashSet<String>,
+    pub ids: HashSet<String>,
+    pub fraction: Option<f64>,
+    pub seed: u64,
+    pub include_ignored: bool,
+}
+
+impl Selection {
+    /// Whether a record of the index is selected.
+    fn matches(&self, record: &IndexRecord) -> bool {
+        if record.reason == Some(FilterReason::Unmatched)
+            || (record.ignored && !self.include_ignored)
+        {
+            return false;
+        }
+
+        let id = RecordIdentifier::from_string(&record.id);
+        let selected =
+            |values: &HashSet<String>, value: &str| values.is_empty() || values.contains(value);
+
+        selected(&self.samples, &record.sample)
+            && selected(&self.barcodes, &id.barcode())
+            && selected(&self.umis, &id.umi())
+            && selected(&self.ids, &record.id)
+            && self
+                .fraction
+                .is_none_or(|fraction| molecule_fraction(self.seed, record) < fraction)
+    }
+}
+
+/// Reads a file with one value, such as a barcode or identifier, per line. Blank lines are skipped.
+pub fn read_values(path: &str) -> Result<Vec<String>> {
+    let file = File::open(path).with_context(|| format!("Unable to open file {path}"))?;
+
+    let mut values = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        let value = line.trim();
+        if !value.is_empty() {
+            values.push(value.to_string());
+        }
+    }
+    Ok(values)
+}
+
+/// Maps the molecule of a record (its sample and identifier) to a number in `[0, 1)`, which is the
+/// same for every read of the molecule and for every run with the same seed. This uses the FNV-1a
+/// hash, which unlike the standard library hasher is stable between versions of Rust.
+fn molecule_fraction(seed: u64, record: &IndexRecord) -> f64 {
+    const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+
+    let bytes = seed
+        .to_le_bytes()
+        .into_iter()
+        .chain(record.sample.bytes())
+        .chain(std::iter::once(0))
+        .chain(record.id.bytes());
+    let hash = bytes.fold(FNV_OFFSET, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    });
+
+    // the top 53 bits are used, which is the precision of an f64
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Reads a batch of records from the input in parallel, and passes each one in order to `write`.
+fn fetch_batch(
+    reader: &RandomReader,
+    batch: &[IndexRecord],
+    write: &mut impl FnMut(&IndexRecord, Record) -> Result<()>,
+) -> Result<()> {
+    let fetched = batch
+        .par_iter()
+        .map(|record| {
+            reader.get_record(&RecordPosition {
+                pos: record.pos,
+                length: record.rec_len,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    for (record, rec) in batch.iter().zip(fetched) {
+        write(record, rec)?;
+    }
+    Ok(())
+}
+
+/// Reads the records of an index which are selected from the input, and passes each one to
+/// `write` in the order of the index. The index is streamed, so only one batch of records is held
+/// in memory at a time.
+///
+/// # Returns
+///
+/// * `Result<usize>` - The number of records which were selected.
+fn fetch_selected(
+    index: &mut IndexReader,
+    input: &str,
+    selection: &Selection,
+    mut write: impl FnMut(&IndexRecord, Record) -> Result<()>,
+) -> Result<usize> {
+    let reader = RandomReader::open(input, false)?;
+
+    let mut batch = Vec::with_capacity(FETCH_BATCH_READS);
+    let mut count = 0;
+    for record in index.index_records()? {
+        let record: IndexRecord = record?;
+        if !selection.matches(&record) {
+            continue;
+        }
+
+        batch.push(record);
+        if batch.len() == FETCH_BATCH_READS {
+            fetch_batch(&reader, &batch, &mut write)?;
+            count += batch.len();
+            batch.clear();
+        }
+    }
+    fetch_batch(&reader, &batch, &mut write)?;
+    count += batch.len();
+
+    info!("Found {count} matching reads");
+    Ok(count)
+}
+
+/// Writes the reads of an index which are selected, reading each one directly from its position
+/// in the input.
+///
+/// # Arguments
+///
+/// * `index` - The index of the input.
+/// * `input` - The .fastq file which the index was created from.
+/// * `selection` - The reads to write.
+/// * `writer` - The writer to write the selected reads to, as a .fastq.
+///
+/// # Returns
+///
+/// * `Result<usize>` - The number of reads written.
+pub fn query(
+    index: &mut IndexReader,
+    input: &str,
+    selection: &Selection,
+    writer: &mut FastqWriter,
+) -> Result<usize> {
+    fetch_selected(index, input, selection, |_, rec| writer.write(&rec))
+}
+
+/// Writes the reads of an index which are selected to a new .fastq, along with an index of the new
+/// .fastq, so that the subset can be used in place of the original input.
+///
+/// # Arguments
+///
+/// * `index` - The index of the input.
+/// * `input` - The .fastq file which the index was created from.
+/// * `selection` - The reads to write.
+/// * `output` - The path to write the new .fastq to. This is not compressed, as the index refers
+///   to positions within it.
+/// * `index_output` - The path to write the new index to.
+///
+/// # Returns
+///
+/// * `Result<usize>` - The number of reads written.
+pub fn subset(
+    index: &mut IndexReader,
+    input: &str,
+    selection: &Selection,
+    output: &str,
+    index_output: &str,
+) -> Result<usize> {
+    let now = std::time::Instant::now();
+
+    if let Some(fraction) = selection.fraction {
+        ensure!(
+            0.0 < fraction && fraction <= 1.0,
+            "The fraction of molecules must be in (0, 1], but is {fraction}"
+        );
+    }
+
+    // the output is only created once a read is selected, so that nothing is written if the
+    // subset is empty
+    let mut writer: Option<BufWriter<File>> = None;
+    let mut wtr = IndexWriter::new(index_output)?;
+
+    // the records are written one after another, so their positions are recomputed
+    let mut pos = 0;
+    let mut bytes = Vec::new();
+    let mut total_quality = 0u64;
+    let mut total_len = 0;
+    let mut read_count = 0;
+    let mut filtered = 0;
+
+    fetch_selected(index, input, selection, |record, rec| {
+        let writer = match &mut writer {
+            Some(writer) => writer,
+            None => {
+                let file = File::create(output)
+                    .with_context(|| format!("Unable to create file {output}"))?;
+                writer.insert(BufWriter::new(file))
+            }
+        };
+
+        bytes.clear();
+        rec.write_fastq(&mut bytes)?;
+        bytes.push(b'\n');
+        writer.write_all(&bytes)?;
+
+        wtr.write_index_record(&IndexRecord {
+            pos,
+            rec_len: bytes.len(),
+            ..record.clone()
+        })?;
+        pos += bytes.len();
+
+        read_count += 1;
+        filtered += record.ignored as usize;
+        total_quality += rec.phred_quality_total() as u64;
+        total_len += rec.len();
+        Ok(())
+    })?;
+
+    // an empty subset has no average quality or length, so would not be a valid index
+    let Some(mut writer) = writer else {
+        bail!("No reads match the selection, so no subset was written");
+    };
+    writer.flush()?;
+
+    // the metadata of the new index describes the subset, with the parameters of the original.
+    // the index is dated after the subset is written, so that `call` does not warn that the
+    // subset was modified after it was indexed
+    wtr.metadata = crate::file::ReadFileMetadata {
+        nailpolish_version: wtr.metadata.nailpolish_version.clone(),
+        file_path: std::fs::canonicalize(output)?.display().to_string(),
+        index_date: format!("{:?}", chrono::offset::Local::now()),
+        elapsed: now.elapsed().as_secs_f64(),
+        gb: pos as f64 / (1024u32.pow(3) as f64),
+        matched_read_count: read_count,
+        unmatched_read_count: 0,
+        read_count,
+        avg_qual: total_quality as f64 / read_count as f64,
+        avg_len: total_len as f64 / read_count as f64,
+        filtered_reads: filtered,
+        ..index.metadata.clone()
+    };
+    wtr.finish_write()?;
+
+    Ok(read_count)
+}
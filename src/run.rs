@@ -0,0 +1,151 @@
+use crate::compress::Compression;
+use crate::duplicates::GroupingOpts;
+use crate::filter::FilterOpts;
+use crate::index::{IndexDuplicates, IndexWriter};
+use crate::io::UMIGroupCollection;
+use crate::preset::BarcodeFormat;
+use crate::summary::SummaryFormat;
+use crate::{call, index, summary};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::Path;
+
+/// Options for each stage of the pipeline run by `run`.
+///
+/// # Fields
+///
+/// * `output_dir` - The directory that every output file is written to.
+/// * `barcode_format` - The barcode format used to create the index.
+/// * `skip_unmatched` - Whether to skip reads which do not match the barcode regex or clusters.
+/// * `clusters` - An optional file of pre-clustered reads.
+/// * `sample_column` - Whether the cluster file has a sample column after the read ID.
+/// * `filter_opts` - The length and quality filters used to create the index.
+/// * `format` - The format of the summary report.
+/// * `threads` - The number of threads used for consensus calling and compression.
+/// * `duplicates_only` - Whether to only output consensus reads of duplicate groups.
+/// * `report_original_reads` - Whether to output the original reads of each duplicate group.
+/// * `compress` - The compression format of the consensus output.
+/// * `grouping` - The identifier components which define a cell and a molecule.
+/// * `mmap` - Whether to memory map the input when calling consensus reads.
+pub struct RunOpts {
+    pub output_dir: String,
+    pub barcode_format: BarcodeFormat,
+    pub skip_unmatched: bool,
+    pub clusters: Option<String>,
+    pub sample_column: bool,
+    pub filter_opts: FilterOpts,
+    pub format: SummaryFormat,
+    pub threads: usize,
+    pub duplicates_only: bool,
+    pub report_original_reads: bool,
+    pub compress: Compression,
+    pub grouping: GroupingOpts,
+    pub mmap: bool,
+}
+
+/// A record of the files produced by `run`, which is written to `manifest.json`.
+///
+/// # Fields
+///
+/// * `nailpolish_version` - The version of nailpolish used.
+/// * `command` - The command line used to run the pipeline.
+/// * `input` - The input .fastq file.
+/// * `files` - The output of each stage, relative to the output directory.
+#[derive(Serialize)]
+struct Manifest {
+    nailpolish_version: String,
+    command: String,
+    input: String,
+    files: ManifestFiles,
+}
+
+#[derive(Serialize)]
+struct ManifestFiles {
+    index: String,
+    summary: String,
+    consensus: String,
+}
+
+/// Runs `index`, `summary` and `call` on a .fastq file in turn, writing every output to a single
+/// directory along with a `manifest.json` listing the files produced. The duplicates of the index
+/// are found while it is written and shared between the summary and consensus calling stages, so
+/// the index is never read back.
+///
+/// # Arguments
+///
+/// * `input` - The path to the input .fastq file.
+/// * `opts` - The options for each stage of the pipeline.
+///
+/// # Returns
+///
+/// * `Result<()>` - Returns an `Ok(())` if successful, or an `anyhow::Error` if an error occurs.
+pub fn run(input: &str, opts: &RunOpts) -> Result<()> {
+    let dir = Path::new(&opts.output_dir);
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Could not create output directory {}", opts.output_dir))?;
+
+    let files = ManifestFiles {
+        index: "index.tsv".to_string(),
+        summary: match opts.format {
+            SummaryFormat::Text => "summary.txt",
+            format => format.default_output(),
+        }
+        .to_string(),
+        consensus: format!("consensus.fastq{}", opts.compress.extension()),
+    };
+
+    let path = |name: &str| dir.join(name).to_string_lossy().to_string();
+    let (index_path, summary_path, consensus_path) = (
+        path(&files.index),
+        path(&files.summary),
+        path(&files.consensus),
+    );
+
+    info!("Creating index at {index_path}");
+    let (metadata, duplicates) = index::construct_index(
+        input,
+        IndexWriter::new(&index_path)?.with_duplicates(opts.grouping.clone()),
+        &opts.barcode_format,
+        opts.skip_unmatched,
+        &opts.clusters,
+        opts.sample_column,
+        opts.filter_opts.clone(),
+    )?;
+    let IndexDuplicates {
+        duplicates,
+        statistics,
+        reads,
+    } = duplicates.expect("duplicates are found by a writer created with_duplicates");
+
+    let sample = summary::sample_name(input);
+    let report =
+        summary::summary_from_duplicates(metadata, &duplicates, statistics, reads, sample)?;
+    summary::write_summaries(&[report], opts.format, &summary_path)?;
+
+    info!("Calling consensus reads to {consensus_path}");
+    let mut collection = UMIGroupCollection::from_duplicates(input, duplicates, opts.mmap)?;
+    let mut writer = crate::get_writer(&Some(consensus_path), &Some(opts.compress), opts.threads)?;
+    call::consensus(
+        &mut collection,
+        &mut writer,
+        opts.threads,
+        opts.duplicates_only,
+        opts.report_original_reads,
+    )?;
+    writer.finish()?;
+
+    let manifest = Manifest {
+        nailpolish_version: crate::cli::VERSION.to_string(),
+        command: std::env::args().collect::<Vec<_>>().join(" "),
+        input: input.to_string(),
+        files,
+    };
+
+    let manifest_path = path("manifest.json");
+    let file = std::fs::File::create(&manifest_path)
+        .with_context(|| format!("Could not create manifest at {manifest_path}"))?;
+    serde_json::to_writer_pretty(file, &manifest)?;
+    info!("Wrote manifest to {manifest_path}");
+
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// The number of points on the rarefaction (subsampling) part of the saturation curve
+const RAREFACTION_POINTS: usize = 25;
+
+/// The sequencing depth, relative to the current depth, up to which unique molecules are
+/// extrapolated
+const MAX_EXTRAPOLATION: f64 = 10.0;
+
+/// The step between extrapolated points, relative to the current depth
+const EXTRAPOLATION_STEP: f64 = 0.25;
+
+/// The number of terms used in the Efron-Thisted estimator
+const EFRON_THISTED_TERMS: usize = 20;
+
+/// A point on the saturation curve.
+///
+/// # Fields
+///
+/// * `reads` - The number of reads sequenced.
+/// * `molecules` - The expected number of unique molecules (UMI groups) at this depth.
+/// * `extrapolated` - Whether this point is beyond the current sequencing depth.
+#[derive(Serialize, Debug, Clone)]
+pub struct SaturationPoint {
+    pub reads: f64,
+    pub molecules: f64,
+    pub extrapolated: bool,
+}
+
+/// Statistics about library complexity and how saturated sequencing is.
+///
+/// # Fields
+///
+/// * `sequencing_saturation` - The proportion of reads which are not the first read of their
+///   molecule, i.e. `1 - molecules / reads`.
+/// * `observed_molecules` - The number of unique molecules (UMI groups) observed.
+/// * `new_molecule_rate` - The estimated probability that an additional read would come from an
+///   unobserved molecule (the Good-Turing estimate, `singletons / reads`).
+/// * `estimated_total_molecules` - The Chao1 estimate of the number of molecules in the library,
+///   which is a lower bound on the true library complexity.
+/// * `curve` - The expected number of unique molecules at a range of sequencing depths. Points up
+///   to the current depth are computed by subsampling (rarefaction); points beyond it are
+///   extrapolated using the Efron-Thisted estimator.
+#[derive(Serialize, Debug, Clone)]
+pub struct SaturationStatistics {
+    pub sequencing_saturation: f64,
+    pub observed_molecules: usize,
+    pub new_molecule_rate: f64,
+    pub estimated_total_molecules: f64,
+    pub curve: Vec<SaturationPoint>,
+}
+
+/// Estimates sequencing saturation and library complexity from a group size distribution.
+///
+/// # Arguments
+///
+/// * `distribution` - A map from group size to the number of UMI groups of that size, as in
+///   `DuplicateStatistics.distribution`.
+pub fn saturation(distribution: &BTreeMap<usize, usize>) -> SaturationStatistics {
+    let reads = distribution.iter().map(|(j, n)| j * n).sum::<usize>() as f64;
+    let molecules = distribution.values().sum::<usize>();
+
+    let n = |j: usize| *distribution.get(&j).unwrap_or(&0) as f64;
+    let (n1, n2) = (n(1), n(2));
+
+    if reads == 0.0 {
+        return SaturationStatistics {
+            sequencing_saturation: 0.0,
+            observed_molecules: 0,
+            new_molecule_rate: 0.0,
+            estimated_total_molecules: 0.0,
+            curve: Vec::new(),
+        };
+    }
+
+    // bias-corrected Chao1, which is well-defined even when there are no doubletons
+    let estimated_total_molecules =
+        molecules as f64 + n1 * (n1 - 1.0).max(0.0) / (2.0 * (n2 + 1.0));
+
+    let mut curve = Vec::new();
+
+    // rarefaction: if each read is kept with probability p, a molecule with j reads is observed
+    // with probability 1 - (1 - p)^j
+    for i in 1..=RAREFACTION_POINTS {
+        let p = i as f64 / RAREFACTION_POINTS as f64;
+        let expected = distribution
+            .iter()
+            .map(|(&j, &count)| count as f64 * (1.0 - (1.0 - p).powi(j as i32)))
+            .sum::<f64>();
+
+        curve.push(SaturationPoint {
+            reads: p * reads,
+            molecules: expected,
+            extrapolated: false,
+        });
+    }
+
+    // extrapolation: the number of new molecules found by sequencing a further t times the
+    // current depth. the estimate should never decrease with depth, so it is clamped to be
+    // non-decreasing
+    let mut previous = molecules as f64;
+    let mut t = EXTRAPOLATION_STEP;
+    while t <= MAX_EXTRAPOLATION - 1.0 + 1e-9 {
+        let expected = (molecules as f64 + efron_thisted(distribution, t)).max(previous);
+        previous = expected;
+
+        curve.push(SaturationPoint {
+            reads: (1.0 + t) * reads,
+            molecules: expected,
+            extrapolated: true,
+        });
+
+        t += EXTRAPOLATION_STEP;
+    }
+
+    SaturationStatistics {
+        sequencing_saturation: 1.0 - molecules as f64 / reads,
+        observed_molecules: molecules,
+        new_molecule_rate: n1 / reads,
+        estimated_total_molecules,
+        curve,
+    }
+}
+
+/// The Efron-Thisted estimate of the number of new molecules observed when sequencing a further
+/// `t` times the current depth. This is the Good-Toulmin estimator
+///     sum_j (-1)^(j+1) t^j n_j
+/// smoothed with binomial tail weights so that it remains stable for t > 1.
+fn efron_thisted(distribution: &BTreeMap<usize, usize>, t: f64) -> f64 {
+    let k = EFRON_THISTED_TERMS;
+    let q = 1.0 / (1.0 + t);
+
+    (1..=k)
+        .map(|j| {
+            let n_j = *distribution.get(&j).unwrap_or(&0) as f64;
+            let sign = if j % 2 == 1 { 1.0 } else { -1.0 };
+            sign * t.powi(j as i32) * binomial_tail(k, q, j) * n_j
+        })
+        .sum()
+}
+
+/// Returns `P(X >= j)` where `X ~ Binomial(k, q)`.
+fn binomial_tail(k: usize, q: f64, j: usize) -> f64 {
+    let mut coefficient = 1.0;
+    let mut total = 0.0;
+
+    for i in 0..=k {
+        if i > 0 {
+            coefficient *= (k - i + 1) as f64 / i as f64;
+        }
+        if i >= j {
+            total += coefficient * q.powi(i as i32) * (1.0 - q).powi((k - i) as i32);
+        }
+    }
+
+    total
+}
@@ -0,0 +1,387 @@
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::io::Write;
+
+/// The bases used for random sequences and substitution errors.
+const BASES: [u8; 4] = *b"ACGT";
+
+/// The distribution of the number of reads (PCR duplicates) sequenced from each molecule.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DuplicationModel {
+    /// 1 + Poisson(mean - 1), where most molecules have close to the mean number of reads
+    Poisson,
+
+    /// a geometric distribution starting at 1, with a long tail of heavily duplicated molecules
+    Geometric,
+}
+
+/// Options which control the simulated data.
+///
+/// # Fields
+///
+/// * `transcripts` - A .fasta/.fastq file of transcripts to sample reads from. If `None`,
+///   random transcripts are generated.
+/// * `transcript_count` - The number of random transcripts to generate.
+/// * `transcript_length` - The mean length of random transcripts.
+/// * `cells` - The number of cells (barcodes).
+/// * `umis` - The number of molecules (UMIs) per cell.
+/// * `barcode_length` - The length of each barcode.
+/// * `umi_length` - The length of each UMI.
+/// * `duplication` - The distribution of the number of reads per molecule.
+/// * `mean_duplicates` - The mean number of reads per molecule, which must be at least 1.
+/// * `substitution_rate`, `insertion_rate`, `deletion_rate` - The per-base sequencing error rates.
+/// * `barcode_error_rate`, `umi_error_rate` - The per-base substitution rates of the barcode and
+///   UMI written in each read header.
+/// * `reference_output` - If given, the path to write the transcripts to as a .fasta file, which
+///   can be used as the reference when evaluating consensus accuracy.
+/// * `seed` - The random seed; the same seed and options always produce the same output.
+pub struct SimulateOpts {
+    pub transcripts: Option<String>,
+    pub transcript_count: usize,
+    pub transcript_length: usize,
+    pub cells: usize,
+    pub umis: usize,
+    pub barcode_length: usize,
+    pub umi_length: usize,
+    pub duplication: DuplicationModel,
+    pub mean_duplicates: f64,
+    pub substitution_rate: f64,
+    pub insertion_rate: f64,
+    pub deletion_rate: f64,
+    pub barcode_error_rate: f64,
+    pub umi_error_rate: f64,
+    pub reference_output: Option<String>,
+    pub seed: u64,
+}
+
+/// A single molecule, from which one or more reads are sequenced.
+struct Molecule {
+    cell: usize,
+    umi: Vec<u8>,
+    transcript: usize,
+}
+
+/// A row of the truth table, which records the molecule that each read was sequenced from.
+///
+/// # Fields
+///
+/// * `read_id` - The identifier of the read, as written in the .fastq header.
+/// * `molecule` - The index of the molecule; reads with the same index are true duplicates.
+/// * `barcode`, `umi` - The true barcode and UMI of the molecule.
+/// * `observed_barcode`, `observed_umi` - The barcode and UMI written in the read header, which
+///   may contain errors.
+/// * `transcript` - The name of the transcript the molecule was sampled from.
+/// * `errors` - The number of sequencing errors (substitutions, insertions and deletions).
+#[derive(Serialize)]
+struct TruthRecord<'a> {
+    read_id: String,
+    molecule: usize,
+    barcode: &'a str,
+    umi: &'a str,
+    observed_barcode: &'a str,
+    observed_umi: &'a str,
+    transcript: &'a str,
+    errors: usize,
+}
+
+/// Simulates reads from a set of cells and molecules, with PCR duplicates and sequencing errors.
+/// Reads are written in the `bc-umi` preset header format (`@BARCODE_UMI#READ`) in a random
+/// order, and the molecule that each read came from is written to a truth table.
+///
+/// # Arguments
+///
+/// * `opts` - The options which control the simulated data.
+/// * `writer` - Where the simulated .fastq is written.
+/// * `truth` - The path to write the truth table to, as a TSV file.
+pub fn simulate(opts: &SimulateOpts, writer: &mut impl Write, truth: &str) -> Result<()> {
+    if opts.mean_duplicates < 1.0 {
+        bail!("The mean number of reads per molecule must be at least 1");
+    }
+    for rate in [
+        opts.substitution_rate,
+        opts.insertion_rate,
+        opts.deletion_rate,
+        opts.barcode_error_rate,
+        opts.umi_error_rate,
+    ] {
+        if !(0.0..1.0).contains(&rate) {
+            bail!("Error rates must be within [0, 1), but {rate} was given");
+        }
+    }
+
+    let mut rng = fastrand::Rng::with_seed(opts.seed);
+
+    let transcripts = match &opts.transcripts {
+        Some(path) => read_transcripts(path)?,
+        None => (0..opts.transcript_count)
+            .map(|i| {
+                // lengths are uniformly distributed between 0.5x and 1.5x the mean
+                let len = opts.transcript_length / 2 + rng.usize(0..=opts.transcript_length);
+                (format!("random_{i}"), random_sequence(&mut rng, len.max(1)))
+            })
+            .collect(),
+    };
+    if transcripts.is_empty() {
+        bail!("There are no transcripts to simulate reads from");
+    }
+
+    if let Some(path) = &opts.reference_output {
+        let mut wtr = std::io::BufWriter::new(
+            std::fs::File::create(path)
+                .with_context(|| format!("Could not create reference at {path}"))?,
+        );
+        for (name, seq) in transcripts.iter() {
+            writeln!(wtr, ">{name}")?;
+            wtr.write_all(seq)?;
+            wtr.write_all(b"\n")?;
+        }
+        wtr.flush()?;
+        info!("Wrote {} transcripts to {path}", transcripts.len());
+    }
+
+    let barcodes = unique_sequences(&mut rng, opts.cells, opts.barcode_length)
+        .context("Could not generate unique barcodes; try a longer --barcode-length")?;
+
+    let mut molecules = Vec::with_capacity(opts.cells * opts.umis);
+    for cell in 0..opts.cells {
+        let umis = unique_sequences(&mut rng, opts.umis, opts.umi_length)
+            .context("Could not generate unique UMIs; try a longer --umi-length")?;
+
+        for umi in umis {
+            molecules.push(Molecule {
+                cell,
+                umi,
+                transcript: rng.usize(0..transcripts.len()),
+            });
+        }
+    }
+
+    // each read is stored as (molecule, seed) and only generated when it is written, so that
+    // reads can be shuffled without holding every sequence in memory
+    let mut reads = Vec::new();
+    for idx in 0..molecules.len() {
+        for _ in 0..duplicate_count(&mut rng, opts.duplication, opts.mean_duplicates) {
+            reads.push((idx, rng.u64(..)));
+        }
+    }
+    rng.shuffle(&mut reads);
+
+    info!(
+        "Simulating {} reads from {} molecules in {} cells",
+        reads.len(),
+        molecules.len(),
+        opts.cells
+    );
+
+    let mut truth_wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_path(truth)
+        .with_context(|| format!("Could not create truth table at {truth}"))?;
+
+    for (read_idx, (molecule_idx, seed)) in reads.into_iter().enumerate() {
+        let molecule = &molecules[molecule_idx];
+        let (transcript_name, transcript) = &transcripts[molecule.transcript];
+        let mut rng = fastrand::Rng::with_seed(seed);
+
+        let barcode = &barcodes[molecule.cell];
+        let observed_barcode = mutate_tag(&mut rng, barcode, opts.barcode_error_rate);
+        let observed_umi = mutate_tag(&mut rng, &molecule.umi, opts.umi_error_rate);
+        let (seq, qual, errors) = sequence_read(&mut rng, transcript, opts);
+
+        // tags only contain A, C, G and T, so are always valid UTF-8
+        let text = |s: &[u8]| String::from_utf8_lossy(s).to_string();
+        let (observed_barcode, observed_umi) = (text(&observed_barcode), text(&observed_umi));
+        let read_id = format!("{observed_barcode}_{observed_umi}#read{read_idx}");
+
+        writeln!(writer, "@{read_id}")?;
+        writer.write_all(&seq)?;
+        writer.write_all(b"\n+\n")?;
+        writer.write_all(&qual)?;
+        writer.write_all(b"\n")?;
+
+        truth_wtr.serialize(TruthRecord {
+            read_id,
+            molecule: molecule_idx,
+            barcode: &text(barcode),
+            umi: &text(&molecule.umi),
+            observed_barcode: &observed_barcode,
+            observed_umi: &observed_umi,
+            transcript: transcript_name,
+            errors,
+        })?;
+    }
+
+    truth_wtr.flush()?;
+
+    Ok(())
+}
+
+/// Reads every record of a .fasta/.fastq file as a `(name, sequence)` pair.
+fn read_transcripts(path: &str) -> Result<Vec<(String, Vec<u8>)>> {
+    let mut reader = needletail::parse_fastx_file(path)
+        .with_context(|| format!("Could not open transcripts at {path}"))?;
+
+    let mut transcripts = Vec::new();
+    while let Some(rec) = reader.next() {
+        let rec = rec.context("Could not parse transcript")?;
+
+        // only the first word of the header is used as the name
+        let id = String::from_utf8_lossy(rec.id()).to_string();
+        let name = id.split_whitespace().next().unwrap_or_default().to_string();
+
+        let seq = rec.seq().to_ascii_uppercase();
+        if !seq.is_empty() {
+            transcripts.push((name, seq));
+        }
+    }
+
+    info!("Read {} transcripts from {path}", transcripts.len());
+
+    Ok(transcripts)
+}
+
+fn random_sequence(rng: &mut fastrand::Rng, len: usize) -> Vec<u8> {
+    (0..len).map(|_| BASES[rng.usize(0..4)]).collect()
+}
+
+/// Generates `count` distinct random sequences of length `len`.
+fn unique_sequences(rng: &mut fastrand::Rng, count: usize, len: usize) -> Result<Vec<Vec<u8>>> {
+    // 4^len possible sequences, saturating for long sequences
+    let possible = 4f64.powi(len as i32);
+    if count as f64 > possible / 2.0 {
+        bail!("Cannot generate {count} unique sequences of length {len}");
+    }
+
+    let mut seen = std::collections::HashSet::with_capacity(count);
+    let mut sequences = Vec::with_capacity(count);
+    while sequences.len() < count {
+        let seq = random_sequence(rng, len);
+        if seen.insert(seq.clone()) {
+            sequences.push(seq);
+        }
+    }
+
+    Ok(sequences)
+}
+
+/// Samples the number of reads sequenced from a molecule, which is always at least 1.
+fn duplicate_count(rng: &mut fastrand::Rng, model: DuplicationModel, mean: f64) -> usize {
+    match model {
+        DuplicationModel::Poisson => 1 + poisson(rng, mean - 1.0),
+        DuplicationModel::Geometric => {
+            // the number of trials until the first success, with success probability 1 / mean
+            let p = 1.0 / mean;
+            if p >= 1.0 {
+                return 1;
+            }
+            let u = 1.0 - rng.f64(); // in (0, 1]
+            1 + (u.ln() / (1.0 - p).ln()).floor() as usize
+        }
+    }
+}
+
+/// Samples from a Poisson distribution with mean `lambda`.
+fn poisson(rng: &mut fastrand::Rng, lambda: f64) -> usize {
+    if lambda <= 0.0 {
+        return 0;
+    }
+
+    // Knuth's method is exact, but slow for large means, where a normal approximation is used
+    if lambda > 30.0 {
+        let normal =
+            (-2.0 * (1.0 - rng.f64()).ln()).sqrt() * (2.0 * std::f64::consts::PI * rng.f64()).cos();
+        return (lambda + lambda.sqrt() * normal).round().max(0.0) as usize;
+    }
+
+    let limit = (-lambda).exp();
+    let mut k = 0;
+    let mut p = rng.f64();
+    while p > limit {
+        k += 1;
+        p *= rng.f64();
+    }
+    k
+}
+
+/// Introduces substitutions into a barcode or UMI, each base being substituted with probability
+/// `rate`.
+fn mutate_tag(rng: &mut fastrand::Rng, tag: &[u8], rate: f64) -> Vec<u8> {
+    tag.iter()
+        .map(|&base| {
+            if rng.f64() < rate {
+                substitute(rng, base)
+            } else {
+                base
+            }
+        })
+        .collect()
+}
+
+/// Returns a random base which is different to `base`.
+fn substitute(rng: &mut fastrand::Rng, base: u8) -> u8 {
+    loop {
+        let new = BASES[rng.usize(0..4)];
+        if new != base {
+            return new;
+        }
+    }
+}
+
+/// Sequences a transcript with a nanopore-like error profile, returning the sequence, the PHRED+33
+/// quality string and the number of errors. Insertions and deletions are more likely within
+/// homopolymers, and erroneous bases are given lower quality scores than correct bases.
+fn sequence_read(
+    rng: &mut fastrand::Rng,
+    transcript: &[u8],
+    opts: &SimulateOpts,
+) -> (Vec<u8>, Vec<u8>, usize) {
+    let mut seq = Vec::with_capacity(transcript.len() + transcript.len() / 10);
+    let mut qual = Vec::with_capacity(seq.capacity());
+    let mut errors = 0;
+
+    let mut push = |seq: &mut Vec<u8>, base: u8, error: bool, rng: &mut fastrand::Rng| {
+        seq.push(base);
+        let q = if error {
+            rng.u8(2..=10)
+        } else {
+            rng.u8(12..=30)
+        };
+        qual.push(q + 33);
+    };
+
+    for (i, &base) in transcript.iter().enumerate() {
+        // indels are twice as likely after the first base of a homopolymer
+        let homopolymer = i > 0 && transcript[i - 1] == base;
+        let indel_scale = if homopolymer { 2.0 } else { 1.0 };
+
+        if rng.f64() < opts.deletion_rate * indel_scale {
+            errors += 1;
+            continue;
+        }
+
+        if rng.f64() < opts.substitution_rate {
+            errors += 1;
+            push(&mut seq, substitute(rng, base), true, rng);
+        } else {
+            push(&mut seq, base, false, rng);
+        }
+
+        if rng.f64() < opts.insertion_rate * indel_scale {
+            errors += 1;
+            // inserted bases usually extend the current homopolymer
+            let inserted = if rng.bool() {
+                base
+            } else {
+                BASES[rng.usize(0..4)]
+            };
+            push(&mut seq, inserted, true, rng);
+        }
+    }
+
+    // a read must contain at least one base
+    if seq.is_empty() {
+        push(&mut seq, transcript[0], false, rng);
+    }
+
+    (seq, qual, errors)
+}
@@ -0,0 +1,203 @@
+use crate::duplicates::RecordIdentifier;
+use anyhow::{Context, Result};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+
+/// The order in which groups are written by `group`.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GroupOrder {
+    /// in the order that the first read of each group appears in the input
+    Input,
+
+    /// sorted by sample (if any), then barcode, then UMI, then group size (largest first)
+    Barcode,
+
+    /// sorted by group size, largest first
+    Size,
+}
+
+impl GroupOrder {
+    /// Creates the sort key of a read within a group. Keys are compared bytewise, and ties are
+    /// broken by the group index and then the position of the read within its group, so that
+    /// the reads of each group stay together and in order.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The identifier of the group.
+    /// * `group_size` - The number of reads in the group.
+    /// * `group_idx` - The index of the group, as in the `UG` tag.
+    /// * `read_idx` - The index of the read within the group.
+    pub fn sort_key(
+        &self,
+        id: &RecordIdentifier,
+        group_size: usize,
+        group_idx: usize,
+        read_idx: usize,
+    ) -> Vec<u8> {
+        let (barcode, umi) = (id.barcode(), id.umi());
+        let mut key = Vec::with_capacity(id.sample.len() + barcode.len() + umi.len() + 27);
+
+        // larger groups should be sorted first, so we store the complement of the size
+        let size = (u64::MAX - group_size as u64).to_be_bytes();
+
+        match self {
+            GroupOrder::Input => {}
+            GroupOrder::Barcode => {
+                // a NUL separator ensures that the sample is compared before the barcode, and the
+                // barcode before the UMI
+                key.extend_from_slice(id.sample.as_bytes());
+                key.push(0);
+                key.extend_from_slice(barcode.as_bytes());
+                key.push(0);
+                key.extend_from_slice(umi.as_bytes());
+                key.push(0);
+                key.extend_from_slice(&size);
+            }
+            GroupOrder::Size => key.extend_from_slice(&size),
+        }
+
+        // big-endian integers sort correctly when compared bytewise
+        key.extend_from_slice(&(group_idx as u64).to_be_bytes());
+        key.extend_from_slice(&(read_idx as u64).to_be_bytes());
+
+        key
+    }
+}
+
+/// The approximate per-entry overhead of the in-memory buffer, in bytes.
+const ENTRY_OVERHEAD: usize = 2 * std::mem::size_of::<Vec<u8>>();
+
+/// An external merge sort over `(key, value)` byte strings. Entries are buffered in memory until
+/// the buffer reaches `max_bytes`, at which point the buffer is sorted and written to a temporary
+/// file as a sorted 'run'. When finished, the runs are merged, so that memory usage stays
+/// bounded regardless of the number of entries.
+///
+/// Temporary files are created in the system temporary directory (which can be set with the
+/// `TMPDIR` environment variable), and are automatically cleaned up.
+pub struct ExternalSorter {
+    buf: Vec<(Vec<u8>, Vec<u8>)>,
+    buf_bytes: usize,
+    max_bytes: usize,
+    runs: Vec<File>,
+}
+
+impl ExternalSorter {
+    pub fn new(max_bytes: usize) -> Self {
+        ExternalSorter {
+            buf: Vec::new(),
+            buf_bytes: 0,
+            max_bytes,
+            runs: Vec::new(),
+        }
+    }
+
+    /// Adds an entry to be sorted.
+    pub fn push(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.buf_bytes += key.len() + value.len() + ENTRY_OVERHEAD;
+        self.buf.push((key, value));
+
+        if self.buf_bytes >= self.max_bytes {
+            self.spill()?;
+        }
+
+        Ok(())
+    }
+
+    /// Sorts the in-memory buffer and writes it to a new temporary file.
+    fn spill(&mut self) -> Result<()> {
+        self.buf.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        let file = tempfile::tempfile().context("Could not create temporary file for sorting")?;
+        let mut wtr = BufWriter::new(file);
+
+        for (key, value) in self.buf.drain(..) {
+            write_entry(&mut wtr, &key, &value)?;
+        }
+
+        let mut file = wtr.into_inner()?;
+        file.seek(SeekFrom::Start(0))?;
+
+        info!(
+            "Wrote sorted run {} ({} MB) to a temporary file",
+            self.runs.len() + 1,
+            self.buf_bytes / 1024usize.pow(2)
+        );
+
+        self.runs.push(file);
+        self.buf_bytes = 0;
+
+        Ok(())
+    }
+
+    /// Consumes the sorter, calling `f` on each value in sorted order.
+    pub fn finish(mut self, mut f: impl FnMut(&[u8]) -> Result<()>) -> Result<()> {
+        // if nothing has been spilled, we can sort entirely in memory
+        if self.runs.is_empty() {
+            self.buf.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            for (_, value) in self.buf.iter() {
+                f(value)?;
+            }
+            return Ok(());
+        }
+
+        if !self.buf.is_empty() {
+            self.spill()?;
+        }
+
+        info!("Merging {} sorted runs", self.runs.len());
+
+        let mut readers = self
+            .runs
+            .into_iter()
+            .map(BufReader::new)
+            .collect::<Vec<_>>();
+
+        // a min-heap of the smallest unmerged entry from each run
+        let mut heap = BinaryHeap::with_capacity(readers.len());
+        for (run, rdr) in readers.iter_mut().enumerate() {
+            if let Some((key, value)) = read_entry(rdr)? {
+                heap.push(Reverse((key, run, value)));
+            }
+        }
+
+        while let Some(Reverse((_, run, value))) = heap.pop() {
+            f(&value)?;
+
+            if let Some((key, value)) = read_entry(&mut readers[run])? {
+                heap.push(Reverse((key, run, value)));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes a length-prefixed `(key, value)` entry.
+fn write_entry(wtr: &mut impl Write, key: &[u8], value: &[u8]) -> std::io::Result<()> {
+    wtr.write_all(&(key.len() as u32).to_le_bytes())?;
+    wtr.write_all(key)?;
+    wtr.write_all(&(value.len() as u64).to_le_bytes())?;
+    wtr.write_all(value)
+}
+
+/// Reads a length-prefixed `(key, value)` entry, or `None` if the end of the run is reached.
+fn read_entry(rdr: &mut impl Read) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    let mut key_len = [0u8; 4];
+    match rdr.read_exact(&mut key_len) {
+        Ok(_) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    let mut key = vec![0; u32::from_le_bytes(key_len) as usize];
+    rdr.read_exact(&mut key)?;
+
+    let mut value_len = [0u8; 8];
+    rdr.read_exact(&mut value_len)?;
+    let mut value = vec![0; u64::from_le_bytes(value_len) as usize];
+    rdr.read_exact(&mut value)?;
+
+    Ok(Some((key, value)))
+}
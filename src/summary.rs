@@ -1,41 +1,1111 @@
-use crate::{duplicates, index};
-use anyhow::{Context, Result};
+use crate::duplicates::{
+    CellStatistics, DuplicateMap, DuplicateStatistics, GroupingOpts, SampleStatistics,
+};
+use crate::file::ReadFileMetadata;
+use crate::histogram::{Histogram, ReadDistributions};
+use crate::plot::{Plot, Series};
+use crate::saturation::SaturationStatistics;
+use crate::{duplicates, index, saturation};
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
 use serde_json::json;
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{IsTerminal, Write};
 
 // encode the template HTML file at compile time as a string literal
 const TEMPLATE_HTML: &str = include_str!("summary_template.html");
+const COMPARISON_TEMPLATE_HTML: &str = include_str!("summary_compare_template.html");
 
-/// Summarizes the index and writes the output in HTML format to a file.
+/// The number of cells (with the most reads) shown in the per-cell table of the HTML report
+const REPORT_CELL_COUNT: usize = 50;
+
+/// The maximum number of points drawn in the barcode rank plot
+const KNEE_PLOT_POINTS: usize = 1000;
+
+/// The width, in characters, of the longest bar in the text summary
+const TEXT_BAR_WIDTH: usize = 50;
+
+/// The largest group size for which each group size has its own row in the text summary
+const TEXT_HISTOGRAM_ROWS: usize = 20;
+
+/// The file formats which a summary can be written in.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SummaryFormat {
+    /// a self-contained HTML report
+    Html,
+
+    /// a JSON object containing the index metadata and duplicate statistics
+    Json,
+
+    /// a `metric<TAB>value` table, with one value column per sample
+    Tsv,
+
+    /// a MultiQC custom content file, which is picked up automatically by MultiQC
+    Multiqc,
+
+    /// a plain-text summary with a bar chart of group sizes, for viewing in a terminal
+    Text,
+}
+
+impl SummaryFormat {
+    /// The output path used when none is given.
+    pub fn default_output(&self) -> &'static str {
+        match self {
+            SummaryFormat::Html => "summary.html",
+            SummaryFormat::Json => "summary.json",
+            SummaryFormat::Tsv => "summary.tsv",
+            SummaryFormat::Multiqc => "nailpolish_mqc.json",
+            SummaryFormat::Text => "-",
+        }
+    }
+}
+
+/// All of the statistics reported in a summary of an index.
+pub struct Summary {
+    sample: String,
+    metadata: ReadFileMetadata,
+    statistics: DuplicateStatistics,
+    cells: Vec<CellStatistics>,
+    samples: Vec<SampleStatistics>,
+    saturation: SaturationStatistics,
+    reads: ReadDistributions,
+}
+
+/// Summarizes one or more indexes and writes the output to a file. If several indexes are given,
+/// a single report comparing each sample is written.
 ///
 /// # Arguments
 ///
-/// * `index` - A string slice that holds the path to the index file.
-/// * `output` - A string slice that holds the path to the output file.
+/// * `indexes` - The paths to the index files.
+/// * `sample_sheet` - If given, the path to a sample sheet which assigns a label to each index.
+/// * `output` - The path to the output file, or `-` for standard output. If `None`, a default
+///   based on `format` is used.
+/// * `format` - The format of the output file. If `None`, a text summary is written when writing
+///   to standard output (either with `-`, or when no output is given and standard output is a
+///   terminal), and an HTML report is written otherwise.
+/// * `cell_output` - If given, the path to write per-cell statistics to as a TSV file.
+/// * `grouping` - The identifier components which define a cell and a molecule.
 ///
 /// # Returns
 ///
 /// * `Result<()>` - Returns an `Ok(())` if successful, or an `anyhow::Error` if an error occurs.
-pub fn summarize(index: &str, output: &str) -> Result<()> {
-    info!("Summarising index at {index}");
-    let mut index = index::IndexReader::from_path(index)?;
-    let (_, statistics) = index.get_duplicates()?;
-    let gb = index.metadata.gb;
+pub fn summarize(
+    indexes: &[String],
+    sample_sheet: &Option<String>,
+    output: &Option<String>,
+    format: Option<SummaryFormat>,
+    cell_output: &Option<String>,
+    grouping: &GroupingOpts,
+) -> Result<()> {
+    let format = format.unwrap_or_else(|| {
+        let to_stdout = match output.as_deref() {
+            Some(output) => output == "-",
+            None => std::io::stdout().is_terminal(),
+        };
+
+        if to_stdout {
+            SummaryFormat::Text
+        } else {
+            SummaryFormat::Html
+        }
+    });
+
+    let labels = sample_labels(indexes, sample_sheet)?;
+
+    let summaries = indexes
+        .iter()
+        .zip(labels)
+        .map(|(index, label)| build_summary(index, label, grouping))
+        .collect::<Result<Vec<_>>>()?;
+
+    if let Some(cell_output) = cell_output {
+        write_cell_tsv(&summaries, cell_output)?;
+        info!("Wrote per-cell statistics to {cell_output}");
+    }
+
+    let output = output.as_deref().unwrap_or(format.default_output());
+    write_summaries(&summaries, format, output)
+}
+
+/// Writes one or more summaries in the given format. If there are several summaries, a single
+/// report comparing each sample is written.
+///
+/// # Arguments
+///
+/// * `summaries` - The summary of each sample.
+/// * `format` - The format of the output file.
+/// * `output` - The path to the output file, or `-` for standard output.
+pub fn write_summaries(summaries: &[Summary], format: SummaryFormat, output: &str) -> Result<()> {
+    match (format, summaries) {
+        (SummaryFormat::Html, [summary]) => write_html(summary, output)?,
+        (SummaryFormat::Html, _) => write_comparison_html(summaries, output)?,
+        (SummaryFormat::Json, _) => write_json(summaries, output)?,
+        (SummaryFormat::Tsv, _) => write_tsv(summaries, output)?,
+        (SummaryFormat::Multiqc, _) => write_multiqc(summaries, output)?,
+        (SummaryFormat::Text, _) => write_text(summaries, output)?,
+    }
+
+    if output == "-" {
+        info!("Wrote {format:?} summary to standard output");
+    } else {
+        info!("Wrote {format:?} summary to {output}");
+    }
+
+    Ok(())
+}
+
+/// Reads an index and computes all of the statistics reported in its summary.
+fn build_summary(index: &str, sample: String, grouping: &GroupingOpts) -> Result<Summary> {
+    info!("Summarising index at {index} (sample {sample})");
+    let mut index = index::IndexReader::from_path(index)?.with_grouping(grouping.clone());
+    let (duplicates, statistics) = index.get_duplicates()?;
+    let reads = index.read_distributions(&duplicates)?;
+
+    summary_from_duplicates(index.metadata, &duplicates, statistics, reads, sample)
+}
+
+/// Computes all of the statistics reported in the summary of an index, whose duplicates and read
+/// distributions have already been found, either by reading the index or while it was written.
+///
+/// # Arguments
+///
+/// * `metadata` - The metadata of the index.
+/// * `duplicates` - The `DuplicateMap` of the index.
+/// * `statistics` - The `DuplicateStatistics` of the index.
+/// * `reads` - The `ReadDistributions` of the index.
+/// * `sample` - The sample label used in the report.
+pub fn summary_from_duplicates(
+    metadata: ReadFileMetadata,
+    duplicates: &DuplicateMap,
+    statistics: DuplicateStatistics,
+    reads: ReadDistributions,
+    sample: String,
+) -> Result<Summary> {
+    let cells = duplicates.cell_statistics();
+    let samples = duplicates.sample_statistics();
+
+    debug!("{}", serde_json::to_string(&statistics)?);
+
+    Ok(Summary {
+        sample,
+        saturation: saturation::saturation(&statistics.distribution),
+        metadata,
+        statistics,
+        cells,
+        samples,
+        reads,
+    })
+}
+
+/// Determines the sample label of each index. Labels are taken from the sample sheet if one is
+/// given, and otherwise from the name of the .fastq file that each index was created from. If
+/// these names are not unique, the names of the index files are used instead, with a numbered
+/// suffix added to any which are still repeated.
+///
+/// A sample sheet has one `INDEX<TAB>LABEL` line per index, where `INDEX` is the path to the index
+/// file as given on the command line. Lines starting with `#` are ignored. Each index must be
+/// given a different label.
+fn sample_labels(indexes: &[String], sample_sheet: &Option<String>) -> Result<Vec<String>> {
+    if let Some(sheet) = sample_sheet {
+        let contents = std::fs::read_to_string(sheet)
+            .with_context(|| format!("Could not read sample sheet {sheet}"))?;
+
+        let mut labels = std::collections::HashMap::new();
+        for line in contents.lines() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((index, label)) = line.split_once('\t') else {
+                bail!("Invalid sample sheet line (expected `INDEX<TAB>LABEL`): {line}");
+            };
+            labels.insert(index.trim().to_string(), label.trim().to_string());
+        }
+
+        let labels = indexes
+            .iter()
+            .map(|index| {
+                labels
+                    .get(index)
+                    .cloned()
+                    .with_context(|| format!("Index {index} is not in the sample sheet {sheet}"))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        // samples are keyed by their label in the MultiQC and TSV outputs
+        let mut seen = std::collections::HashSet::new();
+        for label in labels.iter() {
+            if !seen.insert(label) {
+                bail!("The sample sheet {sheet} gives the label {label} to more than one index");
+            }
+        }
+
+        return Ok(labels);
+    }
 
-    let mut data = serde_json::to_value(index.metadata).context("Could not serialize info")?;
+    let mut labels = Vec::with_capacity(indexes.len());
+    for index in indexes {
+        let metadata = index::IndexReader::from_path(index)?.metadata;
+        labels.push(sample_name(&metadata.file_path));
+    }
+
+    let unique = labels
+        .iter()
+        .collect::<std::collections::HashSet<_>>()
+        .len();
+    if unique == labels.len() {
+        return Ok(labels);
+    }
+
+    // index files with the same name in different directories are numbered, e.g. `sample_2`
+    let mut seen = std::collections::HashSet::new();
+    let labels = indexes
+        .iter()
+        .map(|index| {
+            let name = sample_name(index).trim_end_matches(".tsv").to_string();
+            let mut label = name.clone();
+            let mut n = 1;
+            while !seen.insert(label.clone()) {
+                n += 1;
+                label = format!("{name}_{n}");
+            }
+            label
+        })
+        .collect();
+
+    Ok(labels)
+}
+
+/// Writes the summary as a self-contained HTML report.
+fn write_html(summary: &Summary, output: &str) -> Result<()> {
+    let Summary {
+        metadata,
+        statistics,
+        cells,
+        samples,
+        saturation,
+        reads,
+        ..
+    } = summary;
+
+    let mut data = serde_json::to_value(metadata).context("Could not serialize info")?;
 
-    println!("{}", serde_json::to_string(&statistics)?);
     // round "gb" stat to 3dp
-    data["gb"] = json!(format!("{:.3}", gb));
+    data["gb"] = json!(format!("{:.3}", metadata.gb));
     data["stats"] = json!(serde_json::to_string(&statistics)?);
+    data["parameter_rows"] = json!(parameter_rows(metadata)
+        .into_iter()
+        .map(|(name, value)| json!({"name": name, "value": value}))
+        .collect::<Vec<_>>());
 
-    println!(
+    debug!(
         "{}",
         serde_json::to_string_pretty(&data).context("Should be serialisable")?
     );
 
-    let file = std::fs::File::create(output)?;
+    // charts are rendered as inline SVG, so that the report can be viewed offline
+    let (umi_chart, read_chart) = duplicate_charts(statistics);
+    data["umi_chart"] = json!(umi_chart);
+    data["read_chart"] = json!(read_chart);
+
+    data["cell_count"] = json!(cells.len());
+    data["median_cell_reads"] = json!(median(cells.iter().map(|c| c.reads)));
+    data["median_cell_umis"] = json!(median(cells.iter().map(|c| c.umis)));
+    data["knee_chart"] = json!(knee_chart(cells));
+    data["top_cells"] = json!(cells
+        .iter()
+        .take(REPORT_CELL_COUNT)
+        .map(|c| json!({
+            "barcode": cell_label(c),
+            "reads": c.reads,
+            "umis": c.umis,
+            "duplicate_reads": c.duplicate_reads,
+            "percent_duplicate": format!("{:.1}", c.proportion_duplicate * 100.0),
+        }))
+        .collect::<Vec<_>>());
+    data["report_cell_count"] = json!(REPORT_CELL_COUNT.min(cells.len()));
+    data["sample_rows"] = json!(samples
+        .iter()
+        .map(|s| json!({
+            "sample": s.sample,
+            "reads": s.reads,
+            "cells": s.cells,
+            "umi_groups": s.umi_groups,
+            "duplicate_ids": s.duplicate_ids,
+            "duplicate_reads": s.duplicate_reads,
+            "percent_duplicate": format!("{:.1}", s.proportion_duplicate * 100.0),
+        }))
+        .collect::<Vec<_>>());
+
+    data["sequencing_saturation"] =
+        json!(format!("{:.2}", saturation.sequencing_saturation * 100.0));
+    data["new_molecule_rate"] = json!(format!("{:.2}", saturation.new_molecule_rate * 100.0));
+    data["observed_molecules"] = json!(saturation.observed_molecules);
+    data["estimated_total_molecules"] =
+        json!(format!("{:.0}", saturation.estimated_total_molecules));
+    data["saturation_chart"] = json!(saturation_chart(saturation));
+
+    data["read_categories"] = json!([
+        read_category_row(
+            "singleton",
+            &reads.length.singleton,
+            &reads.quality.singleton
+        ),
+        read_category_row(
+            "duplicate",
+            &reads.length.duplicate,
+            &reads.quality.duplicate
+        ),
+        read_category_row("kept", &reads.length.kept(), &reads.quality.kept()),
+        read_category_row("filtered", &reads.length.filtered, &reads.quality.filtered),
+    ]);
+    data["length_duplicate_chart"] = json!(category_chart(
+        "Read length",
+        &[
+            ("Singleton", &reads.length.singleton),
+            ("Duplicate", &reads.length.duplicate)
+        ]
+    ));
+    data["length_filtered_chart"] = json!(category_chart(
+        "Read length",
+        &[
+            ("Kept", &reads.length.kept()),
+            ("Filtered", &reads.length.filtered)
+        ]
+    ));
+    data["quality_duplicate_chart"] = json!(category_chart(
+        "Average read quality",
+        &[
+            ("Singleton", &reads.quality.singleton),
+            ("Duplicate", &reads.quality.duplicate)
+        ]
+    ));
+    data["quality_filtered_chart"] = json!(category_chart(
+        "Average read quality",
+        &[
+            ("Kept", &reads.quality.kept()),
+            ("Filtered", &reads.quality.filtered)
+        ]
+    ));
+
+    let file = create_output(output)?;
     let reg = handlebars::Handlebars::new();
     reg.render_template_to_write(TEMPLATE_HTML, &data, file)?;
 
     Ok(())
 }
+
+/// Writes a self-contained HTML report comparing several samples.
+fn write_comparison_html(summaries: &[Summary], output: &str) -> Result<()> {
+    // each row of the comparison table has one value per sample
+    let row = |name: &str, value: &dyn Fn(&Summary) -> String| {
+        json!({
+            "name": name,
+            "values": summaries.iter().map(value).collect::<Vec<_>>(),
+        })
+    };
+
+    let rows = vec![
+        row("file path", &|s| s.metadata.file_path.clone()),
+        row("nailpolish version", &|s| {
+            s.metadata.nailpolish_version.clone()
+        }),
+        row("index date", &|s| s.metadata.index_date.clone()),
+        row("dataset size (GB)", &|s| format!("{:.3}", s.metadata.gb)),
+        row("total read count", &|s| s.metadata.read_count.to_string()),
+        row("matched reads", &|s| {
+            s.metadata.matched_read_count.to_string()
+        }),
+        row("unmatched reads", &|s| {
+            s.metadata.unmatched_read_count.to_string()
+        }),
+        row("filtered reads", &|s| s.metadata.filtered_reads.to_string()),
+        row("average quality", &|s| {
+            format!("{:.2}", s.metadata.avg_qual)
+        }),
+        row("average length", &|s| format!("{:.1}", s.metadata.avg_len)),
+        row("barcode format", &|s| {
+            s.metadata.parameters.barcode_format()
+        }),
+        row("length filter", &|s| s.metadata.parameters.len.clone()),
+        row("quality filter", &|s| s.metadata.parameters.qual.clone()),
+        row("duplicate reads", &|s| {
+            s.statistics.duplicate_reads.to_string()
+        }),
+        row("% duplicate reads", &|s| {
+            format!("{:.2}", s.statistics.proportion_duplicate * 100.0)
+        }),
+        row("UMI groups", &|s| {
+            s.saturation.observed_molecules.to_string()
+        }),
+        row("% sequencing saturation", &|s| {
+            format!("{:.2}", s.saturation.sequencing_saturation * 100.0)
+        }),
+        row("estimated molecules (Chao1)", &|s| {
+            format!("{:.0}", s.saturation.estimated_total_molecules)
+        }),
+        row("cell barcodes", &|s| s.cells.len().to_string()),
+        row("median reads per cell", &|s| {
+            median(s.cells.iter().map(|c| c.reads)).to_string()
+        }),
+    ];
+
+    // group sizes are compared as proportions, as samples may have very different depths
+    let proportions = |summary: &Summary, by_read: bool| {
+        let dist = &summary.statistics.distribution;
+        let total = dist
+            .iter()
+            .map(|(&size, &n)| if by_read { size * n } else { n })
+            .sum::<usize>() as f64;
+
+        dist.iter()
+            .map(|(&size, &n)| {
+                let count = if by_read { size * n } else { n };
+                (size as f64, count as f64 / total)
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let (umi_chart, read_chart, saturation_chart) = summaries.iter().fold(
+        (
+            Plot::new("Duplicate count", "Proportion of UMI groups").log_y(),
+            Plot::new("Duplicate count", "Proportion of reads").log_y(),
+            Plot::new("Reads", "Unique molecules"),
+        ),
+        |(umi, read, sat), summary| {
+            let curve = summary
+                .saturation
+                .curve
+                .iter()
+                .map(|p| (p.reads, p.molecules))
+                .collect();
+
+            (
+                umi.series(Series::line(&summary.sample, proportions(summary, false))),
+                read.series(Series::line(&summary.sample, proportions(summary, true))),
+                sat.series(Series::line(&summary.sample, curve)),
+            )
+        },
+    );
+
+    let data = json!({
+        "samples": summaries.iter().map(|s| &s.sample).collect::<Vec<_>>(),
+        "rows": rows,
+        "umi_chart": umi_chart.render(),
+        "read_chart": read_chart.render(),
+        "saturation_chart": saturation_chart.render(),
+    });
+
+    let file = create_output(output)?;
+    let reg = handlebars::Handlebars::new();
+    reg.render_template_to_write(COMPARISON_TEMPLATE_HTML, &data, file)?;
+
+    Ok(())
+}
+
+/// Labels a cell by its barcode, and its sample if the index records samples.
+fn cell_label(cell: &CellStatistics) -> String {
+    if cell.sample.is_empty() {
+        cell.barcode.clone()
+    } else {
+        format!("{} ({})", cell.barcode, cell.sample)
+    }
+}
+
+/// Writes per-cell statistics as a TSV file, with one row per barcode in barcode rank order. If
+/// the index records samples, or there are several indexes, a leading `sample` column is added.
+/// When several indexes record samples, this is of the form `LABEL/SAMPLE`.
+fn write_cell_tsv(summaries: &[Summary], output: &str) -> Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(create_output(output)?);
+
+    if let [summary] = summaries {
+        for cell in summary.cells.iter() {
+            wtr.serialize(cell)?;
+        }
+    } else {
+        wtr.write_record([
+            "sample",
+            "barcode",
+            "reads",
+            "umis",
+            "duplicate_reads",
+            "proportion_duplicate",
+        ])?;
+
+        for summary in summaries {
+            for cell in summary.cells.iter() {
+                let sample = if cell.sample.is_empty() {
+                    summary.sample.clone()
+                } else {
+                    format!("{}/{}", summary.sample, cell.sample)
+                };
+                wtr.write_record([
+                    sample,
+                    cell.barcode.clone(),
+                    cell.reads.to_string(),
+                    cell.umis.to_string(),
+                    cell.duplicate_reads.to_string(),
+                    cell.proportion_duplicate.to_string(),
+                ])?;
+            }
+        }
+    }
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Writes the summary as a JSON object of the form
+/// `{"metadata": ..., "statistics": ..., "saturation": ..., "reads": ...}`, with the statistics
+/// of each sample within the index under `sample_statistics`. If there are several indexes, these
+/// are written as `{"samples": [{"sample": ..., "metadata": ..., ...}, ...]}`.
+fn write_json(summaries: &[Summary], output: &str) -> Result<()> {
+    let to_json = |summary: &Summary| {
+        json!({
+            "sample": summary.sample,
+            "metadata": summary.metadata,
+            "statistics": summary.statistics,
+            "sample_statistics": summary.samples,
+            "saturation": summary.saturation,
+            "reads": summary.reads,
+        })
+    };
+
+    let data = match summaries {
+        [summary] => to_json(summary),
+        _ => json!({ "samples": summaries.iter().map(to_json).collect::<Vec<_>>() }),
+    };
+
+    let file = create_output(output)?;
+    serde_json::to_writer_pretty(file, &data)?;
+
+    Ok(())
+}
+
+/// Writes the summary as a `metric<TAB>value` table. The group size distribution is written as
+/// one `group_size_<N>` row per group size, with the number of UMI groups of that size, and the
+/// read length and quality distributions are summarised by the count and mean of each category.
+/// If there are several samples, there is one value column per sample, headed by the sample label.
+fn write_tsv(summaries: &[Summary], output: &str) -> Result<()> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(create_output(output)?);
+
+    let rows = summaries
+        .iter()
+        .map(metric_rows)
+        .collect::<Result<Vec<_>>>()?;
+
+    // metrics such as `group_size_<N>` may not be present for every sample, so collect every
+    // metric in order of first appearance
+    let mut metrics = indexmap::IndexSet::new();
+    for row in rows.iter() {
+        metrics.extend(row.keys().cloned());
+    }
+
+    let header = match summaries {
+        [_] => vec!["value".to_string()],
+        _ => summaries.iter().map(|s| s.sample.clone()).collect(),
+    };
+    wtr.write_record(std::iter::once("metric".to_string()).chain(header))?;
+
+    for metric in metrics {
+        let values = rows
+            .iter()
+            .map(|row| row.get(&metric).cloned().unwrap_or_default());
+        wtr.write_record(std::iter::once(metric.clone()).chain(values))?;
+    }
+
+    wtr.flush()?;
+
+    Ok(())
+}
+
+/// Returns each metric reported in the TSV output of a summary, in order.
+fn metric_rows(summary: &Summary) -> Result<indexmap::IndexMap<String, String>> {
+    let mut rows = indexmap::IndexMap::new();
+
+    rows.extend(
+        scalar_fields(&summary.metadata)?
+            .into_iter()
+            .chain(scalar_fields(&summary.statistics)?)
+            .chain(scalar_fields(&summary.saturation)?),
+    );
+
+    for (size, count) in summary.statistics.distribution.iter() {
+        rows.insert(format!("group_size_{size}"), count.to_string());
+    }
+
+    for sample in summary.samples.iter() {
+        for (name, value) in scalar_fields(sample)? {
+            if name != "sample" {
+                rows.insert(format!("sample_{}_{name}", sample.sample), value);
+            }
+        }
+    }
+
+    for (name, hists) in [
+        ("length", &summary.reads.length),
+        ("quality", &summary.reads.quality),
+    ] {
+        for (category, hist) in [
+            ("singleton", &hists.singleton),
+            ("duplicate", &hists.duplicate),
+            ("filtered", &hists.filtered),
+        ] {
+            rows.insert(format!("{name}_{category}_count"), hist.count.to_string());
+            rows.insert(format!("{name}_{category}_mean"), hist.mean.to_string());
+        }
+    }
+
+    Ok(rows)
+}
+
+/// Writes the summary as a MultiQC custom content file, which adds the duplicate statistics to the
+/// MultiQC general statistics table. MultiQC only picks up custom content automatically when the
+/// file name ends in `_mqc.json`.
+fn write_multiqc(summaries: &[Summary], output: &str) -> Result<()> {
+    if !output.ends_with("_mqc.json") {
+        warn!("MultiQC will only find {output} automatically if it ends in `_mqc.json`");
+    }
+
+    let samples = summaries
+        .iter()
+        .map(|summary| {
+            let Summary {
+                metadata,
+                statistics,
+                saturation,
+                ..
+            } = summary;
+
+            let stats = json!({
+                "total_reads": statistics.total_reads,
+                "duplicate_reads": statistics.duplicate_reads,
+                "duplicate_ids": statistics.duplicate_ids,
+                "percent_duplicate": statistics.proportion_duplicate * 100.0,
+                "unmatched_read_count": metadata.unmatched_read_count,
+                "filtered_reads": metadata.filtered_reads,
+                "sequencing_saturation": saturation.sequencing_saturation * 100.0,
+            });
+
+            (summary.sample.clone(), stats)
+        })
+        .collect::<serde_json::Map<_, _>>();
+
+    let data = json!({
+        "id": "nailpolish",
+        "section_name": "nailpolish",
+        "description": "UMI duplicate statistics generated by nailpolish",
+        "plot_type": "generalstats",
+        "pconfig": [
+            {"total_reads": {"title": "Reads", "description": "Reads used for duplicate detection", "format": "{:,.0f}"}},
+            {"duplicate_reads": {"title": "Dup. reads", "description": "Reads in a UMI group of size > 1", "format": "{:,.0f}"}},
+            {"duplicate_ids": {"title": "Dup. UMIs", "description": "UMI groups of size > 1", "format": "{:,.0f}"}},
+            {"percent_duplicate": {"title": "% Dup.", "description": "Percentage of reads which are duplicates", "max": 100, "min": 0, "suffix": "%"}},
+            {"unmatched_read_count": {"title": "Unmatched", "description": "Reads which did not match the barcode format", "format": "{:,.0f}"}},
+            {"filtered_reads": {"title": "Filtered", "description": "Reads removed by the length and quality filters", "format": "{:,.0f}"}},
+            {"sequencing_saturation": {"title": "Saturation", "description": "Sequencing saturation, 1 - (UMI groups / reads)", "max": 100, "min": 0, "suffix": "%"}},
+        ],
+        "data": samples,
+    });
+
+    let file = create_output(output)?;
+    serde_json::to_writer_pretty(file, &data)?;
+
+    Ok(())
+}
+
+/// Writes a plain-text summary of each sample, intended to be read in a terminal. This shows the
+/// index metadata, the proportion of duplicate reads and a bar chart of the group size
+/// distribution.
+fn write_text(summaries: &[Summary], output: &str) -> Result<()> {
+    let mut text = String::new();
+
+    for (i, summary) in summaries.iter().enumerate() {
+        if i > 0 {
+            text.push('\n');
+        }
+        text.push_str(&render_text(summary));
+    }
+
+    let mut file = create_output(output)?;
+    file.write_all(text.as_bytes())?;
+    file.flush()?;
+
+    Ok(())
+}
+
+/// Returns the options that the index was created with, as (name, value) rows for the text and
+/// HTML reports.
+fn parameter_rows(metadata: &ReadFileMetadata) -> Vec<(&'static str, String)> {
+    let parameters = &metadata.parameters;
+    let yes_no = |b: bool| if b { "yes" } else { "no" }.to_string();
+    let mut rows = if parameters.is_recorded() {
+        vec![
+            ("barcode format", parameters.barcode_format()),
+            ("skip unmatched", yes_no(parameters.skip_unmatched)),
+            ("length filter", parameters.len.clone()),
+            ("quality filter", parameters.qual.clone()),
+            ("command line", parameters.command_line.clone()),
+        ]
+    } else {
+        vec![("index parameters", "not recorded".to_string())]
+    };
+
+    // each filter applied by `filter`, oldest first
+    if !metadata.filter_history.is_empty() {
+        let history = metadata
+            .filter_history
+            .iter()
+            .map(|applied| {
+                format!(
+                    "length {}, quality {} ({} filtered)",
+                    applied.len, applied.qual, applied.filtered_reads
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        rows.push(("filter history", history));
+    }
+
+    rows
+}
+
+/// Renders the plain-text summary of a single sample.
+fn render_text(summary: &Summary) -> String {
+    let Summary {
+        sample,
+        metadata,
+        statistics,
+        saturation,
+        samples,
+        ..
+    } = summary;
+
+    let metadata_rows = [
+        ("nailpolish version", metadata.nailpolish_version.clone()),
+        ("file path", metadata.file_path.clone()),
+        ("dataset size", format!("{:.3} GB", metadata.gb)),
+        ("index date", metadata.index_date.clone()),
+        ("total read count", metadata.read_count.to_string()),
+        ("matched reads", metadata.matched_read_count.to_string()),
+        ("unmatched reads", metadata.unmatched_read_count.to_string()),
+        ("filtered reads", metadata.filtered_reads.to_string()),
+        ("average quality", format!("{:.2}", metadata.avg_qual)),
+        ("average length", format!("{:.1}", metadata.avg_len)),
+    ];
+    let parameter_rows = parameter_rows(metadata);
+    let duplicate_rows = [
+        ("UMI groups", saturation.observed_molecules.to_string()),
+        ("duplicate UMI groups", statistics.duplicate_ids.to_string()),
+        (
+            "duplicate reads",
+            format!(
+                "{} of {} ({:.2}%)",
+                statistics.duplicate_reads,
+                statistics.total_reads,
+                statistics.proportion_duplicate * 100.0
+            ),
+        ),
+        (
+            "sequencing saturation",
+            format!("{:.2}%", saturation.sequencing_saturation * 100.0),
+        ),
+    ];
+
+    // writing to a String never fails, so the results of writeln! are ignored below
+    let mut text = String::new();
+    let _ = writeln!(text, "nailpolish summary: {sample}\n");
+    for rows in [&metadata_rows[..], &parameter_rows[..], &duplicate_rows[..]] {
+        for (name, value) in rows {
+            let _ = writeln!(text, "  {name:<24}{value}");
+        }
+        text.push('\n');
+    }
+
+    if !samples.is_empty() {
+        let _ = writeln!(text, "by sample\n");
+        let width = samples
+            .iter()
+            .map(|s| s.sample.len())
+            .max()
+            .unwrap_or(0)
+            .max(6);
+        let _ = writeln!(
+            text,
+            "  {:<width$}  {:>10}  {:>8}  {:>10}  {:>11}",
+            "sample", "reads", "cells", "UMI groups", "% duplicate"
+        );
+        for s in samples {
+            let _ = writeln!(
+                text,
+                "  {:<width$}  {:>10}  {:>8}  {:>10}  {:>10.2}%",
+                s.sample,
+                s.reads,
+                s.cells,
+                s.umi_groups,
+                s.proportion_duplicate * 100.0
+            );
+        }
+        text.push('\n');
+    }
+
+    let _ = writeln!(text, "UMI groups by group size\n");
+    let bins = text_histogram_bins(&statistics.distribution);
+    let label_width = bins.iter().map(|(l, _)| l.len()).max().unwrap_or(0);
+    let max_count = bins.iter().map(|(_, n)| *n).max().unwrap_or(0);
+
+    for (label, count) in bins {
+        let _ = writeln!(
+            text,
+            "  {label:>label_width$} \u{2502}{} {count}",
+            text_bar(count, max_count, TEXT_BAR_WIDTH)
+        );
+    }
+
+    text
+}
+
+/// Groups the group size distribution into the bins shown in the text summary. Each group size
+/// has its own bin if there are few enough of them, and otherwise sizes above 2 are binned by
+/// powers of two (`3-4`, `5-8`, ...) so that the chart stays a readable height.
+fn text_histogram_bins(distribution: &BTreeMap<usize, usize>) -> Vec<(String, usize)> {
+    let max_size = distribution.keys().max().copied().unwrap_or(0);
+
+    if max_size <= TEXT_HISTOGRAM_ROWS {
+        return (1..=max_size)
+            .map(|size| (size.to_string(), *distribution.get(&size).unwrap_or(&0)))
+            .collect();
+    }
+
+    let mut bins = Vec::new();
+    let (mut lo, mut hi) = (1, 1);
+    while lo <= max_size {
+        let count = distribution.range(lo..=hi).map(|(_, n)| n).sum();
+        let label = if lo == hi {
+            lo.to_string()
+        } else {
+            format!("{lo}-{hi}")
+        };
+        bins.push((label, count));
+
+        lo = hi + 1;
+        hi *= 2;
+    }
+
+    bins
+}
+
+/// Draws a horizontal bar of length proportional to `value / max`, using Unicode block elements
+/// to draw bars with a resolution of 1/8 of a character.
+fn text_bar(value: usize, max: usize, width: usize) -> String {
+    const PARTIAL_BLOCKS: [char; 8] = [
+        ' ', '\u{258F}', '\u{258E}', '\u{258D}', '\u{258C}', '\u{258B}', '\u{258A}', '\u{2589}',
+    ];
+
+    if max == 0 {
+        return String::new();
+    }
+
+    let eighths = (value as f64 / max as f64 * (width * 8) as f64).round() as usize;
+    // never hide a non-zero value entirely
+    let eighths = if value > 0 { eighths.max(1) } else { 0 };
+
+    let mut bar = "\u{2588}".repeat(eighths / 8);
+    if eighths % 8 > 0 {
+        bar.push(PARTIAL_BLOCKS[eighths % 8]);
+    }
+
+    bar
+}
+
+/// Creates a writer for an output path, where `-` is standard output.
+fn create_output(output: &str) -> Result<Box<dyn Write>> {
+    if output == "-" {
+        return Ok(Box::new(std::io::stdout()));
+    }
+
+    let file = std::fs::File::create(output)
+        .with_context(|| format!("Could not create output file at {output}"))?;
+    Ok(Box::new(std::io::BufWriter::new(file)))
+}
+
+/// Returns each scalar (non-object, non-array) field of a serializable struct as a string.
+fn scalar_fields(value: &impl Serialize) -> Result<Vec<(String, String)>> {
+    let serde_json::Value::Object(map) = serde_json::to_value(value)? else {
+        bail!("Expected a struct to be serialized as an object");
+    };
+
+    Ok(map
+        .into_iter()
+        .filter(|(_, v)| !v.is_object() && !v.is_array())
+        .map(|(k, v)| match v {
+            serde_json::Value::String(s) => (k, s),
+            v => (k, v.to_string()),
+        })
+        .collect())
+}
+
+/// Derives a sample name from the path of a .fastq file, by removing the directory and any
+/// .fastq/.fq and compression extensions.
+pub fn sample_name(path: &str) -> String {
+    let mut name = std::path::Path::new(path)
+        .file_name()
+        .map(|f| f.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string());
+
+    for ext in [".gz", ".bgz", ".zst", ".fastq", ".fq"] {
+        if let Some(stripped) = name.strip_suffix(ext) {
+            name = stripped.to_string();
+        }
+    }
+
+    name
+}
+
+/// Renders the group size distribution as SVG bar charts, counted both by UMI group and by read.
+///
+/// # Returns
+///
+/// * `(String, String)` - The charts by UMI group and by read respectively.
+fn duplicate_charts(statistics: &DuplicateStatistics) -> (String, String) {
+    let by_umi = statistics
+        .distribution
+        .iter()
+        .map(|(&size, &count)| (size as f64, count as f64))
+        .collect::<Vec<_>>();
+
+    let by_read = by_umi
+        .iter()
+        .map(|&(size, count)| (size, size * count))
+        .collect::<Vec<_>>();
+
+    // give each bar a minimum width, and allow the chart to scroll horizontally
+    let max_x = statistics.distribution.keys().max().copied().unwrap_or(1);
+    let width = (15.0 * max_x as f64).max(800.0);
+
+    let umi_chart = Plot::new("Duplicate count", "UMI groups")
+        .width(width)
+        .series(Series::bar("UMI groups", by_umi))
+        .render();
+
+    let read_chart = Plot::new("Duplicate count", "Reads")
+        .width(width)
+        .series(Series::bar("Reads", by_read))
+        .render();
+
+    (umi_chart, read_chart)
+}
+
+/// Renders a barcode rank ('knee') plot of the reads and UMIs of each cell, on log-log axes.
+fn knee_chart(cells: &[CellStatistics]) -> String {
+    // large datasets can have millions of (mostly background) barcodes, so only plot ranks which
+    // are roughly evenly spaced on the log scale
+    let ranks = log_spaced_indices(cells.len(), KNEE_PLOT_POINTS);
+
+    let mut umis = ranks
+        .iter()
+        .map(|&i| ((i + 1) as f64, cells[i].umis as f64))
+        .collect::<Vec<_>>();
+    // UMI counts are not necessarily monotonic in read rank, so sort them independently
+    let mut umi_counts = cells.iter().map(|c| c.umis).collect::<Vec<_>>();
+    umi_counts.sort_unstable_by(|a, b| b.cmp(a));
+    for (point, &i) in umis.iter_mut().zip(ranks.iter()) {
+        point.1 = umi_counts[i] as f64;
+    }
+
+    let reads = ranks
+        .iter()
+        .map(|&i| ((i + 1) as f64, cells[i].reads as f64))
+        .collect::<Vec<_>>();
+
+    Plot::new("Barcode rank", "Count")
+        .log_x()
+        .log_y()
+        .series(Series::line("Reads", reads))
+        .series(Series::line("UMIs", umis))
+        .render()
+}
+
+/// Returns up to (approximately) `count` indices in `0..n`, spaced evenly on a log scale. The
+/// first and last indices are always included.
+fn log_spaced_indices(n: usize, count: usize) -> Vec<usize> {
+    if n <= count {
+        return (0..n).collect();
+    }
+
+    let max = (n as f64).ln();
+    let mut indices = (0..count)
+        .map(|i| ((max * i as f64 / (count - 1) as f64).exp() - 1.0).round() as usize)
+        .map(|i| i.min(n - 1))
+        .collect::<Vec<_>>();
+    indices.dedup();
+
+    indices
+}
+
+/// Returns the median of a sequence of counts, or 0 if there are none.
+fn median(values: impl Iterator<Item = usize>) -> f64 {
+    let mut values = values.collect::<Vec<_>>();
+    if values.is_empty() {
+        return 0.0;
+    }
+
+    values.sort_unstable();
+    let mid = values.len() / 2;
+    if values.len() % 2 == 0 {
+        (values[mid - 1] + values[mid]) as f64 / 2.0
+    } else {
+        values[mid] as f64
+    }
+}
+
+/// Renders the saturation curve: the expected number of unique molecules against the number of
+/// reads, both observed by subsampling and extrapolated to greater depths.
+fn saturation_chart(saturation: &SaturationStatistics) -> String {
+    let points = |extrapolated: bool| {
+        saturation
+            .curve
+            .iter()
+            .filter(|p| p.extrapolated == extrapolated)
+            .map(|p| (p.reads, p.molecules))
+            .collect::<Vec<_>>()
+    };
+
+    // join the extrapolated curve onto the end of the observed curve
+    let mut extrapolated = points(true);
+    if let Some(&last) = points(false).last() {
+        extrapolated.insert(0, last);
+    }
+
+    Plot::new("Reads", "Unique molecules")
+        .series(Series::line("Subsampled", points(false)))
+        .series(Series::line("Extrapolated", extrapolated))
+        .render()
+}
+
+/// Creates a row of the read category table in the HTML report.
+fn read_category_row(name: &str, length: &Histogram, quality: &Histogram) -> serde_json::Value {
+    json!({
+        "name": name,
+        "count": length.count,
+        "mean_length": format!("{:.1}", length.mean),
+        "mean_quality": format!("{:.2}", quality.mean),
+    })
+}
+
+/// Renders overlaid histograms of several read categories, each normalised to the proportion of
+/// reads in that category so that their shapes can be compared.
+fn category_chart(x_label: &str, categories: &[(&str, &Histogram)]) -> String {
+    categories
+        .iter()
+        .fold(
+            Plot::new(x_label, "Proportion of reads"),
+            |plot, (label, hist)| plot.series(Series::line(label, hist.proportions())),
+        )
+        .render()
+}
@@ -4,6 +4,71 @@ use predicates::prelude::*;
 
 const SAMPLE_FASTQ: &str = "tests/data/scmixology2_sample.fastq";
 
+/// A temporary directory holding the input and output files of a test.
+struct Fixture(assert_fs::TempDir);
+
+impl Fixture {
+    /// The path of a file within the directory, as passed on the command line.
+    fn path(&self, name: &str) -> String {
+        self.0.child(name).path().to_str().unwrap().to_string()
+    }
+
+    /// Writes a file within the directory, and returns its path.
+    fn write(&self, name: &str, contents: &str) -> String {
+        self.0.child(name).write_str(contents).unwrap();
+        self.path(name)
+    }
+}
+
+impl std::ops::Deref for Fixture {
+    type Target = assert_fs::TempDir;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Creates an empty temporary directory for a test.
+fn fixture_dir() -> Fixture {
+    Fixture(assert_fs::TempDir::new().unwrap())
+}
+
+/// A .fastq record with the given header, and a short sequence of high quality.
+fn fastq_record(id: &str) -> String {
+    format!("@{id}\nACGTACGTAC\n+\nIIIIIIIIII\n")
+}
+
+/// Runs nailpolish with the given arguments, leaving the exit status to be checked.
+fn nailpolish<I, S>(args: I) -> assert_cmd::assert::Assert
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    Command::cargo_bin("nailpolish")
+        .unwrap()
+        .args(args)
+        .assert()
+}
+
+/// Runs nailpolish with the given arguments, and checks that it succeeds.
+fn run_nailpolish<I, S>(args: I) -> assert_cmd::assert::Assert
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<std::ffi::OsStr>,
+{
+    nailpolish(args).success()
+}
+
+/// Simulates `simulated.fastq` and its `truth.tsv` in the directory, and returns the path of
+/// the reads.
+fn simulate_reads(dir: &Fixture, cells: &str, umis: &str) -> String {
+    let (reads, truth) = (dir.path("simulated.fastq"), dir.path("truth.tsv"));
+    run_nailpolish([
+        "simulate", "-o", &reads, "--truth", &truth, "--cells", cells, "--umis", umis,
+    ]);
+    reads
+}
+
 #[test]
 fn index() {
     let temp = assert_fs::NamedTempFile::new("_index.tsv").unwrap();
@@ -11,7 +76,7 @@ fn index() {
     let mut command = Command::cargo_bin("nailpolish").unwrap();
 
     let _ = command
-        .args(&["index", SAMPLE_FASTQ, "-o", temp.path().to_str().unwrap()])
+        .args(["index", SAMPLE_FASTQ, "-o", temp.path().to_str().unwrap()])
         .assert()
         .success();
 
@@ -34,7 +99,7 @@ fn summary() {
     let mut command = Command::cargo_bin("nailpolish").unwrap();
 
     let _ = command
-        .args(&[
+        .args([
             "summary",
             "--index",
             "tests/correct/index.tsv",
@@ -45,6 +110,18 @@ fn summary() {
         .success();
 
     temp.assert(predicate::path::exists());
+
+    // the plots are drawn inline, so the report does not load anything when opened
+    temp.assert(
+        predicate::str::contains("<svg")
+            .and(predicate::str::contains("<script src").not())
+            .and(predicate::str::contains("cdn").not())
+            .and(
+                predicate::str::is_match(r#"(src|href)="https?://"#)
+                    .unwrap()
+                    .not(),
+            ),
+    );
 }
 
 #[test]
@@ -54,7 +131,7 @@ fn consensus_1t() {
     let mut command = Command::cargo_bin("nailpolish").unwrap();
 
     let _ = command
-        .args(&[
+        .args([
             "call",
             "--index",
             "tests/correct/index.tsv",
@@ -83,7 +160,7 @@ fn consensus_4t() {
     let mut command = Command::cargo_bin("nailpolish").unwrap();
 
     let _ = command
-        .args(&[
+        .args([
             "call",
             "--index",
             "tests/correct/index.tsv",
@@ -104,3 +181,1448 @@ fn consensus_4t() {
 
     temp.close().unwrap();
 }
+
+#[test]
+fn summary_json() {
+    let dir = fixture_dir();
+    let json = dir.path("summary.json");
+
+    run_nailpolish([
+        "summary",
+        "--index",
+        "tests/correct/index.tsv",
+        "--format",
+        "json",
+        "-o",
+        &json,
+    ]);
+
+    dir.child("summary.json").assert(
+        predicate::str::contains("\"duplicate_reads\"")
+            .and(predicate::str::contains("\"matched_read_count\": 14143")),
+    );
+}
+
+#[test]
+fn summary_text() {
+    run_nailpolish(["summary", "--index", "tests/correct/index.tsv", "-o", "-"])
+        .stdout(predicate::str::contains("matched reads           14143"))
+        .stdout(predicate::str::contains("UMI groups by group size"));
+}
+
+#[test]
+fn summary_cell_output() {
+    let dir = fixture_dir();
+    let ids = [
+        "TTTTGGGGCCCCAAAA_ACGTACGTACGT#read1",
+        "AAAACCCCGGGGTTTT_ACGTACGTACGT#read2",
+        "AAAACCCCGGGGTTTT_ACGTACGTACGT#read3",
+        "AAAACCCCGGGGTTTT_CCCCAAAAGGGG#read4",
+        "AAAACCCCGGGGTTTT_ACGTACGTACGT#read5",
+    ];
+    let reads = dir.write("reads.fastq", &ids.map(fastq_record).concat());
+    let (index, cells) = (dir.path("index.tsv"), dir.path("cells.tsv"));
+
+    run_nailpolish(["index", &reads, "-o", &index]);
+    run_nailpolish([
+        "summary",
+        "--index",
+        &index,
+        "-o",
+        "-",
+        "--cell-output",
+        &cells,
+    ]);
+
+    // cells are ranked by their number of reads
+    dir.child("cells.tsv").assert(indoc::indoc! {"
+        barcode\treads\tumis\tduplicate_reads\tproportion_duplicate
+        AAAACCCCGGGGTTTT\t4\t2\t3\t0.75
+        TTTTGGGGCCCCAAAA\t1\t1\t0\t0.0
+    "});
+}
+
+#[test]
+fn summary_saturation() {
+    let dir = fixture_dir();
+
+    // three molecules, one of which is sequenced twice
+    let ids = [
+        "TTTTGGGGCCCCAAAA_ACGTACGTACGT#read1",
+        "AAAACCCCGGGGTTTT_ACGTACGTACGT#read2",
+        "AAAACCCCGGGGTTTT_ACGTACGTACGT#read3",
+        "AAAACCCCGGGGTTTT_CCCCAAAAGGGG#read4",
+    ];
+    let reads = dir.write("reads.fastq", &ids.map(fastq_record).concat());
+    let (index, json) = (dir.path("index.tsv"), dir.path("summary.json"));
+
+    run_nailpolish(["index", &reads, "-o", &index]);
+    run_nailpolish([
+        "summary", "--index", &index, "--format", "json", "-o", &json,
+    ]);
+
+    let summary: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&json).unwrap()).unwrap();
+    let saturation = &summary["saturation"];
+
+    // 1 - 3 molecules / 4 reads
+    assert_eq!(saturation["sequencing_saturation"], 0.25);
+    assert_eq!(saturation["observed_molecules"], 3);
+    // 2 singletons / 4 reads
+    assert_eq!(saturation["new_molecule_rate"], 0.5);
+    // bias-corrected Chao1, 3 + 2 * 1 / (2 * (1 + 1))
+    assert_eq!(saturation["estimated_total_molecules"], 3.5);
+
+    let curve = saturation["curve"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|point| {
+            (
+                point["reads"].as_f64().unwrap(),
+                point["molecules"].as_f64().unwrap(),
+                point["extrapolated"].as_bool().unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    // subsampling reaches the observed molecules at the current depth, and is then extrapolated
+    // up to 10 times the current depth
+    let current = curve.iter().rposition(|point| !point.2).unwrap();
+    assert_eq!(curve[current].0, 4.0);
+    assert!((curve[current].1 - 3.0).abs() < 1e-9);
+    assert!(curve[current + 1..].iter().all(|point| point.2));
+    assert_eq!(curve.last().unwrap().0, 40.0);
+    assert!(curve
+        .windows(2)
+        .all(|w| w[0].0 < w[1].0 && w[0].1 <= w[1].1));
+    assert!(curve.iter().all(|point| point.1 <= point.0));
+}
+
+#[test]
+fn summary_histograms() {
+    let dir = fixture_dir();
+    let reads = dir.write(
+        "reads.fastq",
+        indoc::indoc! {"
+            @TTTTGGGGCCCCAAAA_ACGTACGTACGT#read1
+            ACGTACGTAC
+            +
+            IIIIIIIIII
+            @AAAACCCCGGGGTTTT_ACGTACGTACGT#read2
+            ACGTACGTACGTACGTACGT
+            +
+            ++++++++++++++++++++
+            @AAAACCCCGGGGTTTT_ACGTACGTACGT#read3
+            ACGTACGTAC
+            +
+            IIIIIIIIII
+            @AAAACCCCGGGGTTTT_CCCCAAAAGGGG#read4
+            ACGTACGTAC
+            +
+            IIIIIIIIII
+            @AAAACCCCGGGGTTTT_GGGGTTTTAAAA#read5
+            ACGTACGTACGTACGTACGTACGTACGTAC
+            +
+            555555555555555555555555555555
+        "},
+    );
+    let (index, json) = (dir.path("index.tsv"), dir.path("summary.json"));
+
+    run_nailpolish(["index", &reads, "-o", &index, "--len", "0,25"]);
+    run_nailpolish([
+        "summary", "--index", &index, "--format", "json", "-o", &json,
+    ]);
+
+    let summary: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&json).unwrap()).unwrap();
+    let reads = &summary["reads"];
+
+    // read2 and read3 are duplicates, read1 and read4 are singletons, and read5 is too long
+    assert_eq!(
+        reads["length"],
+        serde_json::json!({
+            "duplicate": {"bin_width": 100.0, "bins": {"0": 2}, "count": 2, "mean": 15.0},
+            "singleton": {"bin_width": 100.0, "bins": {"0": 2}, "count": 2, "mean": 10.0},
+            "filtered": {"bin_width": 100.0, "bins": {"0": 1}, "count": 1, "mean": 30.0},
+        })
+    );
+    assert_eq!(
+        reads["quality"],
+        serde_json::json!({
+            "duplicate": {"bin_width": 1.0, "bins": {"10": 1, "40": 1}, "count": 2, "mean": 25.0},
+            "singleton": {"bin_width": 1.0, "bins": {"40": 2}, "count": 2, "mean": 40.0},
+            "filtered": {"bin_width": 1.0, "bins": {"20": 1}, "count": 1, "mean": 20.0},
+        })
+    );
+}
+
+#[test]
+fn summary_sample_labels() {
+    let dir = fixture_dir();
+    let ids = [
+        "AAAACCCCGGGGTTTT_ACGTACGTACGT#read1",
+        "AAAACCCCGGGGTTTT_ACGTACGTACGT#read2",
+        "TTTTGGGGCCCCAAAA_ACGTACGTACGT#read3",
+    ];
+
+    // two samples with the same .fastq and index names, in different directories
+    for (sample, count) in [("a", 3), ("b", 1)] {
+        let reads = dir.write(
+            &format!("{sample}/reads.fastq"),
+            &ids[..count]
+                .iter()
+                .map(|id| fastq_record(id))
+                .collect::<String>(),
+        );
+        run_nailpolish([
+            "index",
+            &reads,
+            "-o",
+            &dir.path(&format!("{sample}/index.tsv")),
+        ]);
+    }
+    let (a, b) = (dir.path("a/index.tsv"), dir.path("b/index.tsv"));
+
+    let summary =
+        |args: &[&str]| nailpolish([&["summary", "--index", &a, "--index", &b], args].concat());
+
+    // repeated labels are numbered, so that each sample has its own column
+    summary(&["--format", "tsv", "-o", "-"])
+        .success()
+        .stdout(predicate::str::contains("metric\tindex\tindex_2\n"))
+        .stdout(predicate::str::contains("\nread_count\t3\t1\n"));
+
+    let multiqc = dir.path("summary_mqc.json");
+    summary(&["--format", "multiqc", "-o", &multiqc]).success();
+    let multiqc: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&multiqc).unwrap()).unwrap();
+    let samples = multiqc["data"].as_object().unwrap();
+    assert_eq!(samples.keys().collect::<Vec<_>>(), vec!["index", "index_2"]);
+
+    // a sample sheet must give each index a different label
+    let sheet = dir.write("samples.tsv", &format!("{a}\tsample\n{b}\tsample\n"));
+    summary(&["--samples", &sheet, "-o", "-"])
+        .failure()
+        .stderr(predicate::str::contains(
+            "gives the label sample to more than one index",
+        ));
+}
+
+#[test]
+fn call_report_original_reads() {
+    let dir = fixture_dir();
+    let ids = [
+        "AAAACCCCGGGGTTTT_ACGTACGTACGT#read1",
+        "AAAACCCCGGGGTTTT_ACGTACGTACGT#read2",
+        "AAAACCCCGGGGTTTT_ACGTACGTACGT#read3",
+        "TTTTGGGGCCCCAAAA_ACGTACGTACGT#read4",
+        "TTTTGGGGCCCCAAAA_ACGTACGTACGT#read5",
+    ];
+    let reads = dir.write("reads.fastq", &ids.map(fastq_record).concat());
+    let index = dir.path("index.tsv");
+
+    run_nailpolish(["index", &reads, "-o", &index]);
+
+    let output = run_nailpolish([
+        "call",
+        "--index",
+        &index,
+        "--input",
+        &reads,
+        "--report-original-reads",
+    ])
+    .get_output()
+    .stdout
+    .clone();
+    let output = String::from_utf8(output).unwrap();
+
+    // every original read is written as its own record, followed by the consensus
+    let lines = output.split('\n').collect::<Vec<_>>();
+    let headers = lines
+        .iter()
+        .step_by(4)
+        .map(|line| line.split(' ').nth(1).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4 * headers.len());
+    assert_eq!(
+        headers,
+        vec![
+            "UT:Z:ORIG_1_OF_3",
+            "UT:Z:ORIG_2_OF_3",
+            "UT:Z:ORIG_3_OF_3",
+            "UT:Z:CON_3",
+            "UT:Z:ORIG_1_OF_2",
+            "UT:Z:ORIG_2_OF_2",
+            "UT:Z:CON_2",
+        ]
+    );
+}
+
+#[test]
+fn qc_json() {
+    let dir = fixture_dir();
+    let input = dir.write(
+        "consensus.fastq",
+        "@A_A#0 UT:Z:ORIG_1_OF_2 UG:i:0\nACGT\n+\n++++\n\
+         @A_A#1 UT:Z:ORIG_2_OF_2 UG:i:0\nACGTAC\n+\n++++++\n\
+         @A_A UT:Z:CON_2 UG:i:0 QL:f:10.00\nACGTA\n+\n55555\n\
+         @C_C#2 UT:Z:SIN UG:i:1 QL:f:20.00\nACG\n+\n555\n",
+    );
+    let (html, json) = (dir.path("qc.html"), dir.path("qc.json"));
+
+    run_nailpolish(["qc", "--input", &input, "-o", &html, "--json", &json]);
+
+    dir.child("qc.json").assert(
+        predicate::str::contains("\"reads_in\": 3")
+            .and(predicate::str::contains("\"molecules_out\": 2"))
+            .and(predicate::str::contains("\"mean_length_difference\": 0.0"))
+            .and(predicate::str::contains("\"mean_quality_uplift\": 10.0")),
+    );
+    dir.child("qc.html")
+        .assert(predicate::str::contains("<svg"));
+}
+
+#[test]
+fn simulate() {
+    let dir = fixture_dir();
+    let (output, truth) = (dir.path("simulated.fastq"), dir.path("truth.tsv"));
+
+    // with a mean of 1 read per Poisson-distributed molecule, there are no duplicates
+    run_nailpolish([
+        "simulate",
+        "-o",
+        &output,
+        "--truth",
+        &truth,
+        "--cells",
+        "10",
+        "--umis",
+        "10",
+        "--duplication",
+        "poisson",
+        "--mean-duplicates",
+        "1",
+    ]);
+
+    let fastq = std::fs::read_to_string(&output).unwrap();
+    assert_eq!(fastq.lines().count(), 4 * 100);
+
+    let truth = std::fs::read_to_string(&truth).unwrap();
+    assert_eq!(truth.lines().count(), 1 + 100);
+    assert!(truth.starts_with("read_id\tmolecule\tbarcode\tumi"));
+}
+
+#[test]
+fn evaluate_simulated() {
+    let dir = fixture_dir();
+    let reads = simulate_reads(&dir, "5", "20");
+    let (truth, index) = (dir.path("truth.tsv"), dir.path("index.tsv"));
+
+    run_nailpolish(["index", &reads, "-o", &index]);
+
+    // without barcode or UMI errors, grouping should be perfect
+    run_nailpolish([
+        "evaluate", "--truth", &truth, "--index", &index, "--input", &reads,
+    ])
+    .stdout(predicate::str::contains("\"precision\": 1.0"))
+    .stdout(predicate::str::contains("\"recall\": 1.0"))
+    .stdout(predicate::str::contains("\"true_molecules\": 100"));
+}
+
+#[test]
+fn evaluate_consensus() {
+    let dir = fixture_dir();
+    let (reads, truth, reference) = (
+        dir.path("simulated.fastq"),
+        dir.path("truth.tsv"),
+        dir.path("reference.fasta"),
+    );
+    let (index, consensus) = (dir.path("index.tsv"), dir.path("consensus.fastq"));
+
+    run_nailpolish([
+        "simulate",
+        "-o",
+        &reads,
+        "--truth",
+        &truth,
+        "--reference-output",
+        &reference,
+        "--cells",
+        "5",
+        "--umis",
+        "20",
+    ]);
+    run_nailpolish(["index", &reads, "-o", &index]);
+    run_nailpolish([
+        "call", "--index", &index, "--input", &reads, "-o", &consensus,
+    ]);
+
+    let output = run_nailpolish([
+        "evaluate",
+        "--truth",
+        &truth,
+        "--index",
+        &index,
+        "--input",
+        &reads,
+        "--consensus",
+        &consensus,
+        "--reference",
+        &reference,
+    ])
+    .get_output()
+    .stdout
+    .clone();
+    let evaluation: serde_json::Value = serde_json::from_slice(&output).unwrap();
+
+    // every consensus read is matched to the molecule of its group
+    let identity = &evaluation["identity"];
+    let consensus_reads = identity["consensus_reads"].as_u64().unwrap();
+    let written = std::fs::read_to_string(&consensus)
+        .unwrap()
+        .matches("UT:Z:CON_")
+        .count();
+    assert!(consensus_reads > 0);
+    assert_eq!(consensus_reads as usize, written);
+    assert!(identity["mean_consensus_identity"].as_f64().unwrap() > 0.5);
+}
+
+#[test]
+fn group_mmap() {
+    let dir = fixture_dir();
+    let reads = simulate_reads(&dir, "5", "20");
+    let index = dir.path("index.tsv");
+    let (grouped, grouped_mmap) = (dir.path("grouped.fastq"), dir.path("grouped_mmap.fastq"));
+
+    run_nailpolish(["index", &reads, "-o", &index]);
+
+    run_nailpolish([
+        "group", "--index", &index, "--input", &reads, "-o", &grouped, "-t", "4",
+    ]);
+    run_nailpolish([
+        "group",
+        "--index",
+        &index,
+        "--input",
+        &reads,
+        "-o",
+        &grouped_mmap,
+        "-t",
+        "4",
+        "--mmap",
+    ]);
+
+    // reads fetched from a memory map should be identical to those read from the file
+    let expected = std::fs::read_to_string(&grouped).unwrap();
+    assert!(expected.contains("UT:Z:ORIG_2_OF_"));
+    dir.child("grouped_mmap.fastq")
+        .assert(predicate::str::diff(expected));
+}
+
+#[test]
+fn compressed_output() {
+    use std::io::Read;
+
+    let dir = fixture_dir();
+    let reads = simulate_reads(&dir, "5", "20");
+    let index = dir.path("index.tsv");
+
+    run_nailpolish(["index", &reads, "-o", &index]);
+
+    for command in ["call", "group"] {
+        let plain = dir.path(&format!("{command}.fastq"));
+        run_nailpolish([command, "--index", &index, "--input", &reads, "-o", &plain]);
+        let expected = std::fs::read(&plain).unwrap();
+
+        // the output is larger than a single BGZF block
+        assert!(expected.len() > 0xff00);
+
+        for extension in ["gz", "bgz", "zst"] {
+            let compressed = dir.path(&format!("{command}.fastq.{extension}"));
+            run_nailpolish([
+                command,
+                "--index",
+                &index,
+                "--input",
+                &reads,
+                "-o",
+                &compressed,
+            ]);
+            let bytes = std::fs::read(&compressed).unwrap();
+
+            let mut decompressed = Vec::new();
+            if extension == "zst" {
+                zstd::stream::read::Decoder::new(&bytes[..])
+                    .unwrap()
+                    .read_to_end(&mut decompressed)
+                    .unwrap();
+            } else {
+                flate2::read::MultiGzDecoder::new(&bytes[..])
+                    .read_to_end(&mut decompressed)
+                    .unwrap();
+            }
+            assert!(decompressed == expected, "{compressed} differs");
+
+            if extension == "bgz" {
+                // each block has a `BC` extra field, and the file ends with the empty EOF block
+                assert_eq!(&bytes[12..14], b"BC");
+                assert_eq!(
+                    &bytes[bytes.len() - 28..],
+                    &[
+                        0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00,
+                        0x42, 0x43, 0x02, 0x00, 0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00,
+                        0x00, 0x00, 0x00, 0x00,
+                    ]
+                );
+            }
+        }
+    }
+
+    // the format can also be given explicitly, regardless of the extension
+    let explicit = dir.path("explicit.out");
+    run_nailpolish([
+        "group",
+        "--index",
+        &index,
+        "--input",
+        &reads,
+        "-o",
+        &explicit,
+        "--compress",
+        "zstd",
+    ]);
+    let decompressed = zstd::decode_all(&std::fs::read(&explicit).unwrap()[..]).unwrap();
+    assert!(decompressed == std::fs::read(dir.path("group.fastq")).unwrap());
+}
+
+#[test]
+fn group_command() {
+    let dir = fixture_dir();
+    let reads = dir.write(
+        "reads.fastq",
+        indoc::indoc! {"
+            @AAAACCCCGGGGTTTT_ACGTACGTACGT#read1
+            ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT
+            +
+            IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+            @TTTTGGGGCCCCAAAA_ACGTACGTACGT#read2
+            ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT
+            +
+            IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+            @AAAACCCCGGGGTTTT_ACGTACGTACGT#read3
+            ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT
+            +
+            IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+            @AAAACCCCGGGGTTTT_CCCCAAAAGGGG#read4
+            ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT
+            +
+            IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+        "},
+    );
+    let index = dir.path("index.tsv");
+
+    run_nailpolish(["index", &reads, "-o", &index]);
+
+    let group = |command: &str, keep_going: bool| {
+        let mut args = vec!["group", "--index", &index, "--input", &reads, "-t", "4"];
+        if keep_going {
+            args.push("--keep-going");
+        }
+        args.push(command);
+        nailpolish(args)
+    };
+
+    // earlier groups finish last, but the output is still written in group order
+    group(
+        "sleep 0.$((3 - NAILPOLISH_UG)); \
+        echo $NAILPOLISH_UG $NAILPOLISH_ID $NAILPOLISH_BC $NAILPOLISH_UMI \
+        $NAILPOLISH_SIZE $NAILPOLISH_AVG_QUAL $(grep -c '^@' -)",
+        false,
+    )
+    .success()
+    .stdout(indoc::indoc! {"
+        0 AAAACCCCGGGGTTTT_ACGTACGTACGT AAAACCCCGGGGTTTT ACGTACGTACGT 2 40.00 2
+        1 TTTTGGGGCCCCAAAA_ACGTACGTACGT TTTTGGGGCCCCAAAA ACGTACGTACGT 1 40.00 1
+        2 AAAACCCCGGGGTTTT_CCCCAAAAGGGG AAAACCCCGGGGTTTT CCCCAAAAGGGG 1 40.00 1
+    "});
+
+    // a command which fails is an error, unless it is skipped with --keep-going
+    let failing = "[ $NAILPOLISH_UG -ne 1 ] && echo $NAILPOLISH_UG";
+    group(failing, false).failure();
+    group(failing, true)
+        .success()
+        .stdout("0\n2\n")
+        .stderr(predicate::str::contains(
+            "The command failed for 1 of 3 groups",
+        ));
+}
+
+#[test]
+fn group_sort() {
+    let dir = fixture_dir();
+    let reads = simulate_reads(&dir, "5", "20");
+    let index = dir.path("index.tsv");
+
+    run_nailpolish(["index", &reads, "-o", &index]);
+
+    // the (barcode, UMI, group size, group index) of each read in the output
+    let groups = |args: &[&str]| {
+        let output =
+            run_nailpolish([&["group", "--index", &index, "--input", &reads], args].concat())
+                .get_output()
+                .stdout
+                .clone();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .filter(|line| line.starts_with('@'))
+            .map(|line| {
+                let (id, tags) = line[1..].split_once('#').unwrap();
+                let (barcode, umi) = id.split_once('_').unwrap();
+                let size = tags
+                    .split("_OF_")
+                    .nth(1)
+                    .unwrap()
+                    .split(' ')
+                    .next()
+                    .unwrap();
+                let group = tags.rsplit("UG:i:").next().unwrap();
+                (
+                    barcode.to_string(),
+                    umi.to_string(),
+                    size.parse::<usize>().unwrap(),
+                    group.parse::<usize>().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let input = groups(&[]);
+    assert!(input.windows(2).all(|w| w[0].3 <= w[1].3));
+
+    let barcode = groups(&["--sort", "barcode"]);
+    assert!(barcode
+        .windows(2)
+        .all(|w| (&w[0].0, &w[0].1) <= (&w[1].0, &w[1].1)));
+
+    let size = groups(&["--sort", "size"]);
+    assert!(size.windows(2).all(|w| w[0].2 >= w[1].2));
+
+    // every order writes the same reads, with the reads of each group kept together
+    for sorted in [&barcode, &size] {
+        assert_eq!(sorted.len(), input.len());
+        let mut seen = std::collections::HashSet::new();
+        for w in sorted.windows(2) {
+            if w[0].3 != w[1].3 {
+                assert!(seen.insert(w[0].3), "group {} is split", w[0].3);
+            }
+        }
+    }
+
+    // a small memory limit spills sorted runs to disk, without changing the output
+    let spilled = run_nailpolish([
+        "group",
+        "--index",
+        &index,
+        "--input",
+        &reads,
+        "--sort",
+        "barcode",
+        "--sort-memory",
+        "0",
+    ])
+    .stderr(predicate::str::contains("Wrote sorted run 2 "))
+    .stderr(predicate::str::contains("Merging"));
+    let in_memory = run_nailpolish([
+        "group", "--index", &index, "--input", &reads, "--sort", "barcode",
+    ]);
+    assert!(spilled.get_output().stdout == in_memory.get_output().stdout);
+}
+
+#[test]
+fn ignored_outputs() {
+    let dir = fixture_dir();
+    let reads = dir.write(
+        "reads.fastq",
+        indoc::indoc! {"
+            @AAAACCCCGGGGTTTT_ACGTACGTACGT#read1
+            ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT
+            +
+            IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+            @not_a_barcode read2
+            ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT
+            +
+            IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+            @AAAACCCCGGGGTTTT_ACGTACGTACGT#read3
+            ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT
+            +
+            IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+            @AAAACCCCGGGGTTTT_CCCCAAAAGGGG#read4
+            ACGTACGTAC
+            +
+            IIIIIIIIII
+        "},
+    );
+    let (index, filtered, unmatched) = (
+        dir.path("index.tsv"),
+        dir.path("filtered.fastq"),
+        dir.path("unmatched.fastq"),
+    );
+
+    run_nailpolish([
+        "index",
+        &reads,
+        "-o",
+        &index,
+        "--skip-unmatched",
+        "--len",
+        "20,inf",
+    ]);
+
+    dir.child("index.tsv").assert(
+        predicate::str::contains("\ttrue\tunmatched")
+            .and(predicate::str::contains("\ttrue\ttoo_short")),
+    );
+
+    run_nailpolish([
+        "call",
+        "--index",
+        &index,
+        "--input",
+        &reads,
+        "--filtered-output",
+        &filtered,
+        "--unmatched-output",
+        &unmatched,
+    ])
+    .stdout(
+        predicate::str::contains("UT:Z:CON_2")
+            .and(predicate::str::contains("read2").not())
+            .and(predicate::str::contains("read4").not()),
+    );
+
+    dir.child("filtered.fastq")
+        .assert(predicate::str::starts_with(
+            "@AAAACCCCGGGGTTTT_CCCCAAAAGGGG#read4 UT:Z:IGN FR:Z:too_short\n",
+        ));
+    dir.child("unmatched.fastq")
+        .assert(predicate::str::starts_with(
+            "@not_a_barcode read2 UT:Z:IGN FR:Z:unmatched\n",
+        ));
+}
+
+/// Checks that the metadata of an index counts as many filtered and unmatched reads as it has
+/// rows with each reason.
+fn assert_index_counts(index: &str) {
+    let contents = std::fs::read_to_string(index).unwrap();
+    let (header, rows) = contents.split_once('\n').unwrap();
+    let metadata: serde_json::Value = serde_json::from_str(&header[1..]).unwrap();
+
+    let reasons = rows
+        .lines()
+        .skip(1)
+        .map(|row| row.split('\t').collect::<Vec<_>>())
+        .filter(|fields| fields[6] == "true")
+        .map(|fields| fields.get(7).copied().unwrap_or_default())
+        .collect::<Vec<_>>();
+    let unmatched = reasons.iter().filter(|&&r| r == "unmatched").count();
+
+    assert_eq!(metadata["filtered_reads"], reasons.len() - unmatched);
+    assert_eq!(metadata["unmatched_read_count"], unmatched);
+}
+
+#[test]
+fn index_filter_reasons() {
+    const INPUT: &str = "tests/data/filter_reasons.fastq";
+    const CORRECT_FILE: &str = "tests/correct/filter_reasons_index.tsv";
+
+    let dir = fixture_dir();
+    let index = dir.path("index.tsv");
+
+    run_nailpolish([
+        "index",
+        INPUT,
+        "-o",
+        &index,
+        "--skip-unmatched",
+        "--len",
+        "10,40",
+        "--qual",
+        "10,inf",
+    ]);
+
+    // the header contains the date and runtime of the index, so only the rows are compared
+    let rows = |path: &str| {
+        let contents = std::fs::read_to_string(path).unwrap();
+        contents
+            .lines()
+            .skip(1)
+            .map(String::from)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(rows(&index), rows(CORRECT_FILE));
+
+    assert_index_counts(&index);
+    assert_index_counts(CORRECT_FILE);
+    assert_index_counts("tests/correct/index.tsv");
+}
+
+#[test]
+fn query_and_subset() {
+    let dir = fixture_dir();
+    let reads = dir.write(
+        "reads.fastq",
+        indoc::indoc! {"
+            @AAAACCCCGGGGTTTT_ACGTACGTACGT#read1
+            ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT
+            +
+            IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+            @TTTTGGGGCCCCAAAA_ACGTACGTACGT#read2
+            ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT
+            +
+            IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+            @AAAACCCCGGGGTTTT_ACGTACGTACGT#read3
+            ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT
+            +
+            IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+            @AAAACCCCGGGGTTTT_CCCCAAAAGGGG#read4
+            ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT
+            +
+            IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+        "},
+    );
+    let index = dir.path("index.tsv");
+    let (subset, subset_index) = (dir.path("subset.fastq"), dir.path("subset.tsv"));
+
+    run_nailpolish(["index", &reads, "-o", &index]);
+
+    run_nailpolish([
+        "query",
+        "--index",
+        &index,
+        "--input",
+        &reads,
+        "--umi",
+        "ACGTACGTACGT",
+    ])
+    .stdout(
+        predicate::str::contains("read1")
+            .and(predicate::str::contains("read2"))
+            .and(predicate::str::contains("read3"))
+            .and(predicate::str::contains("read4").not()),
+    );
+
+    run_nailpolish([
+        "subset",
+        "--index",
+        &index,
+        "--input",
+        &reads,
+        "-o",
+        &subset,
+        "--index-output",
+        &subset_index,
+        "--barcode",
+        "AAAACCCCGGGGTTTT",
+    ]);
+
+    // the subset can be used in place of the original input
+    run_nailpolish(["group", "--index", &subset_index, "--input", &subset]).stdout(
+        predicate::str::contains("read1 UT:Z:ORIG_1_OF_2 UG:i:0")
+            .and(predicate::str::contains("read3 UT:Z:ORIG_2_OF_2 UG:i:0"))
+            .and(predicate::str::contains("read4 UT:Z:ORIG_1_OF_1 UG:i:1"))
+            .and(predicate::str::contains("read2").not()),
+    );
+
+    // a subset with no reads is not written
+    let (empty, empty_index) = (dir.path("empty.fastq"), dir.path("empty.tsv"));
+    nailpolish([
+        "subset",
+        "--index",
+        &index,
+        "--input",
+        &reads,
+        "-o",
+        &empty,
+        "--index-output",
+        &empty_index,
+        "--barcode",
+        "TTTTTTTTTTTTTTTT",
+    ])
+    .failure()
+    .stderr(predicate::str::contains("No reads match the selection"));
+    dir.child("empty.fastq").assert(predicate::path::missing());
+    dir.child("empty.tsv").assert(predicate::path::missing());
+}
+
+#[test]
+fn filter_index() {
+    let dir = fixture_dir();
+    let reads = dir.write(
+        "reads.fastq",
+        indoc::indoc! {"
+            @AAAACCCCGGGGTTTT_ACGTACGTACGT#read1
+            ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT
+            +
+            IIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIIII
+            @AAAACCCCGGGGTTTT_ACGTACGTACGT#read2
+            ACGTACGTAC
+            +
+            IIIIIIIIII
+            @TTTTGGGGCCCCAAAA_ACGTACGTACGT#read3
+            ACGTACGTACGTACGTACGTACGTACGTACGTACGTACGT
+            +
+            ++++++++++++++++++++++++++++++++++++++++
+        "},
+    );
+    let (index, filtered) = (dir.path("index.tsv"), dir.path("filtered.tsv"));
+
+    run_nailpolish(["index", &reads, "-o", &index]);
+    run_nailpolish([
+        "filter", "--index", &index, "--len", "20,15000", "-o", &filtered,
+    ]);
+
+    let contents = std::fs::read_to_string(&filtered).unwrap();
+    assert!(contents.contains(r#""filtered_reads":1"#));
+    assert!(contents.contains("\ttrue\ttoo_short\n"));
+
+    // the quality filter is added, and the length filter is kept
+    run_nailpolish(["filter", "--index", &filtered, "--qual", "20,inf"]);
+
+    let contents = std::fs::read_to_string(&filtered).unwrap();
+    assert!(contents.contains(r#""filtered_reads":2"#));
+    assert!(contents.contains("\ttrue\tlow_quality\n"));
+
+    run_nailpolish(["summary", "--index", &filtered, "--format", "text"]).stdout(
+        predicate::str::contains("length filter           20,15000")
+            .and(predicate::str::contains("quality filter          20,inf"))
+            .and(predicate::str::contains(
+                "length 0,15000, quality 0,inf (0 filtered) -> \
+                length 20,15000, quality 0,inf (1 filtered) -> \
+                length 20,15000, quality 20,inf (2 filtered)",
+            )),
+    );
+}
+
+#[test]
+fn filter_index_unmatched() {
+    let dir = fixture_dir();
+
+    // read2 is too short, and read3 is both unmatched and too short
+    let reads = dir.write(
+        "reads.fastq",
+        indoc::indoc! {"
+            @AAAACCCCGGGGTTTT_ACGTACGTACGT#read1
+            ACGTACGTACGT
+            +
+            IIIIIIIIIIII
+            @AAAACCCCGGGGTTTT_ACGTACGTACGT#read2
+            ACGT
+            +
+            IIII
+            @unmatched#read3
+            ACGT
+            +
+            IIII
+            @TTTTGGGGCCCCAAAA_ACGTACGTACGT#read4
+            ACGTACGTACGT
+            +
+            IIIIIIIIIIII
+        "},
+    );
+    let index = dir.path("index.tsv");
+
+    run_nailpolish([
+        "index",
+        &reads,
+        "-o",
+        &index,
+        "--skip-unmatched",
+        "--len",
+        "10,inf",
+    ]);
+    let contents = std::fs::read_to_string(&index).unwrap();
+    assert!(contents.contains(r#""filtered_reads":1"#));
+
+    // unmatched reads are not counted as filtered, so filtering again with the same thresholds
+    // leaves the count unchanged
+    run_nailpolish(["filter", "--index", &index, "--len", "10,inf"]);
+    let contents = std::fs::read_to_string(&index).unwrap();
+    assert!(contents.contains(r#""filtered_reads":1"#));
+    assert!(contents.contains("\ttrue\tunmatched\n"));
+    assert!(contents.contains("\ttrue\ttoo_short\n"));
+
+    run_nailpolish(["summary", "--index", &index, "--format", "text"])
+        .stdout(predicate::str::contains(
+        "length 10,inf, quality 0,inf (1 filtered) -> length 10,inf, quality 0,inf (1 filtered)",
+    ));
+}
+
+#[test]
+fn run_pipeline() {
+    let dir = fixture_dir();
+    let reads = simulate_reads(&dir, "5", "10");
+    let output = dir.path("output");
+
+    // some reads are filtered, so that every category of read is summarised
+    run_nailpolish([
+        "run", &reads, "-o", &output, "--format", "json", "--len", "0,1000",
+    ]);
+
+    let output = dir.child("output");
+    output
+        .child("manifest.json")
+        .assert(predicate::str::contains(
+            "\"consensus\": \"consensus.fastq\"",
+        ));
+    output.child("index.tsv").assert(predicate::path::exists());
+    output
+        .child("summary.json")
+        .assert(predicate::str::contains("\"duplicate_reads\""));
+    output
+        .child("consensus.fastq")
+        .assert(predicate::str::contains("UT:Z:"));
+
+    // the duplicates found while writing the index are the same as those found by reading it
+    let (index, summary) = (dir.path("output/index.tsv"), dir.path("summary.json"));
+    run_nailpolish([
+        "summary", "--index", &index, "--format", "json", "-o", &summary,
+    ]);
+    let read_json = |path: &str| {
+        serde_json::from_str::<serde_json::Value>(&std::fs::read_to_string(path).unwrap()).unwrap()
+    };
+    let (written, read) = (
+        read_json(&dir.path("output/summary.json")),
+        read_json(&summary),
+    );
+    assert!(
+        written["reads"]["length"]["filtered"]["count"]
+            .as_u64()
+            .unwrap()
+            > 0
+    );
+    assert_eq!(written, read);
+
+    run_nailpolish(["call", "--index", &index, "--input", &reads]).stdout(
+        predicate::path::eq_file(output.child("consensus.fastq").path())
+            .utf8()
+            .unwrap(),
+    );
+}
+
+#[test]
+fn index_config() {
+    let dir = fixture_dir();
+    let reads = simulate_reads(&dir, "5", "10");
+    let (output, dumped) = (dir.path("index.tsv"), dir.path("dumped.toml"));
+
+    let config = dir.write(
+        "config.toml",
+        &format!(
+            "threads = 2\n[index]\noutput = \"{}\"\nlen = \"-inf,inf\"\nskip-unmatched = true\n",
+            dir.path("ignored.tsv")
+        ),
+    );
+
+    // the output given on the command line overrides the one in the config
+    run_nailpolish([
+        "index",
+        &reads,
+        "--config",
+        &config,
+        "-o",
+        &output,
+        "--dump-config",
+        &dumped,
+    ]);
+
+    dir.child("index.tsv").assert(predicate::path::exists());
+    dir.child("ignored.tsv").assert(predicate::path::missing());
+    dir.child("dumped.toml").assert(
+        predicate::str::contains("[index]")
+            .and(predicate::str::contains("len = \"-inf,inf\""))
+            .and(predicate::str::contains("skip_unmatched = true")),
+    );
+
+    // unknown options in a subcommand's table are rejected
+    dir.write("config.toml", "[index]\nunknown = 1\n");
+    nailpolish(["index", &reads, "--config", &config]).failure();
+}
+
+#[test]
+fn index_parameters() {
+    let dir = fixture_dir();
+    let reads = simulate_reads(&dir, "5", "10");
+    let index = dir.path("index.tsv");
+
+    run_nailpolish(["index", &reads, "-o", &index, "--len", "100,inf"]);
+
+    dir.child("index.tsv").assert(predicate::str::contains(
+        "\"preset\":\"bc-umi\",\"clusters\":null,\"skip_unmatched\":false,\"len\":\"100,inf\"",
+    ));
+
+    run_nailpolish(["summary", "--index", &index, "-o", "-"]).stdout(
+        predicate::str::contains("preset bc-umi")
+            .and(predicate::str::is_match(r"length filter +100,inf").unwrap()),
+    );
+}
+
+#[test]
+fn call_index_parameters() {
+    let dir = fixture_dir();
+    let reads = simulate_reads(&dir, "5", "10");
+    let index = dir.path("index.tsv");
+
+    // every read passes the default filters, and matches the barcode format
+    run_nailpolish(["index", &reads, "-o", &index]);
+
+    let (filtered, unmatched) = (dir.path("filtered.fastq"), dir.path("unmatched.fastq"));
+    run_nailpolish([
+        "call",
+        "--index",
+        &index,
+        "--input",
+        &reads,
+        "-o",
+        "-",
+        "--filtered-output",
+        &filtered,
+        "--unmatched-output",
+        &unmatched,
+    ])
+    .stderr(
+        predicate::str::contains("--filtered-output was given, but no reads were filtered").and(
+            predicate::str::contains("the index was created without --skip-unmatched"),
+        ),
+    );
+
+    nailpolish([
+        "call", "--index", &index, "--input", &reads, "-o", "-", "--cell", "lane",
+    ])
+    .failure()
+    .stderr(predicate::str::contains(
+        "--cell lane is not a component of the index, which was created with barcode format \
+        preset bc-umi",
+    ));
+}
+
+#[test]
+fn presets_list() {
+    let dir = fixture_dir();
+    let presets = dir.write(
+        "presets.yaml",
+        indoc::indoc! {r#"
+            preset:
+              - name: cb-ub
+                regex: 'CB:Z:([ACGT]+)-1\s+UB:Z:([ACGT]+)'
+                components: [barcode, umi]
+                umi_length: 12
+                example: "read1 CB:Z:AAACCCAAGAAACACT-1 UB:Z:CCTTAGGCTGAA"
+        "#},
+    );
+
+    run_nailpolish(["presets", "list", "--presets", &presets]).stdout(
+        predicate::str::contains("bc-umi (built-in)")
+            .and(predicate::str::contains("split-seq (built-in)"))
+            .and(predicate::str::contains("cb-ub (user)"))
+            .and(predicate::str::contains(
+                "barcode=AAACCCAAGAAACACT umi=CCTTAGGCTGAA",
+            )),
+    );
+
+    // the example header must be consistent with the UMI length
+    dir.write(
+        "presets.yaml",
+        indoc::indoc! {r#"
+            preset:
+              - name: cb-ub
+                regex: 'CB:Z:([ACGT]+)-1\s+UB:Z:([ACGT]+)'
+                umi_length: 10
+                example: "read1 CB:Z:AAACCCAAGAAACACT-1 UB:Z:CCTTAGGCTGAA"
+        "#},
+    );
+
+    nailpolish(["presets", "list", "--presets", &presets]).failure();
+}
+
+#[test]
+fn index_auto_preset() {
+    let dir = fixture_dir();
+    let reads = simulate_reads(&dir, "5", "10");
+    let index = dir.path("index.tsv");
+
+    run_nailpolish(["index", &reads, "auto", "-o", &index]);
+
+    dir.child("index.tsv")
+        .assert(predicate::str::contains("\"preset\":\"bc-umi\""));
+
+    // headers which do not match any preset produce a suggested regex
+    let unknown = dir.write(
+        "unknown.fastq",
+        indoc::indoc! {"
+            @read1 bc:AAACCCAAGAAACACT umi:CCTTAGGCTG strand=+
+            ACGTACGTAC
+            +
+            IIIIIIIIII
+            @read2 bc:TTTGGTTAGCATCGGA umi:GGATCTTACA strand=-
+            ACGTACGTAC
+            +
+            IIIIIIIIII
+        "},
+    );
+
+    nailpolish(["index", &unknown, "auto", "-o", &index])
+        .failure()
+        .stderr(predicate::str::contains(
+            "suggestion: pass --barcode-regex 'bc:([ACGTN]{16}).*?umi:([ACGTN]{10})'",
+        ));
+}
+
+#[test]
+fn index_split_seq() {
+    let dir = fixture_dir();
+    let ids = [
+        "CATTCCTAAACATCGGATGAATCC_GTTACAGCAT#read1",
+        "CATTCCTAAACATCGGATGAATCC_GTTACAGCAT#read2",
+        "GGTAGCAAAACATCGGATGAATCC_GTTACAGCAT#read3",
+    ];
+    let reads = dir.write("reads.fastq", &ids.map(fastq_record).concat());
+    let index = dir.path("index.tsv");
+
+    run_nailpolish(["index", &reads, "split-seq", "-o", &index]);
+
+    // each round barcode is a component of its own
+    dir.child("index.tsv").assert(
+        predicate::str::contains(r#""components":["round1","round2","round3","umi"]"#).and(
+            predicate::str::contains("CATTCCTA_AACATCGG_ATGAATCC_GTTACAGCAT"),
+        ),
+    );
+
+    run_nailpolish(["summary", "--index", &index, "-o", "-"])
+        .stdout(predicate::str::is_match(r"UMI groups +2\n").unwrap());
+
+    // the reads of the two round 1 barcodes are one molecule when grouped by rounds 2 and 3
+    run_nailpolish([
+        "summary",
+        "--index",
+        &index,
+        "-o",
+        "-",
+        "--cell",
+        "round2,round3",
+    ])
+    .stdout(predicate::str::is_match(r"UMI groups +1\n").unwrap());
+}
+
+#[test]
+fn summary_cell_components() {
+    let dir = fixture_dir();
+    let ids = [
+        "s1_AAAA_CCCC read1",
+        "s1_AAAA_CCCC read2",
+        "s2_AAAA_CCCC read3",
+        "s2_TT_TT_GGGG read4",
+    ];
+    let reads = dir.write("reads.fastq", &ids.map(fastq_record).concat());
+    let (index, cells) = (dir.path("index.tsv"), dir.path("cells.tsv"));
+
+    run_nailpolish([
+        "index",
+        &reads,
+        "-o",
+        &index,
+        "--barcode-regex",
+        r"^(?<lane>s\d)_(?<barcode>[ACGT_]+)_(?<umi>[ACGT]+) ",
+    ]);
+
+    // components are named after the capture groups, and underscores within them are escaped
+    dir.child("index.tsv").assert(
+        predicate::str::contains(r#""components":["lane","barcode","umi"]"#)
+            .and(predicate::str::contains(r"s2_TT\_TT_GGGG")),
+    );
+
+    run_nailpolish(["summary", "--index", &index, "-o", "-"])
+        .stdout(predicate::str::is_match(r"UMI groups +3\n").unwrap());
+
+    // ignoring the lane merges the reads of AAAA_CCCC into one group
+    run_nailpolish([
+        "summary",
+        "--index",
+        &index,
+        "-o",
+        "-",
+        "--cell",
+        "barcode",
+        "--molecule",
+        "umi",
+        "--cell-output",
+        &cells,
+    ])
+    .stdout(predicate::str::is_match(r"UMI groups +2\n").unwrap());
+
+    dir.child("cells.tsv").assert(
+        predicate::str::contains("AAAA\t3\t1\t3").and(predicate::str::contains("TT_TT\t1\t1\t0")),
+    );
+
+    nailpolish(["summary", "--index", &index, "-o", "-", "--cell", "cell"])
+        .failure()
+        .stderr(predicate::str::contains(
+            "The components of this index are: lane, barcode, umi",
+        ));
+}
+
+#[test]
+fn packed_identifiers() {
+    let dir = fixture_dir();
+
+    // components which are packed at 2 bits per base, and those which are stored as-is
+    let long = |n: usize| "ACGT".repeat(n).chars().take(n).collect::<String>();
+    let identifiers = [
+        vec![long(16), long(12), String::new()],
+        vec![long(5), long(127), "x".to_string()],
+        vec![long(128), long(300), "ACGTN".to_string()],
+        vec!["AC_GT".to_string(), r"A\C".to_string(), "_".to_string()],
+        vec![String::new(), String::new(), "acgt".to_string()],
+        vec!["NNNN".to_string(), "ACGU".to_string(), r"\_\".to_string()],
+    ];
+    let reads = identifiers
+        .iter()
+        .map(|components| fastq_record(&format!("{} read", components.join("|"))))
+        .collect::<String>();
+    let fastq = dir.write("components.fastq", &reads);
+    let index = dir.path("components.tsv");
+
+    run_nailpolish([
+        "index",
+        &fastq,
+        "-o",
+        &index,
+        "--barcode-regex",
+        r"^([^|]*)\|([^|]*)\|([^ ]*) ",
+    ]);
+
+    // each component is unpacked exactly, with underscores and backslashes escaped in the ID
+    let escape = |c: &str| c.replace('\\', r"\\").replace('_', r"\_");
+    let expected = identifiers
+        .iter()
+        .map(|components| {
+            let escaped = components.iter().map(|c| escape(c)).collect::<Vec<_>>();
+            format!("{}\n", escaped.join("_"))
+        })
+        .collect::<String>();
+    run_nailpolish([
+        "group",
+        "--index",
+        &index,
+        "--input",
+        &fastq,
+        "echo \"$NAILPOLISH_ID\"",
+    ])
+    .stdout(expected);
+
+    // samples are interned and packed as varints, so enough samples are used to need 3 bytes
+    let samples = 16500;
+    let reads = (0..samples)
+        .map(|i| fastq_record(&format!("S{i}_AAAACCCC_GGGGTTTT read")))
+        .collect::<String>();
+    let fastq = dir.write("samples.fastq", &reads);
+    let index = dir.path("samples.tsv");
+
+    run_nailpolish([
+        "index",
+        &fastq,
+        "-o",
+        &index,
+        "--barcode-regex",
+        r"^(?<sample>S\d+)_(?<barcode>[ACGT]+)_(?<umi>[ACGT]+) ",
+    ]);
+
+    let output = run_nailpolish(["group", "--index", &index, "--input", &fastq])
+        .get_output()
+        .stdout
+        .clone();
+    let output = String::from_utf8(output).unwrap();
+    let headers = output
+        .lines()
+        .filter(|line| line.starts_with('@'))
+        .collect::<Vec<_>>();
+    assert_eq!(headers.len(), samples);
+    for header in headers {
+        let sample = header[1..].split('_').next().unwrap();
+        assert!(
+            header.contains(&format!(" SM:Z:{sample}")),
+            "{header} has the wrong sample"
+        );
+    }
+}
+
+#[test]
+fn index_samples() {
+    let dir = fixture_dir();
+    let ids = [
+        "S1_AAAA_CCCC read1",
+        "S1_AAAA_CCCC read2",
+        "S2_AAAA_CCCC read3",
+    ];
+    let reads = dir.write("reads.fastq", &ids.map(fastq_record).concat());
+    let index = dir.path("index.tsv");
+
+    run_nailpolish([
+        "index",
+        &reads,
+        "-o",
+        &index,
+        "--barcode-regex",
+        r"^(?<sample>S\d)_(?<barcode>[ACGT]+)_(?<umi>[ACGT]+) ",
+    ]);
+
+    // the sample has its own column, and is not a component of the identifier
+    dir.child("index.tsv").assert(
+        predicate::str::contains(r#""components":["barcode","umi"]"#)
+            .and(predicate::str::contains("AAAA_CCCC\tS2\t")),
+    );
+
+    // reads with the same barcode and UMI are not grouped across samples
+    run_nailpolish(["summary", "--index", &index, "-o", "-"]).stdout(
+        predicate::str::is_match(r"UMI groups +2\n")
+            .unwrap()
+            .and(predicate::str::is_match(r"S1 +2 +1 +1 +100.00%").unwrap())
+            .and(predicate::str::is_match(r"S2 +1 +1 +1 +0.00%").unwrap()),
+    );
+
+    // the same samples can be given in a cluster file
+    let clusters = dir.write(
+        "clusters.csv",
+        indoc::indoc! {"
+            S1_AAAA_CCCC read1;S1;AAAA;CCCC
+            S1_AAAA_CCCC read2;S1;AAAA;CCCC
+            S2_AAAA_CCCC read3;S2;AAAA;CCCC
+        "},
+    );
+
+    run_nailpolish([
+        "index",
+        &reads,
+        "-o",
+        &index,
+        "--clusters",
+        &clusters,
+        "--sample-column",
+    ]);
+
+    run_nailpolish(["summary", "--index", &index, "-o", "-", "--format", "tsv"]).stdout(
+        predicate::str::contains("sample_S1_reads\t2")
+            .and(predicate::str::contains("sample_S2_umi_groups\t1")),
+    );
+}
+
+#[test]
+#[cfg(feature = "benchmark")]
+fn benchmark_duplicate_map() {
+    run_nailpolish(["benchmark", "--reads", "10000", "--cells", "10"]).stdout(
+        predicate::str::is_match(r"(?m)^legacy +[0-9.]+ +[0-9.]+")
+            .unwrap()
+            .and(predicate::str::is_match(r"(?m)^packed +[0-9.]+ +[0-9.]+").unwrap()),
+    );
+}